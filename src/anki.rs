@@ -0,0 +1,605 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use rusqlite::{params, Connection};
+use ruzstd::decoding::StreamingDecoder;
+use serde_json::{json, Value};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::db::{Card, Source};
+use crate::mapping::{self, Field, FieldAssignment, FieldTarget};
+use crate::pinyin::{self, PinyinStyle};
+use crate::review::Schedule;
+use crate::spreadsheet;
+
+#[derive(Debug)]
+pub struct AnkiError(String);
+
+impl std::fmt::Display for AnkiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for AnkiError {}
+
+impl From<std::io::Error> for AnkiError {
+    fn from(e: std::io::Error) -> Self {
+        AnkiError(format!("IO error: {}", e))
+    }
+}
+
+impl From<rusqlite::Error> for AnkiError {
+    fn from(e: rusqlite::Error) -> Self {
+        AnkiError(format!("SQLite error: {}", e))
+    }
+}
+
+impl From<serde_json::Error> for AnkiError {
+    fn from(e: serde_json::Error) -> Self {
+        AnkiError(format!("Serde error: {}", e))
+    }
+}
+
+impl From<csv::Error> for AnkiError {
+    fn from(e: csv::Error) -> Self {
+        AnkiError(format!("Malformed text export: {}", e))
+    }
+}
+
+impl From<zip::result::ZipError> for AnkiError {
+    fn from(e: zip::result::ZipError) -> Self {
+        AnkiError(format!("Zip error: {}", e))
+    }
+}
+
+/// Which cards each exported note produces.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum NoteType {
+    /// Hanzi on the front, pinyin and meaning on the back
+    Basic,
+    /// Meaning on the front, hanzi and pinyin on the back
+    Reverse,
+    /// One card each way
+    Both,
+}
+
+/// The fields of the exported note type, in order. `Hanzi` is the sort field.
+pub const FIELDS: [&str; 6] = ["Hanzi", "Traditional", "Pinyin", "Meaning", "Examples", "Notes"];
+
+const RECOGNITION_FRONT: &str = "<div class=hanzi>{{Hanzi}}</div>";
+const RECALL_FRONT: &str = "<div class=meaning>{{Meaning}}</div>";
+const BACK_DETAILS: &str = "{{#Examples}}<div class=examples>{{Examples}}</div>{{/Examples}}\
+{{#Notes}}<div class=notes>{{Notes}}</div>{{/Notes}}";
+const CSS: &str = ".card { font-family: sans-serif; font-size: 20px; text-align: center; }
+.hanzi { font-size: 48px; }
+.pinyin { color: #555; }
+.examples, .notes { font-size: 16px; margin-top: 12px; }";
+
+/// The legacy (schema 11) collection layout, which every Anki version can import.
+const SCHEMA: &str = "
+CREATE TABLE col (id integer primary key, crt integer not null, mod integer not null, scm integer not null,
+    ver integer not null, dty integer not null, usn integer not null, ls integer not null, conf text not null,
+    models text not null, decks text not null, dconf text not null, tags text not null);
+CREATE TABLE notes (id integer primary key, guid text not null, mid integer not null, mod integer not null,
+    usn integer not null, tags text not null, flds text not null, sfld integer not null, csum integer not null,
+    flags integer not null, data text not null);
+CREATE TABLE cards (id integer primary key, nid integer not null, did integer not null, ord integer not null,
+    mod integer not null, usn integer not null, type integer not null, queue integer not null, due integer not null,
+    ivl integer not null, factor integer not null, reps integer not null, lapses integer not null, left integer not null,
+    odue integer not null, odid integer not null, flags integer not null, data text not null);
+CREATE TABLE revlog (id integer primary key, cid integer not null, usn integer not null, ease integer not null,
+    ivl integer not null, lastIvl integer not null, factor integer not null, time integer not null, type integer not null);
+CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+CREATE INDEX ix_notes_usn ON notes (usn);
+CREATE INDEX ix_cards_usn ON cards (usn);
+CREATE INDEX ix_revlog_usn ON revlog (usn);
+CREATE INDEX ix_cards_nid ON cards (nid);
+CREATE INDEX ix_cards_sched ON cards (did, queue, due);
+CREATE INDEX ix_revlog_cid ON revlog (cid);
+CREATE INDEX ix_notes_csum ON notes (csum);
+";
+
+pub struct AnkiOptions {
+    pub note_type: NoteType,
+    /// Put the card's example sentences on the back.
+    pub examples: bool,
+    pub style: PinyinStyle,
+}
+
+/// Writes `cards` as an Anki package: a SQLite collection plus an empty media manifest,
+/// zipped. Notes get a GUID derived from the card key, so importing a later export of the
+/// same deck updates the notes instead of duplicating them.
+pub fn export_anki(deck_name: &str, cards: &[Card], options: &AnkiOptions, path: &str) -> Result<(), AnkiError> {
+    let collection_path = temp_collection_path();
+    let result = write_collection(&collection_path, deck_name, cards, options).and_then(|_| {
+        let mut package = ZipWriter::new(fs::File::create(path)?);
+        let file_options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        package.start_file("collection.anki2", file_options)?;
+        package.write_all(&fs::read(&collection_path)?)?;
+        package.start_file("media", file_options)?;
+        package.write_all(b"{}")?;
+        package.finish()?;
+        Ok(())
+    });
+    let _ = fs::remove_file(&collection_path);
+    result
+}
+
+fn temp_collection_path() -> PathBuf {
+    std::env::temp_dir().join(format!("{}-{}.anki2", std::process::id(), Utc::now().timestamp_nanos_opt().unwrap_or_default()))
+}
+
+fn write_collection(path: &PathBuf, deck_name: &str, cards: &[Card], options: &AnkiOptions) -> Result<(), AnkiError> {
+    let now = Utc::now();
+    let now_ms = now.timestamp_millis();
+    let now_s = now.timestamp();
+    // Stable ids, so the deck and note type are reused when the same deck is exported again.
+    let deck_id = stable_id(&format!("deck {}", deck_name));
+    let model_id = stable_id(&format!("model {:?}", options.note_type));
+
+    let conn = Connection::open(path)?;
+    conn.execute_batch(SCHEMA)?;
+    conn.execute(
+        "INSERT INTO col VALUES (1, ?1, ?2, ?2, 11, 0, 0, 0, ?3, ?4, ?5, ?6, '{}')",
+        params![
+            now_s,
+            now_ms,
+            json!({
+                "nextPos": cards.len() + 1, "estTimes": true, "activeDecks": [deck_id], "sortType": "noteFld",
+                "timeLim": 0, "sortBackwards": false, "addToCur": true, "curDeck": deck_id, "newSpread": 0,
+                "dueCounts": true, "curModel": model_id.to_string(), "collapseTime": 1200,
+            }).to_string(),
+            json!({ model_id.to_string(): model(model_id, deck_id, options.note_type, now_s) }).to_string(),
+            json!({ "1": deck(1, "Default", now_s), deck_id.to_string(): deck(deck_id, deck_name, now_s) }).to_string(),
+            json!({ "1": deck_options(now_s) }).to_string(),
+        ],
+    )?;
+
+    let templates = templates(options.note_type);
+    let tx = conn.unchecked_transaction()?;
+    let mut card_id = now_ms;
+    for (position, card) in cards.iter().enumerate() {
+        let note_id = now_ms + position as i64;
+        let fields = note_fields(card, options);
+        let tags: BTreeSet<String> = card.category.iter().map(|category| tag(category)).collect();
+        let tags = if tags.is_empty() { String::new() } else { format!(" {} ", tags.into_iter().collect::<Vec<_>>().join(" ")) };
+        tx.execute(
+            "INSERT INTO notes VALUES (?1, ?2, ?3, ?4, -1, ?5, ?6, ?7, ?8, 0, '')",
+            params![note_id, guid(&card.key()), model_id, now_s, tags, fields.join("\x1f"), card.character, checksum(&card.character)],
+        )?;
+        for (ord, (_, _, _, required_field)) in templates.iter().enumerate() {
+            // Anki doesn't generate a card whose front would be empty.
+            if fields[*required_field].is_empty() {
+                continue;
+            }
+            tx.execute(
+                "INSERT INTO cards VALUES (?1, ?2, ?3, ?4, ?5, -1, 0, 0, ?6, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+                params![card_id, note_id, deck_id, ord as i64, now_s, position as i64 + 1],
+            )?;
+            card_id += 1;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn note_fields(card: &Card, options: &AnkiOptions) -> Vec<String> {
+    let examples = if options.examples { card.examples.iter().map(|example| escape(example)).collect::<Vec<_>>().join("<br>") } else { String::new() };
+    vec![
+        escape(&card.character),
+        escape(card.traditional.as_deref().unwrap_or_default()),
+        escape(&pinyin::display(&card.pinyin, options.style)),
+        escape(&card.definitions.join("; ")),
+        examples,
+        escape(&card.notes),
+    ]
+}
+
+/// Name, front, back and the index of the field the front needs, for each card of the note type.
+fn templates(note_type: NoteType) -> Vec<(&'static str, String, String, usize)> {
+    let recognition = (
+        "Recognition",
+        RECOGNITION_FRONT.to_string(),
+        format!("{{{{FrontSide}}}}<hr id=answer><div class=pinyin>{{{{Pinyin}}}}</div><div class=meaning>{{{{Meaning}}}}</div>{}", BACK_DETAILS),
+        0,
+    );
+    let recall = (
+        "Recall",
+        RECALL_FRONT.to_string(),
+        format!("{{{{FrontSide}}}}<hr id=answer><div class=hanzi>{{{{Hanzi}}}}</div><div class=pinyin>{{{{Pinyin}}}}</div>{}", BACK_DETAILS),
+        3,
+    );
+    match note_type {
+        NoteType::Basic => vec![recognition],
+        NoteType::Reverse => vec![recall],
+        NoteType::Both => vec![recognition, recall],
+    }
+}
+
+fn model(id: i64, deck_id: i64, note_type: NoteType, now_s: i64) -> Value {
+    let templates = templates(note_type);
+    let name = match note_type {
+        NoteType::Basic => "Chinese (hanzi to meaning)",
+        NoteType::Reverse => "Chinese (meaning to hanzi)",
+        NoteType::Both => "Chinese (both directions)",
+    };
+    json!({
+        "id": id,
+        "name": name,
+        "type": 0,
+        "mod": now_s,
+        "usn": -1,
+        "sortf": 0,
+        "did": deck_id,
+        "tmpls": templates.iter().enumerate().map(|(ord, (name, front, back, _))| json!({
+            "name": name, "ord": ord, "qfmt": front, "afmt": back, "did": null, "bqfmt": "", "bafmt": "",
+        })).collect::<Vec<_>>(),
+        "flds": FIELDS.iter().enumerate().map(|(ord, name)| json!({
+            "name": name, "ord": ord, "sticky": false, "rtl": false, "font": "Arial", "size": 20, "media": [],
+        })).collect::<Vec<_>>(),
+        "css": CSS,
+        "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage[utf8]{inputenc}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+        "latexPost": "\\end{document}",
+        "tags": [],
+        "vers": [],
+        "req": templates.iter().enumerate().map(|(ord, (_, _, _, field))| json!([ord, "any", [field]])).collect::<Vec<_>>(),
+    })
+}
+
+fn deck(id: i64, name: &str, now_s: i64) -> Value {
+    json!({
+        "id": id, "name": name, "desc": "", "mod": now_s, "usn": -1, "collapsed": false, "browserCollapsed": false,
+        "newToday": [0, 0], "revToday": [0, 0], "lrnToday": [0, 0], "timeToday": [0, 0],
+        "dyn": 0, "conf": 1, "extendNew": 10, "extendRev": 50,
+    })
+}
+
+fn deck_options(now_s: i64) -> Value {
+    json!({
+        "id": 1, "name": "Default", "mod": now_s, "usn": -1, "maxTaken": 60, "autoplay": true, "timer": 0,
+        "replayq": true, "dyn": false,
+        "new": { "delays": [1, 10], "ints": [1, 4, 7], "initialFactor": 2500, "order": 1, "perDay": 20, "bury": true, "separate": true },
+        "rev": { "perDay": 200, "ease4": 1.3, "fuzz": 0.05, "ivlFct": 1, "maxIvl": 36500, "bury": true, "minSpace": 1, "hardFactor": 1.2 },
+        "lapse": { "delays": [10], "mult": 0, "minInt": 1, "leechFails": 8, "leechAction": 0 },
+    })
+}
+
+/// Anki tags can't contain spaces.
+fn tag(category: &str) -> String {
+    category.split_whitespace().collect::<Vec<_>>().join("_")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn guid(key: &str) -> String {
+    sha1_smol::Sha1::from(key).digest().to_string()[..16].to_string()
+}
+
+/// The first 8 hex digits of the SHA-1 of the sort field, which Anki uses to find duplicates.
+fn checksum(field: &str) -> i64 {
+    i64::from_str_radix(&sha1_smol::Sha1::from(field).digest().to_string()[..8], 16).unwrap_or(0)
+}
+
+fn stable_id(seed: &str) -> i64 {
+    // Keep it in the range of the millisecond timestamps Anki uses for ids.
+    let hash = i64::from_str_radix(&sha1_smol::Sha1::from(seed).digest().to_string()[..10], 16).unwrap_or(0);
+    1_500_000_000_000 + hash % 100_000_000_000
+}
+
+/// Where imported cards get their categories from.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum CategorySource {
+    /// The decks the note's cards are in
+    Deck,
+    /// The note's tags
+    Tags,
+    /// Both decks and tags
+    Both,
+}
+
+pub struct AnkiImportOptions {
+    pub mapping: Vec<FieldAssignment>,
+    pub categories: CategorySource,
+    /// A category for every card, on top of the ones from decks and tags.
+    pub category: Option<String>,
+    /// Carry the review state of the note's first card over to the card's schedule.
+    pub intervals: bool,
+}
+
+/// The review state of an Anki card, as stored in the collection's `cards` table.
+#[derive(Debug, Clone)]
+pub struct AnkiSchedule {
+    /// 0 new, 1 learning, 2 review, 3 relearning.
+    pub kind: i64,
+    /// A day number counted from `day_zero` for review cards, a unix time for cards in learning.
+    pub due: i64,
+    /// Days, or negative seconds while in learning.
+    pub interval: i64,
+    /// Ease in permille.
+    pub factor: i64,
+    pub reps: i64,
+    pub lapses: i64,
+    /// The collection's creation time, which review day numbers count from.
+    pub day_zero: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct AnkiNote {
+    /// Where the note is in the file, for messages about it.
+    pub location: String,
+    pub fields: Vec<Field>,
+    pub tags: Vec<String>,
+    /// Deck names, with `::` between parent and child decks.
+    pub decks: Vec<String>,
+    pub created: Option<DateTime<Utc>>,
+    pub modified: Option<DateTime<Utc>>,
+    pub schedule: Option<AnkiSchedule>,
+}
+
+impl AnkiSchedule {
+    fn to_schedule(&self) -> Schedule {
+        if self.kind == 0 {
+            return Schedule::default();
+        }
+        // Day numbers are small; anything past 2001 in seconds is a timestamp.
+        let due = if self.due > 1_000_000_000 { self.due } else { self.day_zero + self.due * 86_400 };
+        let interval_days = self.interval.max(0) as u32;
+        Schedule {
+            due: DateTime::from_timestamp(due, 0).or(Some(Utc::now())),
+            interval_days,
+            ease: if self.factor > 0 { self.factor as f32 / 1000.0 } else { Schedule::default().ease },
+            // Anki doesn't count successful reviews in a row, so a card with an interval is taken
+            // to be past the fixed first steps and keeps growing from the interval it had.
+            repetitions: if interval_days > 0 { (self.reps.max(2)) as u32 } else { 0 },
+            lapses: self.lapses.max(0) as u32,
+            history: Vec::new(),
+        }
+    }
+}
+
+impl AnkiNote {
+    /// Which field was read into which part of the card, like `Front -> hanzi, Back -> meaning`.
+    pub fn describe_mapping(&self, mapping: &[FieldAssignment]) -> String {
+        mapping::describe(&self.fields, mapping)
+    }
+
+    pub fn to_card(&self, options: &AnkiImportOptions, source: Source) -> Result<Card, String> {
+        let value = |target: FieldTarget| mapping::find(&self.fields, target, &options.mapping).map(|field| plain_text(&field.value)).unwrap_or_default();
+        let lines = |target: FieldTarget| -> Vec<String> {
+            value(target).lines().map(str::trim).filter(|line| !line.is_empty()).map(str::to_string).collect()
+        };
+        let hanzi_field = value(FieldTarget::Hanzi);
+        static HAN: OnceLock<Regex> = OnceLock::new();
+        let han = HAN.get_or_init(|| Regex::new(r"\p{Han}+").expect("Regex failed to initialize"));
+        let Some(hanzi) = han.find(&hanzi_field) else {
+            return Err(format!("no Chinese in its hanzi field \"{}\"", hanzi_field));
+        };
+
+        let mut categories: Vec<String> = Vec::new();
+        if options.categories != CategorySource::Tags {
+            categories.extend(self.decks.iter().map(|deck| deck.replace("::", "/")));
+        }
+        if options.categories != CategorySource::Deck {
+            // leech and marked are Anki's own flags rather than topics.
+            categories.extend(
+                self.tags
+                    .iter()
+                    .filter(|tag| !tag.eq_ignore_ascii_case("leech") && !tag.eq_ignore_ascii_case("marked"))
+                    .map(|tag| tag.replace("::", "/").replace('_', " ")),
+            );
+        }
+        categories.extend(lines(FieldTarget::Category));
+        let mut seen = BTreeSet::new();
+        categories.retain(|category| seen.insert(category.to_lowercase()));
+
+        let mut card = Card::new(hanzi.as_str(), categories, &value(FieldTarget::Pinyin).replace('\n', " "), source);
+        card.definitions = lines(FieldTarget::Meaning);
+        card.traditional = han.find(&value(FieldTarget::Traditional)).map(|traditional| traditional.as_str().to_string()).filter(|traditional| *traditional != card.character);
+        card.examples = lines(FieldTarget::Examples);
+        card.notes = value(FieldTarget::Notes);
+        card.created = self.created.or(card.created);
+        card.modified = self.modified.or(card.modified);
+        if options.intervals {
+            if let Some(schedule) = &self.schedule {
+                card.schedule = schedule.to_schedule();
+            }
+        }
+        Ok(card)
+    }
+}
+
+/// Reads the notes of an Anki package (`.apkg` or `.colpkg`), in any of the collection formats
+/// Anki has written.
+pub fn read_package(path: &str) -> Result<Vec<AnkiNote>, AnkiError> {
+    let mut archive = ZipArchive::new(fs::File::open(path)?)?;
+    // Newer packages hold a zstd compressed collection next to a stub asking to upgrade Anki.
+    let Some(name) = ["collection.anki21b", "collection.anki21", "collection.anki2"]
+        .into_iter()
+        .find(|name| archive.index_for_name(name).is_some())
+    else {
+        return Err(AnkiError(format!("{} is not an Anki package, it has no collection", path)));
+    };
+    let mut contents = Vec::new();
+    let mut entry = archive.by_name(name)?;
+    if name.ends_with('b') {
+        StreamingDecoder::new(&mut entry)
+            .map_err(|e| AnkiError(format!("Failed to decompress the collection: {}", e)))?
+            .read_to_end(&mut contents)?;
+    } else {
+        entry.read_to_end(&mut contents)?;
+    }
+
+    let collection_path = temp_collection_path();
+    fs::write(&collection_path, contents)?;
+    let notes = Connection::open(&collection_path).map_err(AnkiError::from).and_then(|conn| read_collection(&conn));
+    let _ = fs::remove_file(&collection_path);
+    notes
+}
+
+fn read_collection(conn: &Connection) -> Result<Vec<AnkiNote>, AnkiError> {
+    let (day_zero, models, decks): (i64, String, String) =
+        conn.query_row("SELECT crt, models, decks FROM col", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+
+    // Collections written since Anki 2.1.28 keep note types and decks in tables of their own.
+    let has_tables: bool = conn.query_row("SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'fields'", [], |row| row.get(0))?;
+    let mut field_names: HashMap<i64, Vec<String>> = HashMap::new();
+    let mut deck_names: HashMap<i64, String> = HashMap::new();
+    if has_tables {
+        let mut statement = conn.prepare("SELECT ntid, name FROM fields ORDER BY ntid, ord")?;
+        for row in statement.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))? {
+            let (note_type, name) = row?;
+            field_names.entry(note_type).or_default().push(name);
+        }
+        let mut statement = conn.prepare("SELECT id, name FROM decks")?;
+        for row in statement.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))? {
+            let (id, name) = row?;
+            deck_names.insert(id, name.replace('\x1f', "::"));
+        }
+    } else {
+        let models: HashMap<String, Value> = serde_json::from_str(&models)?;
+        for (id, model) in models {
+            let names = model["flds"].as_array().into_iter().flatten().filter_map(|field| field["name"].as_str().map(str::to_string)).collect();
+            field_names.insert(id.parse().unwrap_or_default(), names);
+        }
+        let decks: HashMap<String, Value> = serde_json::from_str(&decks)?;
+        for (id, deck) in decks {
+            deck_names.insert(id.parse().unwrap_or_default(), deck["name"].as_str().unwrap_or_default().to_string());
+        }
+    }
+
+    let mut note_decks: HashMap<i64, Vec<String>> = HashMap::new();
+    let mut schedules: HashMap<i64, AnkiSchedule> = HashMap::new();
+    let mut statement = conn.prepare("SELECT nid, did, type, due, ivl, factor, reps, lapses FROM cards ORDER BY nid, ord")?;
+    let rows = statement.query_map([], |row| {
+        let schedule = AnkiSchedule {
+            kind: row.get(2)?,
+            due: row.get(3)?,
+            interval: row.get(4)?,
+            factor: row.get(5)?,
+            reps: row.get(6)?,
+            lapses: row.get(7)?,
+            day_zero,
+        };
+        Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, schedule))
+    })?;
+    for row in rows {
+        let (note_id, deck_id, schedule) = row?;
+        let decks = note_decks.entry(note_id).or_default();
+        if let Some(name) = deck_names.get(&deck_id).filter(|name| !decks.contains(name)) {
+            decks.push(name.clone());
+        }
+        // The first card, recognition in most note types, stands for the note.
+        schedules.entry(note_id).or_insert(schedule);
+    }
+
+    let mut notes = Vec::new();
+    let mut statement = conn.prepare("SELECT id, mid, mod, tags, flds FROM notes ORDER BY id")?;
+    let rows = statement.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?, row.get::<_, String>(3)?, row.get::<_, String>(4)?))
+    })?;
+    for (position, row) in rows.enumerate() {
+        let (id, note_type, modified, tags, fields) = row?;
+        let names = field_names.get(&note_type);
+        notes.push(AnkiNote {
+            location: format!("note {}", position + 1),
+            fields: fields
+                .split('\x1f')
+                .enumerate()
+                .map(|(index, value)| Field {
+                    name: names.and_then(|names| names.get(index)).cloned(),
+                    column: index + 1,
+                    value: value.to_string(),
+                })
+                .collect(),
+            tags: tags.split_whitespace().map(str::to_string).collect(),
+            decks: note_decks.remove(&id).unwrap_or_default(),
+            // Note ids are the creation time in milliseconds.
+            created: DateTime::from_timestamp_millis(id),
+            modified: DateTime::from_timestamp(modified, 0),
+            schedule: schedules.remove(&id),
+        });
+    }
+    Ok(notes)
+}
+
+/// Reads Anki's "Notes in Plain Text" export. The `#` header lines Anki writes say which
+/// columns hold the deck and tags; without them every column is a field.
+pub fn read_text(path: &str) -> Result<Vec<AnkiNote>, AnkiError> {
+    let contents = fs::read_to_string(path)?;
+    let mut delimiter = b'\t';
+    let mut special_columns: HashMap<String, usize> = HashMap::new();
+    let mut header_lines = 0;
+    for line in contents.lines() {
+        let Some(header) = line.strip_prefix('#') else {
+            break;
+        };
+        header_lines += 1;
+        let Some((key, value)) = header.split_once(':') else {
+            continue;
+        };
+        match key.trim() {
+            "separator" => delimiter = spreadsheet::parse_delimiter(value.trim()).map_err(AnkiError)?,
+            key if key.ends_with(" column") => {
+                let column = value.trim().parse().map_err(|_| AnkiError(format!("Invalid column number in header #{}", header)))?;
+                special_columns.insert(key.trim_end_matches(" column").to_string(), column);
+            },
+            _ => {},
+        }
+    }
+
+    let body: String = contents.split_inclusive('\n').skip(header_lines).collect();
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(body.as_bytes());
+    let mut notes = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let line = header_lines + record.position().map(|position| position.line() as usize).unwrap_or_default();
+        let special = |name: &str| special_columns.get(name).and_then(|column| record.get(column - 1)).unwrap_or_default();
+        notes.push(AnkiNote {
+            location: format!("line {}", line),
+            fields: record
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| !special_columns.values().any(|column| *column == index + 1))
+                .map(|(index, value)| Field { name: None, column: index + 1, value: value.to_string() })
+                .collect(),
+            tags: special("tags").split_whitespace().map(str::to_string).collect(),
+            decks: Some(special("deck").to_string()).filter(|deck| !deck.is_empty()).into_iter().collect(),
+            created: None,
+            modified: None,
+            schedule: None,
+        });
+    }
+    Ok(notes)
+}
+
+/// Anki fields are HTML. Keeps the text, one line per `<br>` or block, without sound tags.
+/// Called for every field of every note, so the regexes are only compiled once.
+fn plain_text(html: &str) -> String {
+    static BREAKS: OnceLock<Regex> = OnceLock::new();
+    static TAGS: OnceLock<Regex> = OnceLock::new();
+    let breaks = BREAKS.get_or_init(|| Regex::new(r"(?i)<br\s*/?>|</?(div|p|li)[^>]*>").expect("Regex failed to initialize"));
+    let tags = TAGS.get_or_init(|| Regex::new(r"<[^>]*>|\[sound:[^\]]*\]").expect("Regex failed to initialize"));
+    let text = breaks.replace_all(html, "\n");
+    let text = tags.replace_all(&text, "");
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    text.lines().map(str::trim).filter(|line| !line.is_empty()).collect::<Vec<_>>().join("\n")
+}
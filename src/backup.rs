@@ -0,0 +1,277 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use chrono::{DateTime, Duration, FixedOffset, Local, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+
+use crate::db::{parse_db, DBError, Storage, DB};
+use crate::sqlite::SqliteStorage;
+
+const POLICY_FILE: &str = "retention.json";
+const COMPRESSED_EXTENSION: &str = "gz";
+/// How SQLite database files start, to tell them from flat file backups.
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+/// Where every backup went, relative to where the tool ran, before each DB had its own directory.
+const LEGACY_DIR: &str = "backups";
+
+/// Numbers the temporary copies SQLite backups are read from, so reading several doesn't clash.
+static READS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+pub struct BackupError(String);
+
+impl std::fmt::Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+impl From<std::io::Error> for BackupError {
+    fn from(e: std::io::Error) -> Self {
+        BackupError(format!("IO error: {}", e))
+    }
+}
+
+impl From<serde_json::Error> for BackupError {
+    fn from(e: serde_json::Error) -> Self {
+        BackupError(format!("Serde error: {}", e))
+    }
+}
+
+impl From<DBError> for BackupError {
+    fn from(e: DBError) -> Self {
+        BackupError(format!("DB error: {}", e))
+    }
+}
+
+/// How many backups survive a prune. The newest `keep_last` stay as they are; beyond those,
+/// the newest backup of each of the last `keep_daily_days` days is kept gzip-compressed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub keep_daily_days: u32,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy { keep_last: 10, keep_daily_days: 30 }
+    }
+}
+
+pub struct Backup {
+    /// The RFC 3339 time the backup was taken, which is also its file name.
+    pub id: String,
+    pub taken_at: DateTime<FixedOffset>,
+    pub path: PathBuf,
+    pub compressed: bool,
+}
+
+impl Backup {
+    pub fn read(&self) -> Result<DB, BackupError> {
+        let mut contents = Vec::new();
+        if self.compressed {
+            GzDecoder::new(fs::File::open(&self.path)?).read_to_end(&mut contents)?;
+        } else {
+            contents = fs::read(&self.path)?;
+        }
+        if !contents.starts_with(SQLITE_HEADER) {
+            let contents = String::from_utf8(contents).map_err(|e| BackupError(format!("Backup {} is not valid UTF-8: {}", self.id, e)))?;
+            return Ok(parse_db(&contents)?);
+        }
+        // Opening a SQLite DB may upgrade it, so a copy is read to leave the backup as it was.
+        let read = READS.fetch_add(1, Ordering::Relaxed);
+        let copy = std::env::temp_dir().join(format!("backup-{}-{}.sqlite", std::process::id(), read));
+        fs::write(&copy, contents)?;
+        let db = SqliteStorage::open_without_backups(&copy.to_string_lossy()).and_then(|storage| storage.load());
+        let _ = fs::remove_file(&copy);
+        Ok(db?)
+    }
+
+    pub fn size(&self) -> u64 {
+        fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or(0)
+    }
+}
+
+/// Backups are kept next to the DB they were taken of.
+pub fn backup_dir(db_location: &str) -> PathBuf {
+    PathBuf::from(format!("{}.backups", db_location))
+}
+
+/// Copies what `storage` holds into the backup directory of the DB at `db_location` and prunes
+/// by the saved retention policy.
+pub fn create_backup(storage: &dyn Storage, db_location: &str) -> Result<(), BackupError> {
+    let directory = backup_dir(db_location);
+    fs::create_dir_all(&directory)?;
+    if storage.backup(&directory.join(Local::now().to_rfc3339()))? {
+        prune(&directory, &load_policy(&directory)?, false)?;
+    }
+    Ok(())
+}
+
+/// Every backup in `directory`, newest first.
+pub fn list_backups(directory: &Path) -> Result<Vec<Backup>, BackupError> {
+    if !directory.exists() {
+        return Ok(Vec::new());
+    }
+    let mut backups: Vec<Backup> = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let compressed_suffix = format!(".{}", COMPRESSED_EXTENSION);
+        let (id, compressed) = match file_name.strip_suffix(&compressed_suffix) {
+            Some(id) => (id.to_string(), true),
+            None => (file_name.to_string(), false),
+        };
+        // Anything that isn't named after its timestamp, like the policy file, is not a backup.
+        if let Ok(taken_at) = DateTime::parse_from_rfc3339(&id) {
+            backups.push(Backup { id, taken_at, path, compressed });
+        }
+    }
+    backups.sort_by_key(|backup| std::cmp::Reverse(backup.taken_at));
+    Ok(backups)
+}
+
+/// The backups still in the old shared `./backups` directory. They are all of the flat file DB.
+pub fn legacy_backups() -> Result<Vec<Backup>, BackupError> {
+    list_backups(Path::new(LEGACY_DIR))
+}
+
+/// Moves the backups in the old shared `./backups` directory into `directory`, returning how
+/// many there were. The directory itself is removed once nothing else is left in it.
+pub fn adopt_legacy_backups(directory: &Path) -> Result<usize, BackupError> {
+    let backups = legacy_backups()?;
+    if backups.is_empty() {
+        return Ok(0);
+    }
+    fs::create_dir_all(directory)?;
+    for backup in &backups {
+        let target = directory.join(backup.path.file_name().expect("Backups are named after their timestamp"));
+        // Renaming fails when the DB is on another file system than the working directory.
+        if fs::rename(&backup.path, &target).is_err() {
+            fs::copy(&backup.path, &target)?;
+            fs::remove_file(&backup.path)?;
+        }
+    }
+    let _ = fs::remove_dir(LEGACY_DIR);
+    Ok(backups.len())
+}
+
+/// Finds a backup by its id, or by its position in `backup list` where 1 is the newest.
+pub fn find_backup(directory: &Path, id: &str) -> Result<Backup, BackupError> {
+    let mut backups = list_backups(directory)?;
+    if let Ok(position) = id.parse::<usize>() {
+        if position >= 1 && position <= backups.len() {
+            return Ok(backups.remove(position - 1));
+        }
+    }
+    backups
+        .into_iter()
+        .find(|backup| backup.id == id)
+        .ok_or(BackupError(format!("No backup {}", id)))
+}
+
+pub struct PruneReport {
+    pub compressed: Vec<String>,
+    pub deleted: Vec<String>,
+}
+
+pub fn prune(directory: &Path, policy: &RetentionPolicy, dry_run: bool) -> Result<PruneReport, BackupError> {
+    let cutoff = Utc::now() - Duration::days(policy.keep_daily_days as i64);
+    let mut days_kept: HashSet<chrono::NaiveDate> = HashSet::new();
+    let mut report = PruneReport { compressed: Vec::new(), deleted: Vec::new() };
+    for backup in list_backups(directory)?.into_iter().skip(policy.keep_last) {
+        let day = backup.taken_at.with_timezone(&Local).date_naive();
+        if backup.taken_at >= cutoff && days_kept.insert(day) {
+            if !backup.compressed {
+                if !dry_run {
+                    compress(&backup.path)?;
+                }
+                report.compressed.push(backup.id);
+            }
+        } else {
+            if !dry_run {
+                fs::remove_file(&backup.path)?;
+            }
+            report.deleted.push(backup.id);
+        }
+    }
+    Ok(report)
+}
+
+fn compress(path: &Path) -> Result<(), BackupError> {
+    let compressed_path = PathBuf::from(format!("{}.{}", path.display(), COMPRESSED_EXTENSION));
+    let mut encoder = GzEncoder::new(fs::File::create(&compressed_path)?, Compression::default());
+    encoder.write_all(&fs::read(path)?)?;
+    encoder.finish()?;
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+pub fn load_policy(directory: &Path) -> Result<RetentionPolicy, BackupError> {
+    let path = directory.join(POLICY_FILE);
+    if !path.exists() {
+        return Ok(RetentionPolicy::default());
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+pub fn save_policy(directory: &Path, policy: &RetentionPolicy) -> Result<(), BackupError> {
+    fs::create_dir_all(directory)?;
+    fs::write(directory.join(POLICY_FILE), serde_json::to_string_pretty(policy)?)?;
+    Ok(())
+}
+
+/// Which cards differ between a backup and the live DB.
+pub struct BackupDiff {
+    /// In the live DB but not in the backup.
+    pub added: Vec<String>,
+    /// In the backup but no longer in the live DB.
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    pub unchanged: usize,
+}
+
+pub fn diff(backup: &DB, live: &DB) -> Result<BackupDiff, BackupError> {
+    let mut diff = BackupDiff { added: Vec::new(), removed: Vec::new(), changed: Vec::new(), unchanged: 0 };
+    for (key, card) in live {
+        match backup.get(key) {
+            None => diff.added.push(key.clone()),
+            Some(old) if serde_json::to_value(old)? != serde_json::to_value(card)? => diff.changed.push(key.clone()),
+            Some(_) => diff.unchanged += 1,
+        }
+    }
+    diff.removed = backup.keys().filter(|key| !live.contains_key(*key)).cloned().collect();
+    diff.added.sort();
+    diff.removed.sort();
+    diff.changed.sort();
+    Ok(diff)
+}
+
+impl std::fmt::Display for BackupDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} added, {} removed, {} changed, {} unchanged since the backup",
+            self.added.len(), self.removed.len(), self.changed.len(), self.unchanged
+        )?;
+        for (marker, keys) in [("+", &self.added), ("-", &self.removed), ("~", &self.changed)] {
+            for key in keys.iter().take(10) {
+                writeln!(f, "  {} {}", marker, key)?;
+            }
+            if keys.len() > 10 {
+                writeln!(f, "  {} ... and {} more", marker, keys.len() - 10)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{rng, SeedableRng};
+use regex::Regex;
+
+use crate::db::DB;
+use crate::staging::Candidate;
+
+#[derive(Debug)]
+pub struct ChunkError(String);
+
+impl std::fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+/// The order words are filed into chunks in.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ChunkOrder {
+    /// As they appear in the source, page by page
+    Source,
+    /// Shuffled, the same way every time when given a --seed
+    Shuffle,
+    /// Most frequent first, by a --frequencies list
+    Frequency,
+    /// Lowest HSK level first, by a --levels list
+    Hsk,
+}
+
+pub struct ChunkOptions {
+    pub size: usize,
+    pub order: ChunkOrder,
+    pub seed: Option<u64>,
+    /// The name of each chunk, with `{category}` and `{n}` filled in.
+    pub name: String,
+    pub frequencies: Option<HashMap<String, u64>>,
+    pub levels: Option<HashMap<String, u32>>,
+}
+
+impl ChunkOptions {
+    pub fn new(
+        size: usize,
+        order: ChunkOrder,
+        seed: Option<u64>,
+        name: &str,
+        frequencies: Option<HashMap<String, u64>>,
+        levels: Option<HashMap<String, u32>>,
+    ) -> Result<Self, ChunkError> {
+        if size == 0 {
+            return Err(ChunkError("Chunks need room for at least one word".to_string()));
+        }
+        if !name.contains("{n}") {
+            return Err(ChunkError(format!("The chunk name {} needs {{n}} for the chunk's number", name)));
+        }
+        match order {
+            ChunkOrder::Frequency if frequencies.is_none() => return Err(ChunkError("Ordering by frequency needs a --frequencies list".to_string())),
+            ChunkOrder::Hsk if levels.is_none() => return Err(ChunkError("Ordering by HSK level needs a --levels list".to_string())),
+            _ => {},
+        }
+        Ok(ChunkOptions { size, order, seed, name: name.to_string(), frequencies, levels })
+    }
+
+    fn chunk_name(&self, category: &str, number: usize) -> String {
+        self.name.replace("{category}", category).replace("{n}", &number.to_string())
+    }
+
+    /// Matches the names of `category`'s chunks, capturing their number.
+    fn chunk_pattern(&self, category: &str) -> Regex {
+        let pattern = regex::escape(&self.name).replace(r"\{category\}", &regex::escape(category)).replace(r"\{n\}", r"(\d+)");
+        Regex::new(&format!("^{}$", pattern)).expect("Regex failed to initialize")
+    }
+}
+
+/// Reads an HSK word list, one `word level` per line, like `爱 1`.
+pub fn load_levels(path: &str) -> Result<HashMap<String, u32>, ChunkError> {
+    let contents = fs::read_to_string(path).map_err(|e| ChunkError(format!("Failed to read HSK list {}: {}", path, e)))?;
+    let mut levels = HashMap::new();
+    for line in contents.lines() {
+        let mut fields = line.split_whitespace();
+        if let (Some(word), Some(level)) = (fields.next(), fields.next()) {
+            if let Ok(level) = level.trim_start_matches(|c: char| !c.is_ascii_digit()).parse::<u32>() {
+                levels.entry(word.to_string()).or_insert(level);
+            }
+        }
+    }
+    Ok(levels)
+}
+
+/// Orders the new words of a category by `options.order`. Words missing from the frequency or
+/// HSK list go last, in source order.
+fn order(words: &mut [(usize, Candidate)], options: &ChunkOptions) {
+    match options.order {
+        ChunkOrder::Source => {},
+        ChunkOrder::Shuffle => match options.seed {
+            Some(seed) => words.shuffle(&mut StdRng::seed_from_u64(seed)),
+            None => words.shuffle(&mut rng()),
+        },
+        ChunkOrder::Frequency => {
+            let frequencies = options.frequencies.clone().unwrap_or_default();
+            words.sort_by_key(|(_, word)| std::cmp::Reverse(frequencies.get(&word.word).copied().unwrap_or(0)));
+        },
+        ChunkOrder::Hsk => {
+            let levels = options.levels.clone().unwrap_or_default();
+            words.sort_by_key(|(_, word)| levels.get(&word.word).copied().unwrap_or(u32::MAX));
+        },
+    }
+}
+
+/// Files the words of each category into chunks of `options.size`. Words already in one of the
+/// category's chunks, in the DB or waiting in `queued`, are left out, so importing the same
+/// pages again changes nothing. New words fill up the last chunk before starting another.
+/// The words stay in source order, for review.
+pub fn assign(candidates: Vec<Candidate>, options: &ChunkOptions, db: &DB, queued: &[Candidate]) -> Vec<Candidate> {
+    let mut by_category: BTreeMap<String, Vec<(usize, Candidate)>> = BTreeMap::new();
+    for (index, candidate) in candidates.into_iter().enumerate() {
+        by_category.entry(candidate.category.clone()).or_default().push((index, candidate));
+    }
+
+    let mut assigned = Vec::new();
+    for (category, words) in by_category {
+        let pattern = options.chunk_pattern(&category);
+        let number = |name: &str| pattern.captures(name).and_then(|captures| captures[1].parse::<usize>().ok());
+        // How full each existing chunk is, and the words already in one.
+        let mut sizes: BTreeMap<usize, usize> = BTreeMap::new();
+        let mut filed: HashSet<String> = HashSet::new();
+        let existing = db
+            .values()
+            .map(|card| (&card.character, card.category.as_slice()))
+            .chain(queued.iter().map(|queued| (&queued.word, std::slice::from_ref(&queued.category))));
+        for (word, categories) in existing {
+            for chunk in categories.iter().filter_map(|name| number(name)) {
+                *sizes.entry(chunk).or_default() += 1;
+                filed.insert(word.clone());
+            }
+        }
+
+        let total = words.len();
+        let mut new_words: Vec<(usize, Candidate)> = Vec::new();
+        for (index, word) in words {
+            if !filed.contains(&word.word) && !new_words.iter().any(|(_, new_word)| new_word.word == word.word) {
+                new_words.push((index, word));
+            }
+        }
+        if new_words.len() < total {
+            println!("{} of the words for {} are already in its chunks or repeated, leaving them out", total - new_words.len(), category);
+        }
+        order(&mut new_words, options);
+
+        let (mut chunk, mut used) = sizes.iter().next_back().map(|(chunk, size)| (*chunk, *size)).unwrap_or((1, 0));
+        for (index, mut word) in new_words {
+            if used >= options.size {
+                chunk += 1;
+                used = 0;
+            }
+            word.category = options.chunk_name(&category, chunk);
+            used += 1;
+            assigned.push((index, word));
+        }
+    }
+    assigned.sort_by_key(|(index, _)| *index);
+    assigned.into_iter().map(|(_, word)| word).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Card, Source};
+
+    fn candidate(word: &str, category: &str) -> Candidate {
+        Candidate { word: word.to_string(), category: category.to_string(), ..Candidate::default() }
+    }
+
+    fn words(count: usize) -> Vec<Candidate> {
+        (0..count).map(|n| candidate(&format!("词{}", n), "hsk3")).collect()
+    }
+
+    fn options(size: usize, order: ChunkOrder, seed: Option<u64>) -> ChunkOptions {
+        ChunkOptions::new(size, order, seed, "{category}-{n}", None, None).unwrap()
+    }
+
+    fn filing(assigned: &[Candidate]) -> Vec<(&str, &str)> {
+        assigned.iter().map(|word| (word.word.as_str(), word.category.as_str())).collect()
+    }
+
+    #[test]
+    fn words_fill_chunks_in_source_order() {
+        let assigned = assign(words(5), &options(2, ChunkOrder::Source, None), &DB::new(), &[]);
+        assert_eq!(filing(&assigned), vec![
+            ("词0", "hsk3-1"), ("词1", "hsk3-1"), ("词2", "hsk3-2"), ("词3", "hsk3-2"), ("词4", "hsk3-3"),
+        ]);
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_chunks() {
+        let shuffled = |seed| {
+            let assigned = assign(words(20), &options(5, ChunkOrder::Shuffle, Some(seed)), &DB::new(), &[]);
+            assigned.into_iter().map(|word| (word.word, word.category)).collect::<Vec<_>>()
+        };
+        assert_eq!(shuffled(7), shuffled(7));
+        assert_ne!(shuffled(7), shuffled(8));
+        // The words come back in source order whatever chunk they went to.
+        assert!(shuffled(7).iter().map(|(word, _)| word.clone()).eq((0..20).map(|n| format!("词{}", n))));
+    }
+
+    #[test]
+    fn words_already_filed_are_left_out() {
+        let card = Card::new("词0", vec!["hsk3-1".to_string(), "lesson".to_string()], "", Source::new("test", None, None));
+        let db: DB = HashMap::from([(card.key(), card)]);
+        let queued = vec![candidate("词1", "hsk3-1"), candidate("词9", "other-1")];
+        let mut incoming = words(4);
+        incoming.push(candidate("词2", "hsk3"));
+        let assigned = assign(incoming, &options(3, ChunkOrder::Source, None), &db, &queued);
+        // 词0 and 词1 are in chunk 1 already and 词2 is repeated. The rest fill chunk 1 up first.
+        assert_eq!(filing(&assigned), vec![("词2", "hsk3-1"), ("词3", "hsk3-2")]);
+    }
+
+    #[test]
+    fn other_categories_chunks_do_not_count() {
+        let queued = vec![candidate("词0", "hsk30-1"), candidate("词1", "other-1")];
+        let assigned = assign(words(2), &options(3, ChunkOrder::Source, None), &DB::new(), &queued);
+        assert_eq!(filing(&assigned), vec![("词0", "hsk3-1"), ("词1", "hsk3-1")]);
+    }
+}
@@ -1,20 +1,259 @@
 use std::collections::HashMap;
 use std::fs;
-use std::io::Write;
+use std::fs::TryLockError;
+use std::io::{BufWriter, Write};
+use std::path::Path;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use chrono;
+use chrono::{DateTime, Utc};
+use crate::backup::create_backup;
+use crate::migrations::{migrate, MigrationReport, Record, CURRENT_VERSION};
+use crate::pinyin;
+use crate::pleco::{DictRef, ScoreInfo};
+use crate::review::Schedule;
+use crate::sqlite::SqliteStorage;
 
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Card {
     pub character: String,
     pub category: Vec<String>,
-    pub pinyin: String
+    pub pinyin: String,
+    #[serde(default)]
+    pub schedule: Schedule,
+    #[serde(default)]
+    pub definitions: Vec<String>,
+    #[serde(default)]
+    pub traditional: Option<String>,
+    #[serde(default)]
+    pub notes: String,
+    #[serde(default)]
+    pub created: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub modified: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub source: Option<Source>,
+    #[serde(default)]
+    pub dictrefs: Vec<DictRef>,
+    /// Pleco's own scores for the card, kept so they survive a round trip through Pleco.
+    #[serde(default)]
+    pub pleco_scores: Vec<ScoreInfo>,
+    /// Example sentences using the word, shown on the back of exported Anki cards.
+    #[serde(default)]
+    pub examples: Vec<String>,
+}
+
+/// Where a card came from: the command that created it and, when known, the file and page.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Source {
+    pub command: String,
+    pub file: Option<String>,
+    pub page: Option<u32>,
+    /// The word's number in a textbook's vocabulary list, when the page numbered its entries.
+    #[serde(default)]
+    pub entry: Option<u32>,
+}
+
+impl Source {
+    pub fn new(command: &str, file: Option<&str>, page: Option<u32>) -> Self {
+        Source { command: command.to_string(), file: file.map(|f| f.to_string()), page, entry: None }
+    }
+}
+
+impl Card {
+    pub fn new(character: &str, category: Vec<String>, pinyin: &str, source: Source) -> Self {
+        let now = Utc::now();
+        Card {
+            character: character.to_string(),
+            category,
+            pinyin: pinyin::canonical(pinyin),
+            schedule: Schedule::default(),
+            definitions: Vec::new(),
+            traditional: None,
+            notes: String::new(),
+            created: Some(now),
+            modified: Some(now),
+            source: Some(source),
+            dictrefs: Vec::new(),
+            pleco_scores: Vec::new(),
+            examples: Vec::new(),
+        }
+    }
+
+    pub fn touch(&mut self) {
+        self.modified = Some(Utc::now());
+    }
+
+    pub fn key(&self) -> String {
+        card_key(&self.character, &self.pinyin)
+    }
+}
+
+/// Cards are keyed by headword and reading, so heteronyms such as 行 xing2 and 行 hang2 are
+/// separate cards. Cards whose reading isn't known yet are keyed by the bare headword.
+/// The reading is normalized first, so `háng`, `hang2` and `ㄏㄤˊ` give the same key.
+pub fn card_key(character: &str, pinyin: &str) -> String {
+    let reading = pinyin::canonical(pinyin);
+    if reading.is_empty() {
+        character.to_string()
+    } else {
+        format!("{}|{}", character, reading)
+    }
+}
+
+/// Keys written or removed while changing the in-memory `DB`, to hand to `Storage` afterwards.
+#[derive(Debug, Default)]
+pub struct CardChanges {
+    pub saved: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl CardChanges {
+    pub fn persist(&self, storage: &dyn Storage, db: &DB) -> Result<(), DBError> {
+        if !self.removed.is_empty() {
+            storage.remove_cards(db, &self.removed)?;
+        }
+        storage.save_cards(db, &self.saved)
+    }
+}
+
+/// Every card for `headword`, one per reading, matching either the simplified or traditional form.
+pub fn find_cards<'a>(db: &'a DB, headword: &str) -> Vec<(&'a String, &'a Card)> {
+    let mut cards: Vec<(&String, &Card)> = db
+        .iter()
+        .filter(|(_, card)| card.character == headword || card.traditional.as_deref() == Some(headword))
+        .collect();
+    cards.sort_by(|a, b| a.0.cmp(b.0));
+    cards
+}
+
+/// How an imported card is combined with the card already stored for it.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum MergePolicy {
+    /// Add the imported categories, definitions and references and fill in missing fields
+    Union,
+    /// Leave the existing card as it is
+    Keep,
+    /// Take the imported card, keeping only the existing review schedule and notes
+    Overwrite,
+    /// Ask for every card that already exists
+    Interactive,
+}
+
+impl std::fmt::Display for MergePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            MergePolicy::Union => "Union: add the imported categories and details",
+            MergePolicy::Keep => "Keep: leave the existing card alone",
+            MergePolicy::Overwrite => "Overwrite: take the imported card",
+            MergePolicy::Interactive => "Interactive",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// Inserts `card` under its headword + reading key, merging it by `policy` into the card
+/// already stored for it. `Interactive` has to be resolved by the caller and merges as `Union`.
+///
+/// A card without a reading is folded into the existing card when the headword has exactly one
+/// reading, and a card with a reading is merged into a reading-less card for the same headword.
+pub fn insert_card(db: &mut DB, card: Card, policy: MergePolicy, changes: &mut CardChanges) -> String {
+    let Some(existing_key) = existing_key(db, &card) else {
+        let key = card.key();
+        db.insert(key.clone(), card);
+        changes.saved.push(key.clone());
+        return key;
+    };
+    let Some(mut existing) = db.remove(&existing_key) else {
+        return existing_key;
+    };
+    merge_card(&mut existing, card, policy);
+    let key = existing.key();
+    if key != existing_key {
+        changes.removed.push(existing_key);
+    }
+    db.insert(key.clone(), existing);
+    changes.saved.push(key.clone());
+    key
+}
+
+/// The key of the card `card` would be merged into, if there is one.
+pub fn existing_key(db: &DB, card: &Card) -> Option<String> {
+    if card.pinyin.trim().is_empty() {
+        let readings: Vec<&String> = db
+            .iter()
+            .filter(|(key, existing)| existing.character == card.character && **key != card.character)
+            .map(|(key, _)| key)
+            .collect();
+        if let [key] = readings.as_slice() {
+            return Some(key.to_string());
+        }
+    }
+    [card.key(), card.character.clone()].into_iter().find(|key| db.contains_key(key))
+}
+
+fn merge_card(existing: &mut Card, incoming: Card, policy: MergePolicy) {
+    match policy {
+        MergePolicy::Keep => {},
+        MergePolicy::Overwrite => {
+            let schedule = std::mem::take(&mut existing.schedule);
+            let notes = std::mem::take(&mut existing.notes);
+            let examples = std::mem::take(&mut existing.examples);
+            let created = earliest(existing.created, incoming.created);
+            *existing = Card {
+                schedule,
+                notes: if incoming.notes.is_empty() { notes } else { incoming.notes.clone() },
+                examples: if incoming.examples.is_empty() { examples } else { incoming.examples.clone() },
+                created,
+                ..incoming
+            };
+        },
+        MergePolicy::Union | MergePolicy::Interactive => {
+            extend_unique(&mut existing.category, incoming.category);
+            extend_unique(&mut existing.definitions, incoming.definitions);
+            extend_unique(&mut existing.dictrefs, incoming.dictrefs);
+            extend_unique(&mut existing.examples, incoming.examples);
+            for score in incoming.pleco_scores {
+                match existing.pleco_scores.iter_mut().find(|existing| existing.scorefile == score.scorefile) {
+                    Some(existing) => *existing = score,
+                    None => existing.pleco_scores.push(score),
+                }
+            }
+            if existing.pinyin.is_empty() {
+                existing.pinyin = incoming.pinyin;
+            }
+            if existing.traditional.is_none() {
+                existing.traditional = incoming.traditional;
+            }
+            if existing.notes.is_empty() {
+                existing.notes = incoming.notes;
+            }
+            if existing.source.is_none() {
+                existing.source = incoming.source;
+            }
+            existing.created = earliest(existing.created, incoming.created);
+            existing.modified = existing.modified.max(incoming.modified);
+        },
+    }
+}
+
+fn earliest(a: Option<DateTime<Utc>>, b: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        _ => a.or(b),
+    }
+}
+
+fn extend_unique<T: PartialEq>(values: &mut Vec<T>, more: Vec<T>) {
+    for value in more {
+        if !values.contains(&value) {
+            values.push(value);
+        }
+    }
 }
 
 #[derive(Debug)]
-pub struct DBError(String);
+pub struct DBError(pub String);
 
 pub type DB = HashMap<String, Card>;
 
@@ -38,41 +277,203 @@ impl From<std::io::Error> for DBError {
     }
 }
 
-pub fn load_db(path: &str) -> Result<DB, DBError> {
+impl From<rusqlite::Error> for DBError {
+    fn from(e: rusqlite::Error) -> Self {
+        DBError(format!("SQLite error: {}", e))
+    }
+}
+
+/// Where cards are persisted. Callers mutate the in-memory `DB` first and then tell the
+/// storage which keys changed, so backends that can write single rows don't rewrite everything.
+pub trait Storage {
+    fn load(&self) -> Result<DB, DBError>;
+    /// Replaces everything stored with the contents of `db`.
+    fn save(&self, db: &DB) -> Result<(), DBError>;
+    /// Persists the cards under `keys`, which have already been inserted or updated in `db`.
+    fn save_cards(&self, db: &DB, keys: &[String]) -> Result<(), DBError>;
+    /// Persists the removal of `keys`, which have already been removed from `db`.
+    fn remove_cards(&self, db: &DB, keys: &[String]) -> Result<(), DBError>;
+    fn category_cards(&self, category: &str) -> Result<Vec<Card>, DBError>;
+    /// Upgrades stored cards to `CURRENT_VERSION`. With `dry_run` nothing is written.
+    fn migrate(&self, dry_run: bool) -> Result<MigrationReport, DBError>;
+    /// Copies everything stored to a new file at `path`. Returns false, writing nothing, when
+    /// there is nothing stored yet.
+    fn backup(&self, path: &Path) -> Result<bool, DBError>;
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum StorageKind {
+    Flat,
+    Sqlite,
+}
+
+impl StorageKind {
+    pub fn default_location(&self) -> &'static str {
+        match self {
+            StorageKind::Flat => "./data",
+            StorageKind::Sqlite => "./data.sqlite",
+        }
+    }
+}
+
+/// Opens the DB at `path`. Pass `exclusive` when holding its lock exclusively, which lets an
+/// older SQLite DB be upgraded as it is loaded.
+pub fn open_storage(kind: StorageKind, path: &str, exclusive: bool) -> Result<Box<dyn Storage>, DBError> {
+    match kind {
+        StorageKind::Flat => Ok(Box::new(FlatFileStorage::new(path))),
+        StorageKind::Sqlite => Ok(Box::new(SqliteStorage::open(path, exclusive)?)),
+    }
+}
+
+/// The original `key=json` line file. Every write rewrites the whole file.
+pub struct FlatFileStorage {
+    path: String,
+}
+
+impl FlatFileStorage {
+    pub fn new(path: &str) -> Self {
+        FlatFileStorage { path: path.to_string() }
+    }
+
+    /// Every write replaces the whole file, so the file is backed up before each one.
+    fn write(&self, db: &DB) -> Result<(), DBError> {
+        create_backup(self, &self.path).map_err(|e| DBError(format!("Backup failed: {}", e)))?;
+        save_db(&self.path, db)
+    }
+}
+
+impl Storage for FlatFileStorage {
+    fn load(&self) -> Result<DB, DBError> {
+        load_db(&self.path)
+    }
+
+    fn save(&self, db: &DB) -> Result<(), DBError> {
+        self.write(db)
+    }
+
+    fn save_cards(&self, db: &DB, _keys: &[String]) -> Result<(), DBError> {
+        self.write(db)
+    }
+
+    fn remove_cards(&self, db: &DB, _keys: &[String]) -> Result<(), DBError> {
+        self.write(db)
+    }
+
+    fn category_cards(&self, category: &str) -> Result<Vec<Card>, DBError> {
+        let db = load_db(&self.path)?;
+        Ok(get_category_cards(&db).remove(&category.to_lowercase()).unwrap_or_default())
+    }
+
+    fn migrate(&self, dry_run: bool) -> Result<MigrationReport, DBError> {
+        let (version, records) = read_flat_file(&self.path)?;
+        let (records, report) = migrate(records, version)?;
+        if !dry_run && !report.is_empty() {
+            self.write(&records_to_db(records)?)?;
+        }
+        Ok(report)
+    }
+
+    fn backup(&self, path: &Path) -> Result<bool, DBError> {
+        if !Path::new(&self.path).exists() {
+            return Ok(false);
+        }
+        fs::copy(&self.path, path)?;
+        Ok(true)
+    }
+}
+
+/// First line of the flat file. Files without it predate versioning and are version 0.
+const VERSION_HEADER: &str = "#format-version=";
+
+fn read_flat_file(path: &str) -> Result<(u32, Vec<Record>), DBError> {
     let contents: String = fs::read_to_string(path).unwrap_or_default();
-    let mut db: HashMap<String, Card> = HashMap::new();
-    for line in contents.lines() {
+    parse_flat(&contents)
+}
+
+fn parse_flat(contents: &str) -> Result<(u32, Vec<Record>), DBError> {
+    let mut lines = contents.lines().peekable();
+    let mut version = 0;
+    if let Some(header) = lines.peek().and_then(|line| line.strip_prefix(VERSION_HEADER)) {
+        version = header.trim().parse().map_err(|e| DBError(format!("Invalid format version {}: {}", header, e)))?;
+        lines.next();
+    }
+    let mut records: Vec<Record> = Vec::new();
+    for line in lines {
         let kv_option: Option<(&str, &str)> = line.split_once('=');
         if let Some((k, v)) = kv_option {
-            let card: Card = serde_json::from_str(v.trim())?;
-            db.insert(k.trim().to_string(), card);
+            records.push((k.trim().to_string(), serde_json::from_str(v.trim())?));
         }
-        
     }
-    return Ok(db);
+    Ok((version, records))
+}
+
+pub fn records_to_db(records: Vec<Record>) -> Result<DB, DBError> {
+    let mut db: DB = HashMap::new();
+    for (key, value) in records {
+        db.insert(key, serde_json::from_value(value)?);
+    }
+    Ok(db)
+}
+
+/// Loads the flat file, upgrading older formats in memory. The upgrade is written on the next save.
+pub fn load_db(path: &str) -> Result<DB, DBError> {
+    let contents: String = fs::read_to_string(path).unwrap_or_default();
+    parse_db(&contents)
+}
+
+/// Parses flat file contents, such as a backup, upgrading older formats in memory.
+pub fn parse_db(contents: &str) -> Result<DB, DBError> {
+    let (version, records) = parse_flat(contents)?;
+    let (records, _) = migrate(records, version)?;
+    records_to_db(records)
 }
 
+/// Writes the DB to a fresh temp file, fsyncs it and renames it over `path`, so a crash
+/// leaves either the old or the new file in place, never a truncated one.
 pub fn save_db(path: &str, contents: &DB) -> Result<(), DBError>{
     let temp_path  = format!("{}.{}", path, "temp");
-    let backup_path  = format!("backups/{}", chrono::Local::now().to_rfc3339());
     let file_path = path.to_string();
-    fs::File::create(&temp_path)?;
-    if !(fs::exists(&file_path)?) {
-        fs::File::create(&file_path)?;
-    }
-    if !(fs::exists("backups")?) {
-        fs::create_dir("backups")?;
-    }
-    fs::copy(&file_path, &backup_path)?;
-    let mut temp_file = fs::OpenOptions::new().write(true).create(true).append(true).open(&temp_path)?;
+    let mut temp_file = BufWriter::new(fs::File::create(&temp_path)?);
+    temp_file.write_all(format!("{}{}\n", VERSION_HEADER, CURRENT_VERSION).as_bytes())?;
     for (key,value) in contents {
-        temp_file.write(format!("{}={}\n", key, serde_json::to_string(value)?).as_bytes())?;
+        temp_file.write_all(format!("{}={}\n", key, serde_json::to_string(value)?).as_bytes())?;
+    }
+    let temp_file = temp_file.into_inner().map_err(|e| DBError(format!("IO error: {}", e)))?;
+    temp_file.sync_all()?;
+    fs::rename(&temp_path, &file_path)?;
+    // Persist the rename itself. Directories can't be opened for syncing on every platform.
+    let parent = Path::new(&file_path).parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    if let Ok(directory) = fs::File::open(parent) {
+        let _ = directory.sync_all();
     }
-    fs::copy(&temp_path, &file_path)?;
-    fs::remove_file(temp_path)?;
     Ok(())
 }
 
+/// Advisory lock on `<db>.lock`, held for a whole load -> mutate -> save cycle. Released on drop.
+pub struct DBLock {
+    _file: fs::File,
+}
+
+/// Locks the DB at `path`: exclusively for commands that write, shared for ones that only read.
+/// When another command holds a conflicting lock this waits for it, or fails if `wait` is false.
+pub fn lock_db(path: &str, exclusive: bool, wait: bool) -> Result<DBLock, DBError> {
+    let lock_path = format!("{}.lock", path);
+    let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)?;
+    let attempt = if exclusive { file.try_lock() } else { file.try_lock_shared() };
+    match attempt {
+        Ok(()) => {},
+        Err(TryLockError::WouldBlock) if wait => {
+            println!("Waiting for another command to finish with {}...", path);
+            if exclusive { file.lock()? } else { file.lock_shared()? }
+        },
+        Err(TryLockError::WouldBlock) => {
+            return Err(DBError(format!("{} is in use by another command", path)));
+        },
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+    Ok(DBLock { _file: file })
+}
+
 pub fn get_category_cards(db: &DB) -> HashMap<String, Vec<Card>> {
     let mut category_cards: HashMap<String, Vec<Card>> = HashMap::new();
     for card in db.values() {
@@ -85,4 +486,27 @@ pub fn get_category_cards(db: &DB) -> HashMap<String, Vec<Card>> {
         );
     }
     category_cards
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_without_header_is_version_0() {
+        let (version, records) = parse_flat("你好={\"character\":\"你好\",\"category\":[\"HSK1\"],\"pinyin\":\"nǐ hǎo\"}\n").unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0, "你好");
+        // Loading it runs every migration, schedule and rekeying included.
+        let db = parse_db("你好={\"character\":\"你好\",\"category\":[\"HSK1\"],\"pinyin\":\"nǐ hǎo\"}\n").unwrap();
+        assert_eq!(db.keys().collect::<Vec<_>>(), vec!["你好|ni3hao3"]);
+    }
+
+    #[test]
+    fn header_gives_the_version() {
+        let (version, records) = parse_flat(&format!("{}{}\n", VERSION_HEADER, CURRENT_VERSION)).unwrap();
+        assert_eq!(version, CURRENT_VERSION);
+        assert!(records.is_empty());
+        assert!(parse_flat(&format!("{}seven\n", VERSION_HEADER)).is_err());
+    }
+}
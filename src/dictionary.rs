@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::fs;
+
+use crate::db::Card;
+use crate::pinyin;
+
+pub const DEFAULT_DICTIONARY_LOCATION: &str = "./cedict_ts.u8";
+
+#[derive(Debug)]
+pub struct DictionaryError(String);
+
+impl std::fmt::Display for DictionaryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for DictionaryError {}
+
+impl From<std::io::Error> for DictionaryError {
+    fn from(e: std::io::Error) -> Self {
+        DictionaryError(format!("IO error: {}", e))
+    }
+}
+
+/// One line of CC-CEDICT: `傳統 传统 [chuan2 tong3] /tradition/traditional/`.
+#[derive(Debug, Clone)]
+pub struct DictionaryEntry {
+    pub traditional: String,
+    pub simplified: String,
+    /// Space separated numbered pinyin, exactly as CC-CEDICT writes it.
+    pub pinyin: String,
+    pub definitions: Vec<String>,
+}
+
+impl DictionaryEntry {
+    /// CC-CEDICT capitalizes the pinyin of proper nouns such as surnames and place names.
+    pub fn is_proper_noun(&self) -> bool {
+        self.pinyin.chars().next().map(|c| c.is_uppercase()).unwrap_or(false)
+    }
+}
+
+/// A local CC-CEDICT file, indexed by both simplified and traditional headwords.
+pub struct Dictionary {
+    entries: Vec<DictionaryEntry>,
+    by_headword: HashMap<String, Vec<usize>>,
+}
+
+impl Dictionary {
+    pub fn load(path: &str) -> Result<Self, DictionaryError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| DictionaryError(format!("Failed to read CC-CEDICT file {}: {}", path, e)))?;
+        Ok(Dictionary::parse(&contents))
+    }
+
+    pub fn parse(contents: &str) -> Self {
+        let mut dictionary = Dictionary { entries: Vec::new(), by_headword: HashMap::new() };
+        for line in contents.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            if let Some(entry) = parse_line(line) {
+                let index = dictionary.entries.len();
+                dictionary.by_headword.entry(entry.simplified.clone()).or_default().push(index);
+                if entry.traditional != entry.simplified {
+                    dictionary.by_headword.entry(entry.traditional.clone()).or_default().push(index);
+                }
+                dictionary.entries.push(entry);
+            }
+        }
+        dictionary
+    }
+
+    /// Every simplified and traditional headword in the dictionary.
+    pub fn headwords(&self) -> impl Iterator<Item = &str> {
+        self.by_headword.keys().map(|headword| headword.as_str())
+    }
+
+    /// Every entry whose simplified or traditional headword is `word`, in file order.
+    pub fn lookup(&self, word: &str) -> Vec<&DictionaryEntry> {
+        self.by_headword
+            .get(word)
+            .map(|indexes| indexes.iter().map(|i| &self.entries[*i]).collect())
+            .unwrap_or_default()
+    }
+
+    /// The entry used to fill in a card: the first reading that isn't a proper noun, with the
+    /// definitions of any other entries sharing that reading merged in.
+    pub fn best_entry(&self, word: &str) -> Option<DictionaryEntry> {
+        let entries = self.lookup(word);
+        let first = entries.iter().find(|entry| !entry.is_proper_noun()).or(entries.first())?;
+        let mut best = (*first).clone();
+        for entry in entries.iter().filter(|entry| entry.pinyin == first.pinyin).skip(1) {
+            best.definitions.extend(entry.definitions.iter().cloned());
+        }
+        Some(best)
+    }
+
+    /// Fills in whatever the card is missing: pinyin, definitions and the traditional form.
+    /// Returns whether the card was found in the dictionary.
+    pub fn fill_card(&self, card: &mut Card) -> bool {
+        let entry = if card.pinyin.is_empty() {
+            self.best_entry(&card.character)
+        } else {
+            // Keep the card's own reading and only look for definitions matching it.
+            let reading = compact(&card.pinyin);
+            self.lookup(&card.character)
+                .into_iter()
+                .find(|entry| compact(&entry.pinyin) == reading)
+                .cloned()
+        };
+        let Some(entry) = entry else {
+            return false;
+        };
+        if card.pinyin.is_empty() {
+            card.pinyin = compact(&entry.pinyin);
+        }
+        if card.definitions.is_empty() {
+            card.definitions = entry.definitions;
+        }
+        if card.traditional.is_none() && entry.traditional != card.character {
+            card.traditional = Some(entry.traditional);
+        }
+        true
+    }
+}
+
+/// Joins CC-CEDICT's spaced syllables into the stored form, `ni3 hao3` -> `ni3hao3`, `lu:4` -> `lü4`.
+fn compact(pinyin: &str) -> String {
+    pinyin::canonical(pinyin)
+}
+
+fn parse_line(line: &str) -> Option<DictionaryEntry> {
+    let (traditional, rest) = line.trim().split_once(' ')?;
+    let (simplified, rest) = rest.split_once(' ')?;
+    let rest = rest.strip_prefix('[')?;
+    let (pinyin, rest) = rest.split_once(']')?;
+    let definitions = rest
+        .trim()
+        .trim_matches('/')
+        .split('/')
+        .map(|definition| definition.trim().to_string())
+        .filter(|definition| !definition.is_empty())
+        .collect();
+    Some(DictionaryEntry {
+        traditional: traditional.to_string(),
+        simplified: simplified.to_string(),
+        pinyin: pinyin.to_string(),
+        definitions,
+    })
+}
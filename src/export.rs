@@ -1,75 +1,324 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
+use std::io::Write;
 
+use crate::anki::{self, AnkiError, AnkiOptions};
 use crate::db::DB;
+use crate::mapping::FieldTarget;
+use crate::spreadsheet;
 use crate::db::get_category_cards;
 use crate::db::Card;
+use crate::pinyin::{self, PinyinStyle};
+use crate::pleco::PlecoFormat;
 use chrono::Datelike;
 use chrono::Timelike;
 use chrono::{Local};
-use quick_xml::*;
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::Writer;
 
+#[derive(Debug)]
+pub struct ExportError(String);
 
-pub fn export_pleco(category: &str, db: &DB) {
-    let category_count = get_category_cards(db);
+impl std::fmt::Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl From<std::io::Error> for ExportError {
+    fn from(e: std::io::Error) -> Self {
+        ExportError(format!("IO error: {}", e))
+    }
+}
+
+impl From<AnkiError> for ExportError {
+    fn from(e: AnkiError) -> Self {
+        ExportError(format!("Anki error: {}", e))
+    }
+}
+
+fn category_cards(category: &str, db: &DB) -> Result<Vec<Card>, ExportError> {
+    let mut category_count = get_category_cards(db);
+    category_count.remove(&category.to_lowercase()).ok_or_else(|| {
+        let mut available: Vec<&String> = category_count.keys().collect();
+        available.sort();
+        ExportError(format!("Category {} not found. Available Categories: {:?}", category, available))
+    })
+}
+
+/// A category as part of a file name. Category paths like `HSK/Level 1` become `HSK-Level 1`.
+fn file_stem(category: &str) -> String {
+    category.replace(['/', '\\'], "-")
+}
+
+/// Writes every card in `category` to a timestamped Pleco flashcard file in `format`,
+/// returning its name.
+pub fn export_pleco(category: &str, style: PinyinStyle, format: PlecoFormat, db: &DB) -> Result<String, ExportError> {
+    let cards = category_cards(category, db)?;
     let local_time = Local::now();
-    let export_file = fs::File::create(format!("{}-{}-{}-{}_{}:{}.xml", category, local_time.year(), local_time.month(), local_time.day(), local_time.hour(), local_time.minute()));
-    match export_file {
-        Ok(file) => {
-            match category_count.get(category) {
-                Some(cards) => export_cards_to_pleco(cards, file),
-                None => println!("Category {} not found. Available Categories: {:?}", category, category_count.keys())
-            }
+    let extension = match format {
+        PlecoFormat::Xml => "xml",
+        PlecoFormat::Text => "txt",
+    };
+    let file_name = format!("{}-{}-{}-{}_{}:{}.{}", file_stem(category), local_time.year(), local_time.month(), local_time.day(), local_time.hour(), local_time.minute(), extension);
+    let file = fs::File::create(&file_name)
+        .map_err(|e| ExportError(format!("Failed to create export file {}: {}", file_name, e)))?;
+    match format {
+        PlecoFormat::Xml => export_cards_to_pleco(&cards, style, file)?,
+        PlecoFormat::Text => export_cards_to_pleco_text(&cards, style, file)?,
+    }
+    Ok(file_name)
+}
+
+/// Writes every card in `category` to `<category>.apkg`, a deck of the same name, returning
+/// the file name.
+pub fn export_anki(category: &str, options: &AnkiOptions, db: &DB) -> Result<String, ExportError> {
+    let mut cards = category_cards(category, db)?;
+    cards.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.key().cmp(&b.key())));
+    // Name the deck as the category is written on the cards, not as it was typed.
+    let deck_name = cards
+        .iter()
+        .flat_map(|card| card.category.iter())
+        .find(|name| name.to_lowercase() == category.to_lowercase())
+        .cloned()
+        .unwrap_or(category.to_string());
+    let file_name = format!("{}.apkg", file_stem(&deck_name));
+    anki::export_anki(&deck_name, &cards, options, &file_name)?;
+    Ok(file_name)
+}
+
+/// Writes `cards` as a plecoflash file Pleco can import without losing anything the cards
+/// carry: both headwords, the reading, definitions, every category, dictionary references
+/// and Pleco's scores.
+pub fn export_cards_to_pleco<W: Write>(cards: &[Card], style: PinyinStyle, out: W) -> Result<(), ExportError> {
+    let style = pleco_style(style);
+    let tones = if style == PinyinStyle::Marks { "marks" } else { "numbers" };
+    let now = Local::now().timestamp().to_string();
+    let creator = std::env::var("USER").unwrap_or("chinese-pratice-tool".to_string());
+    let generator = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+
+    let mut xml_writer = Writer::new_with_indent(out, b' ', 2);
+    xml_writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    let mut root = BytesStart::new("plecoflash");
+    root.push_attribute(("formatversion", "2"));
+    root.push_attribute(("creator", creator.as_str()));
+    root.push_attribute(("generator", generator.as_str()));
+    root.push_attribute(("platform", std::env::consts::OS));
+    root.push_attribute(("created", now.as_str()));
+    xml_writer.write_event(Event::Start(root))?;
+
+    // Pleco only files cards under categories declared up front.
+    let categories: BTreeSet<&str> = cards.iter().flat_map(|card| card.category.iter().map(String::as_str)).collect();
+    xml_writer.write_event(Event::Start(BytesStart::new("categories")))?;
+    for category in categories {
+        xml_writer.write_event(Event::Empty(BytesStart::new("category").with_attributes([("name", category)])))?;
+    }
+    xml_writer.write_event(Event::End(BytesEnd::new("categories")))?;
+
+    xml_writer.write_event(Event::Start(BytesStart::new("cards")))?;
+    for card in cards {
+        let created = card.created.map(|created| created.timestamp().to_string()).unwrap_or(now.clone());
+        let modified = card.modified.map(|modified| modified.timestamp().to_string()).unwrap_or(now.clone());
+        let card_event = BytesStart::new("card").with_attributes([
+            ("language", "chinese"),
+            ("created", created.as_str()),
+            ("modified", modified.as_str()),
+        ]);
+        xml_writer.write_event(Event::Start(card_event))?;
+        xml_writer.write_event(Event::Start(BytesStart::new("entry")))?;
+
+        write_text_element(&mut xml_writer, BytesStart::new("headword").with_attributes([("charset", "sc")]), &card.character)?;
+        if let Some(traditional) = &card.traditional {
+            write_text_element(&mut xml_writer, BytesStart::new("headword").with_attributes([("charset", "tc")]), traditional)?;
+        }
+        // Headwords with several readings are told apart by their pron.
+        if !card.pinyin.is_empty() {
+            let pron = BytesStart::new("pron").with_attributes([("type", "hypy"), ("tones", tones)]);
+            write_text_element(&mut xml_writer, pron, &pinyin::display(&card.pinyin, style))?;
+        }
+        if !card.definitions.is_empty() {
+            write_text_element(&mut xml_writer, BytesStart::new("defn"), &card.definitions.join("; "))?;
         }
-        Err(error) => {
-            println!("Failed to create export file: {:?}", error);
+        xml_writer.write_event(Event::End(BytesEnd::new("entry")))?;
+
+        for dictref in &card.dictrefs {
+            let dictref = BytesStart::new("dictref")
+                .with_attributes([("dictid", dictref.dictid.as_str()), ("entryid", dictref.entryid.as_str())]);
+            xml_writer.write_event(Event::Empty(dictref))?;
+        }
+        for category in &card.category {
+            xml_writer.write_event(Event::Empty(BytesStart::new("catassign").with_attributes([("category", category.as_str())])))?;
+        }
+        for score in &card.pleco_scores {
+            let mut scoreinfo = BytesStart::new("scoreinfo");
+            scoreinfo.push_attribute(("scorefile", score.scorefile.as_str()));
+            for (name, value) in &score.fields {
+                scoreinfo.push_attribute((name.as_str(), value.as_str()));
+            }
+            xml_writer.write_event(Event::Empty(scoreinfo))?;
         }
+        xml_writer.write_event(Event::End(BytesEnd::new("card")))?;
     }
+
+    xml_writer.write_event(Event::End(BytesEnd::new("cards")))?;
+    xml_writer.write_event(Event::End(BytesEnd::new("plecoflash")))?;
+    xml_writer.get_mut().flush()?;
+    Ok(())
 }
 
-pub fn export_cards_to_pleco(cards: &Vec<Card>, file: fs::File) {
-    let mut xml_writer = Writer::new(file);
-    let decl = events::BytesDecl::new("1.0", Some("UTF-8"), None);
-    assert!(xml_writer.write_event(events::Event::Decl(decl)).is_ok());
-    let mut elem = events::BytesStart::new("plecoflash");
-    elem.push_attribute(("formatversion", "2"));
-    elem.push_attribute(("creator", "Pleco User 19080708"));
-    elem.push_attribute(("generator", "Pleco 2.0 Flashcard Exporter"));
-    elem.push_attribute(("platform", "iPhone OS"));
-    elem.push_attribute(("created", "1757266198"));
-    assert!(xml_writer.write_event(events::Event::Start(elem)).is_ok());
-    assert!(xml_writer.write_event(events::Event::Start(events::BytesStart::new("cards"))).is_ok());
-
-    let created= Local::now().timestamp().to_string();
+/// Writes `cards` in Pleco's text format, each card under a `//Category/Sub` line for every
+/// category it is in, and cards without a category first.
+pub fn export_cards_to_pleco_text<W: Write>(cards: &[Card], style: PinyinStyle, mut out: W) -> Result<(), ExportError> {
+    let style = pleco_style(style);
+    let mut by_category: BTreeMap<&str, Vec<&Card>> = BTreeMap::new();
     for card in cards {
-        let mut card_event: events::BytesStart<'_> = events::BytesStart::new("card");
-        card_event.push_attribute(("language", "chinese"));
-        card_event.push_attribute(("created", created.as_str()));
-        card_event.push_attribute(("modified", created.as_str()));
-        assert!(xml_writer.write_event(events::Event::Start(card_event)).is_ok());
+        if card.category.is_empty() {
+            writeln!(out, "{}", pleco_text_line(card, style))?;
+        }
+        for category in &card.category {
+            by_category.entry(category.as_str()).or_default().push(card);
+        }
+    }
+    for (category, cards) in by_category {
+        writeln!(out, "//{}", category)?;
+        for card in cards {
+            writeln!(out, "{}", pleco_text_line(card, style))?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn pleco_text_line(card: &Card, style: PinyinStyle) -> String {
+    // Tabs and line breaks would start a new column or card.
+    let clean = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let headword = match &card.traditional {
+        Some(traditional) => format!("{}[{}]", card.character, traditional),
+        None => card.character.clone(),
+    };
+    format!("{}\t{}\t{}", headword, clean(&pinyin::display(&card.pinyin, style)), clean(&card.definitions.join("; ")))
+}
+
+/// Pleco reads pinyin with either tone marks or tone numbers, not zhuyin.
+fn pleco_style(style: PinyinStyle) -> PinyinStyle {
+    if style == PinyinStyle::Zhuyin {
+        println!("Pleco flashcards take pinyin, exporting tone numbers instead of zhuyin");
+        PinyinStyle::Numbers
+    } else {
+        style
+    }
+}
+
+fn write_text_element<W: Write>(xml_writer: &mut Writer<W>, start: BytesStart, text: &str) -> Result<(), ExportError> {
+    let end = start.to_end().into_owned();
+    xml_writer.write_event(Event::Start(start))?;
+    xml_writer.write_event(Event::Text(BytesText::new(text)))?;
+    xml_writer.write_event(Event::End(end))?;
+    Ok(())
+}
+
+pub struct CsvExportOptions {
+    pub columns: Vec<FieldTarget>,
+    pub delimiter: String,
+    pub header: bool,
+    pub encoding: String,
+    pub style: PinyinStyle,
+}
+
+/// Writes every card in `category` to `<category>.csv`, or `.tsv` when tab separated, with
+/// one column per entry in `options.columns`, returning the file name.
+pub fn export_csv(category: &str, options: &CsvExportOptions, db: &DB) -> Result<String, ExportError> {
+    let mut cards = category_cards(category, db)?;
+    cards.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.key().cmp(&b.key())));
+    let delimiter = spreadsheet::parse_delimiter(&options.delimiter).map_err(ExportError)?;
+    let header = options.header.then(|| options.columns.iter().map(|column| column.name().to_string()).collect());
+    let rows = cards
+        .iter()
+        .map(|card| {
+            options
+                .columns
+                .iter()
+                .map(|column| match column {
+                    FieldTarget::Hanzi => card.character.clone(),
+                    FieldTarget::Traditional => card.traditional.clone().unwrap_or_default(),
+                    FieldTarget::Pinyin => pinyin::display(&card.pinyin, options.style),
+                    FieldTarget::Meaning => card.definitions.join("; "),
+                    FieldTarget::Examples => card.examples.join("\n"),
+                    FieldTarget::Notes => card.notes.clone(),
+                    FieldTarget::Category => card.category.join("; "),
+                })
+                .collect()
+        })
+        .collect();
+    let text = spreadsheet::write_rows(header, rows, delimiter).map_err(ExportError)?;
+    let bytes = spreadsheet::encode(&text, &options.encoding).map_err(ExportError)?;
+    let file_name = format!("{}.{}", file_stem(category), if delimiter == b'\t' { "tsv" } else { "csv" });
+    fs::write(&file_name, bytes).map_err(|e| ExportError(format!("Failed to write {}: {}", file_name, e)))?;
+    Ok(file_name)
+}
 
-        assert!(xml_writer.write_event(events::Event::Start(events::BytesStart::new("entry"))).is_ok());
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
 
-        let mut headword = events::BytesStart::new("headword");
-        headword.push_attribute(("charset", "sc"));
-        assert!(xml_writer.write_event(events::Event::Start(headword)).is_ok());
+    use super::*;
+    use crate::db::{insert_card, CardChanges, MergePolicy, Source};
+    use crate::pleco;
 
-        assert!(xml_writer.write_event(events::Event::Text(events::BytesText::new(&card.character))).is_ok());
+    fn card(character: &str, traditional: Option<&str>, pinyin: &str, categories: &[&str], definitions: &[&str]) -> Card {
+        let categories = categories.iter().map(|category| category.to_string()).collect();
+        let mut card = Card::new(character, categories, pinyin, Source::new("test", None, None));
+        card.traditional = traditional.map(str::to_string);
+        card.definitions = definitions.iter().map(|definition| definition.to_string()).collect();
+        card
+    }
 
-        assert!(xml_writer.write_event(events::Event::End(events::BytesEnd::new("headword"))).is_ok());
+    /// What the text format carries of a card: its traditional form, categories and definitions.
+    type Summary = (Option<String>, BTreeSet<String>, Vec<String>);
 
-        assert!(xml_writer.write_event(events::Event::End(events::BytesEnd::new("entry"))).is_ok());
-        // dict-id?
+    fn summary(cards: &[Card]) -> BTreeMap<String, Summary> {
+        cards
+            .iter()
+            .map(|card| (card.key(), (card.traditional.clone(), card.category.iter().cloned().collect(), card.definitions.clone())))
+            .collect()
+    }
 
-        let mut catassign = events::BytesStart::new("catassign");
-        match card.category.get(0) {
-            Some(category) => catassign.push_attribute(("category", category.as_str())),
-            None => println!("No category for {}", card.character),
+    fn round_trip(cards: &[Card], style: PinyinStyle) -> (String, Vec<Card>) {
+        let mut text = Vec::new();
+        export_cards_to_pleco_text(cards, style, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        // A card in several categories comes back once per category, and is merged as an import would.
+        let mut db = DB::new();
+        for parsed in pleco::parse_text(&text).unwrap().cards {
+            insert_card(&mut db, parsed.into_card(Source::new("test", None, None)), MergePolicy::Union, &mut CardChanges::default());
         }
-        assert!(xml_writer.write_event(events::Event::Empty(catassign)).is_ok());
+        (text, db.into_values().collect())
+    }
 
-        assert!(xml_writer.write_event(events::Event::End(events::BytesEnd::new("card"))).is_ok());
+    #[test]
+    fn text_export_reads_back_the_same_cards() {
+        let cards = vec![
+            card("学习", Some("學習"), "xue2xi2", &["HSK/Level 1", "Lesson 2"], &["to learn", "to study"]),
+            card("银行", None, "yin2hang2", &["HSK/Level 3"], &["bank"]),
+            card("他", None, "", &["HSK/Level 1"], &[]),
+            card("你好", None, "ni3hao3", &[], &["hello"]),
+        ];
+        for style in [PinyinStyle::Numbers, PinyinStyle::Marks] {
+            let (text, read) = round_trip(&cards, style);
+            assert_eq!(summary(&read), summary(&cards), "{:?} export:\n{}", style, text);
+        }
     }
 
-    assert!(xml_writer.write_event(events::Event::End(events::BytesEnd::new("cards"))).is_ok());
-    assert!(xml_writer.write_event(events::Event::End(events::BytesEnd::new("plecoflash"))).is_ok());
-}
\ No newline at end of file
+    #[test]
+    fn text_export_layout() {
+        let cards = vec![
+            card("学习", Some("學習"), "xue2xi2", &["Lesson 2", "HSK/Level 1"], &["to learn", "to\tstudy"]),
+            card("他", None, "", &["HSK/Level 1"], &[]),
+            card("你好", None, "ni3hao3", &[], &["hello"]),
+        ];
+        let (text, _) = round_trip(&cards, PinyinStyle::Numbers);
+        assert_eq!(text, "你好\tni3hao3\thello\n//HSK/Level 1\n学习[學習]\txue2xi2\tto learn; to study\n他\t\t\n//Lesson 2\n学习[學習]\txue2xi2\tto learn; to study\n");
+    }
+}
@@ -1,19 +1,32 @@
 use std::{fs};
 use crate::db::Card;
-use crate::db::save_db;
+use crate::db::Storage;
 use crate::db::DB;
 use crate::db::DBError;
+use crate::db::Source;
+use crate::db::CardChanges;
+use crate::db::insert_card;
+use crate::db::existing_key;
+use crate::db::MergePolicy;
+use crate::dictionary::Dictionary;
+use crate::segment::{Segmentation, Segmenter};
+use std::collections::HashMap;
 use crate::openai_prompts::generate_openai_prompt;
-use quick_xml::Reader;
-use quick_xml::encoding::EncodingError;
-use quick_xml::events::{BytesStart, Event};
+use crate::pleco::{self, PlecoError};
+use crate::anki::{self, AnkiError, AnkiImportOptions};
+use crate::mapping::{self, FieldAssignment, FieldTarget};
+use crate::spreadsheet::{self, HeaderMode};
+use crate::pdf::{PageCategory, PageRanges, Pdf, PdfError};
+use crate::ocr::{self, OcrError, OcrOptions, OcrWord};
+use crate::staging::{self, Candidate, Staging, StagingError};
+use crate::vocab_table;
+use crate::chunking::{self, ChunkOptions};
+use crate::pinyin::{self, PinyinStyle};
 use std::string::FromUtf8Error;
-use inquire::{Text};
-use leptess::LepTess;
-use std::path::Path;
+use inquire::{Select, Text};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use regex::Regex;
-use rand::seq::SliceRandom;
-use rand::rng;
 
 
 #[derive(Debug)]
@@ -52,9 +65,33 @@ impl From<FromUtf8Error> for ImportError {
 }
 
 
-impl From<EncodingError> for ImportError {
-    fn from(e: EncodingError) -> Self {
-        ImportError(format!("Encoding error: {}", e))
+impl From<PlecoError> for ImportError {
+    fn from(e: PlecoError) -> Self {
+        ImportError(format!("Malformed Pleco file at {}", e))
+    }
+}
+
+impl From<AnkiError> for ImportError {
+    fn from(e: AnkiError) -> Self {
+        ImportError(format!("Anki error: {}", e))
+    }
+}
+
+impl From<PdfError> for ImportError {
+    fn from(e: PdfError) -> Self {
+        ImportError(format!("PDF error: {}", e))
+    }
+}
+
+impl From<OcrError> for ImportError {
+    fn from(e: OcrError) -> Self {
+        ImportError(format!("OCR error: {}", e))
+    }
+}
+
+impl From<StagingError> for ImportError {
+    fn from(e: StagingError) -> Self {
+        ImportError(format!("Import queue error: {}", e))
     }
 }
 
@@ -64,165 +101,497 @@ impl From<DBError> for ImportError {
     }
 }
 
-pub fn import_pleco(import_file: &str, db_location: &str, db: &mut DB) -> Result<(), ImportError> {
+/// Imports a Pleco flashcard export, XML or text, merging cards that already exist by `policy`. With
+/// `dry_run` the import runs against a copy of the DB and only the resulting diff is printed.
+pub fn import_pleco(import_file: &str, policy: MergePolicy, dry_run: bool, storage: &dyn Storage, db: &mut DB, dictionary: Option<&Dictionary>) -> Result<(), ImportError> {
     println!("importing {}", import_file);
-    let xml_file = fs::read_to_string(import_file)?;
-    let mut reader = Reader::from_str(&xml_file);
-
-    let mut buf: Vec<u8> = Vec::new();
-    let mut card: bool = false;
-    let mut entry: bool = false;
-    let mut headword: bool = false;
-    let mut pron: bool = false;
-    let mut character: String = "".to_string();
-    let mut category: String = "".to_string();
-    let mut pinyin: String = "".to_string();
-    loop {
-        match reader.read_event_into(&mut buf) {
-            Ok(Event::Start(ref e)) => {
-                let name: String = String::from_utf8(e.name().as_ref().to_vec())?;
-                if name == "card" {
-                    card = true;
-                    println!("Entered card: {:?}", card);
-                } else if name == "entry" && card {
-                    entry = true;
-                    println!("Entered entry {:?}", entry);
-                } else if name == "headword" && entry && card {
-                    headword = true;
-                    println!("Entered headword {:?}", headword);
-                } else if name == "pron" && entry && card {
-                    pron = true;
-                    println!("Entered pron {:?}", pron);
-                }
-                println!("Start element: {:?}", name);
-            }
-            Ok(Event::Text(e)) => {
-                if headword == true && character == "" {
-                    character = String::from_utf8(e.to_vec())?;
-                }
-                if pron == true && pinyin == "" {
-                    pinyin = String::from_utf8(e.to_vec())?;
-                }
-                println!("Text: {}", e.xml_content()?);
+    let pleco_file = pleco::parse_any(&fs::read_to_string(import_file)?)?;
+    println!("{} cards in {} categories from {}", pleco_file.cards.len(), pleco_file.categories.len(), pleco_file.header.describe());
+    let mut cards = Vec::new();
+    for pleco_card in pleco_file.cards {
+        if let Some(language) = pleco_card.language.as_deref().filter(|language| *language != "chinese") {
+            println!("Skipping the {} card on line {}", language, pleco_card.line);
+            continue;
+        }
+        if let Some(pron) = &pleco_card.pron {
+            if let Err(e) = pinyin::parse(&pron.text) {
+                let system = pron.system.as_deref().unwrap_or("unknown");
+                println!("Keeping the {} reading of {} on line {} as written: {}", system, pleco_card.headword(), pleco_card.line, e);
             }
-            Ok(Event::End(ref e)) => {
-                let name: String = String::from_utf8(e.name().as_ref().to_vec())?;
-                if name == "card" {
-                    let mut categories = Vec::new();
-                    categories.push(category.to_string());
-                    let new_card: Card = Card {
-                        character: character.to_string(),
-                        category: categories,
-                        pinyin: pinyin.to_string(),
-                    };
-                    db.insert(character.clone(),new_card);
-                    character.clear();
-                    category.clear();
-                    pinyin.clear();
-                    card = false;
-                    println!("Left card: {:?}", card);
-                } else if name == "entry" {
-                    entry = false;
-                    println!("Left entry {:?}", entry);
-                } else if name == "headword" {
-                    headword = false;
-                    println!("Left headword {:?}", headword);
-                } else if name == "pron" {
-                    pron = false;
-                    println!("Left pron {:?}", headword);
+        }
+        let headword_only = pleco_card.pron.is_none();
+        let mut card = pleco_card.into_card(Source::new("import pleco", Some(import_file), None));
+        // Like Pleco, look up the reading and definition of cards that only give a headword.
+        if headword_only {
+            fill_from_dictionary(&mut card, dictionary);
+        }
+        cards.push(card);
+    }
+    import_cards(cards, policy, dry_run, storage, db)
+}
+
+/// Imports the notes of an Anki package (`.apkg`, `.colpkg`) or plain text export. Cards
+/// without a category from their deck or tags go into `options.category`, or one named after
+/// the file.
+pub fn import_anki(
+    import_file: &str,
+    options: &AnkiImportOptions,
+    policy: MergePolicy,
+    dry_run: bool,
+    storage: &dyn Storage,
+    db: &mut DB,
+    dictionary: Option<&Dictionary>,
+) -> Result<(), ImportError> {
+    println!("importing {}", import_file);
+    let notes = if import_file.ends_with(".apkg") || import_file.ends_with(".colpkg") {
+        anki::read_package(import_file)?
+    } else {
+        anki::read_text(import_file)?
+    };
+    if let Some(note) = notes.first() {
+        println!("{} notes, reading {}", notes.len(), note.describe_mapping(&options.mapping));
+    }
+    let default_category = options.category.clone().unwrap_or_else(|| {
+        Path::new(import_file).file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default()
+    });
+    let mut cards = Vec::new();
+    for note in &notes {
+        match note.to_card(options, Source::new("import anki", Some(import_file), None)) {
+            Ok(mut card) => {
+                if card.category.is_empty() || options.category.is_some() {
+                    card.category.push(default_category.clone());
                 }
-                println!("End element: {:?}", std::str::from_utf8(e.name().as_ref())?);
-            }
-            Ok(Event::Empty(ref e)) => {
-                handle_element(e, &mut category);
-            }
-            Ok(Event::Eof) => break,
-            Ok(other_event) => {
-                // Catch-all for any other Event variants
-                println!("Other event: {:?}", other_event);
-            }
-            Err(e) => return Err(ImportError(e.to_string()))
+                fill_from_dictionary(&mut card, dictionary);
+                cards.push(card);
+            },
+            Err(e) => println!("Skipping {}: {}", note.location, e),
         }
-        buf.clear();
     }
+    import_cards(cards, policy, dry_run, storage, db)
+}
 
-    save_db(db_location, &db)?;
+pub struct CsvImportOptions {
+    pub mapping: Vec<FieldAssignment>,
+    /// The delimiter's name or character, detected from the file when not given.
+    pub delimiter: Option<String>,
+    pub header: HeaderMode,
+    /// The file's encoding label, detected when not given.
+    pub encoding: Option<String>,
+    /// A category for every card, on top of any from a category column.
+    pub category: Option<String>,
+}
+
+/// Imports a vocabulary list saved as CSV or TSV from a spreadsheet. Rows whose hanzi column
+/// has no Chinese are skipped. Cards without a category column go into `options.category`, or
+/// one named after the file.
+pub fn import_csv(
+    import_file: &str,
+    options: &CsvImportOptions,
+    policy: MergePolicy,
+    dry_run: bool,
+    storage: &dyn Storage,
+    db: &mut DB,
+    dictionary: Option<&Dictionary>,
+) -> Result<(), ImportError> {
+    println!("importing {}", import_file);
+    let rows = spreadsheet::read_rows(import_file, options.delimiter.as_deref(), options.header, options.encoding.as_deref())
+        .map_err(ImportError)?;
+    if let Some(row) = rows.first() {
+        println!("{} rows, reading {}", rows.len(), mapping::describe(&row.fields, &options.mapping));
+    }
+    let default_category = options.category.clone().unwrap_or_else(|| {
+        Path::new(import_file).file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default()
+    });
+    let mut cards = Vec::new();
+    for row in rows {
+        let value = |target: FieldTarget| {
+            mapping::find(&row.fields, target, &options.mapping).map(|field| field.value.trim().to_string()).unwrap_or_default()
+        };
+        let hanzi = value(FieldTarget::Hanzi);
+        let runs = extract_chinese_runs(&hanzi);
+        let Some(character) = runs.first() else {
+            println!("Skipping line {}: no Chinese in \"{}\"", row.line, hanzi);
+            continue;
+        };
+        if runs.len() > 1 {
+            println!("Line {}: importing {} and leaving out {}", row.line, character, runs[1..].join(", "));
+        }
+
+        let mut categories = split_list(&value(FieldTarget::Category));
+        if categories.is_empty() || options.category.is_some() {
+            categories.push(default_category.clone());
+        }
+        let mut card = Card::new(character, categories, &value(FieldTarget::Pinyin), Source::new("import csv", Some(import_file), None));
+        // Definitions are written joined by semicolons, as `export csv` does.
+        card.definitions = split_list(&value(FieldTarget::Meaning));
+        card.traditional = extract_chinese_runs(&value(FieldTarget::Traditional)).into_iter().next().filter(|traditional| *traditional != card.character);
+        card.examples = value(FieldTarget::Examples).lines().map(str::trim).filter(|line| !line.is_empty()).map(str::to_string).collect();
+        card.notes = value(FieldTarget::Notes);
+        fill_from_dictionary(&mut card, dictionary);
+        cards.push(card);
+    }
+    import_cards(cards, policy, dry_run, storage, db)
+}
+
+/// Merges imported cards into the DB by `policy` and prints what changed. With `dry_run` the
+/// cards are merged into a copy of the DB and nothing is written.
+fn import_cards(cards: Vec<Card>, policy: MergePolicy, dry_run: bool, storage: &dyn Storage, db: &mut DB) -> Result<(), ImportError> {
+    let before = db.clone();
+    let mut preview_db = dry_run.then(|| db.clone());
+    let target = preview_db.as_mut().unwrap_or(db);
+    let mut changes = CardChanges::default();
+    for card in cards {
+        let policy = match (policy, existing_key(target, &card)) {
+            (MergePolicy::Interactive, Some(key)) => choose_merge(&target[&key], &card)?,
+            (policy, _) => policy,
+        };
+        insert_card(target, card, policy, &mut changes);
+    }
+
+    print!("{}", ImportPreview::new(&before, target, &changes)?);
+    if dry_run {
+        println!("Dry run, nothing was written.");
+    } else {
+        changes.persist(storage, target)?;
+    }
     Ok(())
 }
 
-pub async fn import_text(category: &str, text: &str, db: &mut DB, db_location: &str) {
-    let response= generate_openai_prompt(text, "generate-csv", None).await;
-    match response {
-        Ok(response_text) => {
-            let text_characters: Vec<String> = extract_chinese_runs(&response_text);
-            println!("llm output {:?}", text_characters);
-            text_characters.into_iter().for_each(|character| {db.insert(character.to_string(),
-                Card { character: character.to_string(), category: vec![category.to_string()], pinyin: "".to_string() }
-            );});
-            match save_db(db_location, &db) {
-                Ok(_) => println!("Succesfully imported cards into category"),
-                Err(e) => println!("Failed to save imported cards {:?}", e)
+fn choose_merge(existing: &Card, incoming: &Card) -> Result<MergePolicy, ImportError> {
+    println!("\n{} is already in your cards", existing.key());
+    println!("  existing: {}", summarize(existing));
+    println!("  imported: {}", summarize(incoming));
+    let policies = vec![MergePolicy::Union, MergePolicy::Keep, MergePolicy::Overwrite];
+    Select::new("How should they be merged?", policies)
+        .prompt()
+        .map_err(|e| ImportError(format!("Import cancelled: {}", e)))
+}
+
+fn summarize(card: &Card) -> String {
+    format!("{} [{}] {} ({})", card.character, card.pinyin, card.definitions.join("; "), card.category.join(", "))
+}
+
+/// What an import did, or would do, to the cards it touched.
+struct ImportPreview {
+    added: Vec<String>,
+    /// Keys with the names of the fields that changed.
+    changed: Vec<(String, Vec<String>)>,
+    /// Reading-less cards that were given a reading and so moved to a new key, old key first.
+    rekeyed: Vec<(String, String)>,
+    unchanged: usize,
+}
+
+impl ImportPreview {
+    fn new(before: &DB, after: &DB, changes: &CardChanges) -> Result<Self, ImportError> {
+        let mut preview = ImportPreview { added: Vec::new(), changed: Vec::new(), rekeyed: Vec::new(), unchanged: 0 };
+        let mut keys = changes.saved.clone();
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            let (Some(card), old) = (after.get(&key), before.get(&key)) else {
+                continue;
+            };
+            let Some(old) = old else {
+                // A reading-less card is keyed by its bare headword until it gets a reading.
+                if changes.removed.contains(&card.character) && before.contains_key(&card.character) {
+                    preview.rekeyed.push((card.character.clone(), key));
+                } else {
+                    preview.added.push(key);
+                }
+                continue;
+            };
+            let (old, new) = (serde_json::to_value(old).map_err(DBError::from)?, serde_json::to_value(card).map_err(DBError::from)?);
+            let fields: Vec<String> = new
+                .as_object()
+                .map(|new| new.iter().filter(|(field, value)| old.get(field.as_str()) != Some(value)).map(|(field, _)| field.clone()).collect())
+                .unwrap_or_default();
+            if fields.is_empty() {
+                preview.unchanged += 1;
+            } else {
+                preview.changed.push((key, fields));
             }
+        }
+        Ok(preview)
+    }
+}
 
+impl std::fmt::Display for ImportPreview {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} added, {} changed, {} unchanged", self.added.len(), self.changed.len() + self.rekeyed.len(), self.unchanged)?;
+        for key in &self.added {
+            writeln!(f, "  + {}", key)?;
+        }
+        for (key, fields) in &self.changed {
+            writeln!(f, "  ~ {} ({})", key, fields.join(", "))?;
+        }
+        for (old, new) in &self.rekeyed {
+            writeln!(f, "  ~ {} given a reading, now {}", old, new)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct TextImportOptions {
+    pub category: String,
+    pub segmentation: Segmentation,
+    /// Word frequencies to guide dictionary segmentation.
+    pub frequencies: Option<HashMap<String, u64>>,
+}
+
+pub async fn import_text(text: &str, options: &TextImportOptions, staging: &Staging, storage: &dyn Storage, db: &mut DB, dictionary: Option<&Dictionary>) {
+    // Words with the line of the text they were on, when that is known.
+    let words: Vec<(String, Option<usize>)> = match (options.segmentation, dictionary) {
+        (Segmentation::Dictionary, Some(dictionary)) => {
+            let segmenter = Segmenter::new(dictionary, options.frequencies.as_ref());
+            text.lines().enumerate().flat_map(|(index, line)| segmenter.segment(line).into_iter().map(move |word| (word, Some(index + 1)))).collect()
+        },
+        (Segmentation::Dictionary, None) => {
+            println!("No dictionary to segment with, importing each run of characters as one word");
+            text.lines().enumerate().flat_map(|(index, line)| extract_chinese_runs(line).into_iter().map(move |word| (word, Some(index + 1)))).collect()
         },
-        Err(e) => println!("Failed to parse text input to import {:?}", e),
+        (Segmentation::Llm, _) => match generate_openai_prompt(text, "generate-csv", None).await {
+            Ok(response_text) => {
+                let text_characters = extract_chinese_runs(&response_text);
+                println!("llm output {:?}", text_characters);
+                text_characters.into_iter().map(|word| (word, None)).collect()
+            },
+            Err(e) => return println!("Failed to parse text input to import {:?}", e),
+        },
+    };
+
+    let candidates: Vec<Candidate> = words
+        .into_iter()
+        .map(|(word, line)| Candidate {
+            word,
+            category: options.category.clone(),
+            source: Some(Source::new("import text", None, None)),
+            line,
+            ..Candidate::default()
+        })
+        .collect();
+    match stage(candidates, staging, storage, db, dictionary) {
+        Ok(0) => {},
+        Ok(added) => println!("Succesfully imported {} cards into category", added),
+        Err(e) => println!("Failed to save imported cards {:?}", e)
     }
 }
 
-fn handle_element(bytes_start: &BytesStart, category: &mut String) -> () {
-    let name: String = String::from_utf8(bytes_start.name().as_ref().to_vec()).unwrap_or("[invalid utf8]".to_string());
-    println!("Element name: {}", name);
-    for attribute in bytes_start.attributes() {
-        if let Ok(attribute_result) = attribute {
-            let key = str::from_utf8(attribute_result.key.as_ref()).unwrap_or("invalid key");
-            let value = attribute_result.unescape_value().unwrap_or_default();
-            println!("Attribute key={} value={}", key, value);
-            if name == "catassign" && key == "category" {
-                category.clear();
-                category.push_str(&value.as_ref());
-            }
+pub struct PdfImportOptions {
+    pub category: String,
+    /// Only these pages, every page when not given.
+    pub pages: Option<PageRanges>,
+    /// Categories for some pages, in place of `category`.
+    pub page_categories: Vec<PageCategory>,
+    pub ocr: OcrOptions,
+    pub chunks: ChunkOptions,
+}
+
+/// Imports the words on the pages of a PDF. Pages with a text layer are read directly, others
+/// are rendered and OCRed. Words go into chunks of the category their page is given.
+pub async fn import_pdf(
+    import_file: &str,
+    options: &PdfImportOptions,
+    staging: &Staging,
+    storage: &dyn Storage,
+    db: &mut DB,
+    dictionary: Option<&Dictionary>,
+) -> Result<(), ImportError> {
+    let pdf = Arc::new(Pdf::open(import_file)?);
+    let page_numbers: Vec<u32> = (1..=pdf.page_count()).filter(|page| options.pages.as_ref().is_none_or(|pages| pages.contains(*page))).collect();
+    println!("importing {} pages of {}", page_numbers.len(), import_file);
+    let render_directory = std::env::temp_dir().join(format!("pdf-import-{}", std::process::id()));
+    fs::create_dir_all(&render_directory)?;
+
+    // The words of each page, or None for scans, which are rendered and OCRed together after.
+    let mut pages: Vec<(u32, Option<Vec<OcrWord>>)> = Vec::new();
+    let mut scans: Vec<u32> = Vec::new();
+    for page in page_numbers {
+        let text = pdf.text(page);
+        if let Some(entries) = vocab_table::entries(vocab_table::text_rows(&text)) {
+            pages.push((page, Some(entries)));
+        } else if extract_chinese_runs(&text).is_empty() {
+            scans.push(page);
+            pages.push((page, None));
+        } else {
+            pages.push((page, Some(text_layer_words(&text))));
         }
     }
+    let ocred_pages = scans.len();
+    let render = {
+        let (pdf, render_directory) = (pdf.clone(), render_directory.clone());
+        move |page: &u32| Ok(pdf.rasterize(*page, &render_directory)?)
+    };
+    let mut recognized = ocr::recognize_pages(scans, &options.ocr, render).await.into_iter();
 
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for (page, words) in pages {
+        let (words, ocred) = match words {
+            Some(words) => (words, false),
+            None => match recognized.next() {
+                Some(Ok(words)) => (words, true),
+                Some(Err(e)) => {
+                    println!("Skipping page {}: {}", page, e);
+                    continue;
+                },
+                None => continue,
+            },
+        };
+        let page_category = options
+            .page_categories
+            .iter()
+            .find(|page_category| page_category.pages.contains(page))
+            .map(|page_category| page_category.category.as_str())
+            .unwrap_or(&options.category);
+        let source = Source::new("import pdf", Some(import_file), Some(page));
+        for word in words {
+            let confidence = ocred.then_some(word.confidence);
+            candidates.push(page_candidate(word, confidence, page_category, &source, &options.ocr));
+        }
+    }
+    let _ = fs::remove_dir_all(&render_directory);
+    report_cache_hits(&options.ocr, ocred_pages);
+    let candidates = file_in_chunks(candidates, &options.chunks, staging, db)?;
+    stage(candidates, staging, storage, db, dictionary)?;
+    Ok(())
 }
 
-pub async fn import_png(_category: &str, db_location: &str, db: &mut DB) -> Result<(), ImportError> {
+/// Imports the words in a directory of page images (PNG, JPEG, TIFF or WebP), named so they sort
+/// in page order, in chunks of `category`. A page that can't be read is skipped.
+pub async fn import_images(
+    category: &str,
+    options: &OcrOptions,
+    chunks: &ChunkOptions,
+    staging: &Staging,
+    storage: &dyn Storage,
+    db: &mut DB,
+    dictionary: Option<&Dictionary>,
+) -> Result<(), ImportError> {
     let directory = select_directory()?;
-    let mut ocr_pages: Vec<String> = Vec::new();
+    let mut image_paths: Vec<PathBuf> = Vec::new();
     for entry in fs::read_dir(directory)? {
         let path = entry?.path();
-        if path.extension().map(|e| e == "png").unwrap_or(false) {
-            let text = ocr_png(&path)?;
-            ocr_pages.push(text);
+        if ocr::is_image(&path) {
+            image_paths.push(path);
         }
     }
-    let mut parsed_pages: Vec<String> = Vec::new();
-    for page in ocr_pages {
-        for character in page.split('\n') {
-            let cleaned = character.replace(' ', "").replace('"', "");
-            parsed_pages.push(cleaned)
+    image_paths.sort();
+
+    let recognized = ocr::recognize_pages(image_paths.clone(), options, |path: &PathBuf| Ok(path.clone())).await;
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for (page_index, (path, words)) in image_paths.iter().zip(recognized).enumerate() {
+        let words = match words {
+            Ok(words) => words,
+            Err(e) => {
+                println!("Skipping {}: {}", path.display(), e);
+                continue;
+            },
+        };
+        let source = Source::new("import images", path.to_str(), Some(page_index as u32 + 1));
+        for word in words {
+            let confidence = Some(word.confidence);
+            candidates.push(page_candidate(word, confidence, category, &source, options));
         }
     }
-    let text = parsed_pages.join("");
-    let mut results = extract_chinese_runs(&text);
-    let mut rng = rng();
+    report_cache_hits(options, image_paths.len());
+    let candidates = file_in_chunks(candidates, chunks, staging, db)?;
+    stage(candidates, staging, storage, db, dictionary)?;
+    Ok(())
+}
+
+fn report_cache_hits(options: &OcrOptions, ocred_pages: usize) {
+    let hits = options.cache.hits();
+    if hits > 0 {
+        println!("{} of {} pages were read from the OCR cache, pass --reocr to OCR them again", hits, ocred_pages);
+    }
+}
 
-    // Shuffle in-place
-    results.shuffle(&mut rng);
+/// The runs of Chinese characters on each line of a page's text layer.
+fn text_layer_words(text: &str) -> Vec<OcrWord> {
+    text.lines()
+        .enumerate()
+        .flat_map(|(index, line)| {
+            extract_chinese_runs(line).into_iter().map(move |text| OcrWord { text, confidence: 100.0, line: Some(index + 1), ..OcrWord::default() })
+        })
+        .collect()
+}
 
-    for (index, character) in results.iter().enumerate() {
-        let category_index: usize = (index / 50) + 1;
-        let card: Card = Card {
-            character: character.to_string(),
-            category: vec![format!("{}-{}", _category, category_index)],
-            pinyin: "".to_string(),
-        };
-        db.insert(character.to_string(), card);
+/// A word read off a page, `confidence` being OCR's when the page was OCRed.
+fn page_candidate(word: OcrWord, confidence: Option<f32>, category: &str, source: &Source, options: &OcrOptions) -> Candidate {
+    Candidate {
+        word: word.text,
+        pinyin: word.pinyin,
+        meaning: word.gloss,
+        category: category.to_string(),
+        source: Some(Source { entry: word.entry, ..source.clone() }),
+        line: word.line,
+        confidence,
+        unsure: confidence.is_some_and(|confidence| confidence < options.min_confidence),
+    }
+}
+
+/// Files the words into chunks, leaving out those already filed in the DB or the queue.
+fn file_in_chunks(candidates: Vec<Candidate>, options: &ChunkOptions, staging: &Staging, db: &DB) -> Result<Vec<Candidate>, ImportError> {
+    let queued = staging::load_queue(&staging.queue)?;
+    Ok(chunking::assign(candidates, options, db, &queued))
+}
 
+/// Queues the imported words for review, or with review off, adds all but the unsure ones.
+/// Returns how many were added.
+fn stage(candidates: Vec<Candidate>, staging: &Staging, storage: &dyn Storage, db: &mut DB, dictionary: Option<&Dictionary>) -> Result<usize, ImportError> {
+    let (queued, direct): (Vec<Candidate>, Vec<Candidate>) = candidates.into_iter().partition(|candidate| staging.review || candidate.unsure);
+    if !queued.is_empty() {
+        let count = queued.len();
+        staging::enqueue(&staging.queue, queued)?;
+        if staging.review {
+            println!("{} words are waiting for review, go through them with `import review`", count);
+        } else {
+            println!("{} words were read with low confidence and are waiting for review, go through them with `import review`", count);
+        }
+    }
+    let added = direct.len();
+    add_candidates(direct, storage, db, dictionary)?;
+    Ok(added)
+}
+
+/// Goes through the words waiting in the import queue, adding the ones accepted.
+pub fn review_queue(staging: &Staging, style: PinyinStyle, storage: &dyn Storage, db: &mut DB, dictionary: Option<&Dictionary>) -> Result<(), ImportError> {
+    let queue = staging::load_queue(&staging.queue)?;
+    if queue.is_empty() {
+        println!("No imported words are waiting for review.");
+        return Ok(());
+    }
+    let reviewed = staging::review(queue, dictionary, style);
+    let accepted = reviewed.accepted.len();
+    add_candidates(reviewed.accepted, storage, db, dictionary)?;
+    staging::save_queue(&staging.queue, &reviewed.remaining)?;
+    println!("Added {} words, {} still waiting for review.", accepted, reviewed.remaining.len());
+    Ok(())
+}
+
+fn add_candidates(candidates: Vec<Candidate>, storage: &dyn Storage, db: &mut DB, dictionary: Option<&Dictionary>) -> Result<(), ImportError> {
+    let mut changes = CardChanges::default();
+    for candidate in candidates {
+        let source = candidate.source.unwrap_or(Source::new("import", None, None));
+        let mut card = Card::new(&candidate.word, vec![candidate.category], &candidate.pinyin, source);
+        card.definitions = split_list(&candidate.meaning);
+        fill_from_dictionary(&mut card, dictionary);
+        insert_card(db, card, MergePolicy::Union, &mut changes);
+    }
+    changes.persist(storage, db)?;
+    Ok(())
+}
+
+fn fill_from_dictionary(card: &mut Card, dictionary: Option<&Dictionary>) {
+    if let Some(dictionary) = dictionary {
+        if !dictionary.fill_card(card) && card.pinyin.is_empty() {
+            println!("{} is not in the dictionary, leaving its pinyin empty", card.character);
+        }
     }
+}
 
-    save_db(db_location, &db)?;
-    return Ok(())
+/// The non-empty items of a list written with semicolons, like `to study; to learn`.
+fn split_list(value: &str) -> Vec<String> {
+    value.split(';').map(str::trim).filter(|item| !item.is_empty()).map(str::to_string).collect()
 }
 
 fn extract_chinese_runs(input: &str) -> Vec<String> {
@@ -232,18 +601,135 @@ fn extract_chinese_runs(input: &str) -> Vec<String> {
         .collect()
 }
 
-fn ocr_png(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
-    // chi_sim = Simplified Chinese; use chi_tra for Traditional
-    let mut tess = LepTess::new(None, "chi_sim")?;
-    tess.set_image(path)?;
-    let text = tess.get_utf8_text()?;
-    Ok(text)
-}
-
 fn select_context() -> Result<String, Box<dyn std::error::Error>> {
     Ok(Text::new("Include any additional context").prompt()?)
 }
 
 fn select_directory() -> Result<String, Box<dyn std::error::Error>> {
-    Ok(Text::new("Enter the directory with the png files.").prompt()?)
-}
\ No newline at end of file
+    Ok(Text::new("Enter the directory with the page images.").prompt()?)
+}
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::migrations::MigrationReport;
+
+    /// Records which cards an import writes, instead of writing them.
+    #[derive(Default)]
+    struct RecordingStorage {
+        saved: RefCell<Vec<String>>,
+        removed: RefCell<Vec<String>>,
+    }
+
+    impl Storage for RecordingStorage {
+        fn load(&self) -> Result<DB, DBError> {
+            unreachable!("Imports are given the loaded DB")
+        }
+
+        fn save(&self, _db: &DB) -> Result<(), DBError> {
+            unreachable!("Imports only write the cards they touch")
+        }
+
+        fn save_cards(&self, _db: &DB, keys: &[String]) -> Result<(), DBError> {
+            self.saved.borrow_mut().extend_from_slice(keys);
+            Ok(())
+        }
+
+        fn remove_cards(&self, _db: &DB, keys: &[String]) -> Result<(), DBError> {
+            self.removed.borrow_mut().extend_from_slice(keys);
+            Ok(())
+        }
+
+        fn category_cards(&self, _category: &str) -> Result<Vec<Card>, DBError> {
+            unreachable!()
+        }
+
+        fn migrate(&self, _dry_run: bool) -> Result<MigrationReport, DBError> {
+            unreachable!()
+        }
+
+        fn backup(&self, _path: &Path) -> Result<bool, DBError> {
+            unreachable!()
+        }
+    }
+
+    fn card(category: &str, definition: &str, notes: &str) -> Card {
+        let mut card = Card::new("学习", vec![category.to_string()], "xue2xi2", Source::new("test", None, None));
+        card.definitions = vec![definition.to_string()];
+        card.notes = notes.to_string();
+        card
+    }
+
+    /// A DB holding one card that has been reviewed, and a card to import over it.
+    fn existing() -> (DB, Card) {
+        let mut stored = card("HSK1", "to learn", "my note");
+        stored.schedule.repetitions = 3;
+        (HashMap::from([(stored.key(), stored)]), card("Lesson 2", "to study", ""))
+    }
+
+    fn import(policy: MergePolicy) -> (DB, RecordingStorage) {
+        let (mut db, incoming) = existing();
+        let storage = RecordingStorage::default();
+        import_cards(vec![incoming], policy, false, &storage, &mut db).unwrap();
+        (db, storage)
+    }
+
+    #[test]
+    fn union_adds_to_the_existing_card() {
+        let (db, storage) = import(MergePolicy::Union);
+        let merged = &db["学习|xue2xi2"];
+        assert_eq!(merged.category, vec!["HSK1", "Lesson 2"]);
+        assert_eq!(merged.definitions, vec!["to learn", "to study"]);
+        assert_eq!((merged.notes.as_str(), merged.schedule.repetitions), ("my note", 3));
+        assert_eq!(*storage.saved.borrow(), vec!["学习|xue2xi2"]);
+    }
+
+    #[test]
+    fn keep_leaves_the_existing_card() {
+        let (db, _) = import(MergePolicy::Keep);
+        let kept = &db["学习|xue2xi2"];
+        assert_eq!(kept.category, vec!["HSK1"]);
+        assert_eq!(kept.definitions, vec!["to learn"]);
+    }
+
+    #[test]
+    fn overwrite_replaces_all_but_the_review_schedule_and_notes() {
+        let (db, storage) = import(MergePolicy::Overwrite);
+        let overwritten = &db["学习|xue2xi2"];
+        assert_eq!(overwritten.category, vec!["Lesson 2"]);
+        assert_eq!(overwritten.definitions, vec!["to study"]);
+        assert_eq!((overwritten.notes.as_str(), overwritten.schedule.repetitions), ("my note", 3));
+        assert_eq!(*storage.saved.borrow(), vec!["学习|xue2xi2"]);
+    }
+
+    #[test]
+    fn dry_run_writes_nothing() {
+        let (mut db, incoming) = existing();
+        let before = serde_json::to_value(&db).unwrap();
+        let storage = RecordingStorage::default();
+        let new = Card::new("你好", Vec::new(), "ni3hao3", Source::new("test", None, None));
+        import_cards(vec![incoming, new], MergePolicy::Overwrite, true, &storage, &mut db).unwrap();
+        assert_eq!(serde_json::to_value(&db).unwrap(), before);
+        assert!(storage.saved.borrow().is_empty() && storage.removed.borrow().is_empty());
+    }
+
+    #[test]
+    fn preview_counts_each_card_once() {
+        let bare = Card::new("行", vec!["HSK2".to_string()], "", Source::new("test", None, None));
+        let before: DB = HashMap::from([(bare.key(), bare)]);
+        let mut after = before.clone();
+        let mut changes = CardChanges::default();
+        let reading = Card::new("行", Vec::new(), "hang2", Source::new("test", None, None));
+        let new = Card::new("你好", Vec::new(), "ni3hao3", Source::new("test", None, None));
+        for card in [reading, new.clone(), new] {
+            insert_card(&mut after, card, MergePolicy::Union, &mut changes);
+        }
+        let preview = ImportPreview::new(&before, &after, &changes).unwrap();
+        // The card given a reading is listed as moved, not as a new card as well.
+        assert_eq!(preview.rekeyed, vec![("行".to_string(), "行|hang2".to_string())]);
+        assert_eq!(preview.added, vec!["你好|ni3hao3"]);
+        assert!(preview.changed.is_empty());
+        assert_eq!(preview.to_string().lines().next(), Some("1 added, 1 changed, 0 unchanged"));
+    }
+}
@@ -3,18 +3,67 @@ mod openai_prompts;
 mod import;
 mod db;
 mod export;
+mod review;
+mod sqlite;
+mod migrations;
+mod backup;
+mod dictionary;
+mod segment;
+mod pinyin;
+mod pleco;
+mod sync;
+mod anki;
+mod mapping;
+mod spreadsheet;
+mod pdf;
+mod ocr;
+mod ocr_cache;
+mod vocab_table;
+mod staging;
+mod chunking;
+
+use std::path::Path;
+use std::sync::Arc;
 
 use clap::{Parser, Subcommand};
 
-use crate::db::save_db;
+use crate::db::open_storage;
+use crate::db::Storage;
+use crate::db::StorageKind;
+use crate::db::MergePolicy;
 use crate::translation::generate_translation;
 use crate::translation::generate_translation_category;
 use crate::import::import_pleco;
 use crate::import::import_text;
-use crate::import::import_png;
+use crate::import::import_images;
+use crate::import::import_pdf;
+use crate::pdf::{PageCategory, PageRanges};
+use crate::ocr::{OcrLanguage, OcrOptions};
+use crate::ocr_cache::OcrCache;
 use crate::export::export_pleco;
-
-const DB_LOCATION: &str = "./data";
+use crate::review::review;
+use crate::dictionary::Dictionary;
+use crate::dictionary::DEFAULT_DICTIONARY_LOCATION;
+use crate::segment::Segmentation;
+use crate::review::DailyLimits;
+use crate::pinyin::PinyinStyle;
+use crate::sync::ConflictPolicy;
+use crate::pleco::PlecoFormat;
+use crate::anki::NoteType;
+use crate::anki::CategorySource;
+use crate::mapping::FieldAssignment;
+use crate::anki::AnkiImportOptions;
+use crate::import::import_anki;
+use crate::import::import_csv;
+use crate::import::CsvImportOptions;
+use crate::import::PdfImportOptions;
+use crate::import::TextImportOptions;
+use crate::import::review_queue;
+use crate::staging::Staging;
+use crate::chunking::{ChunkOptions, ChunkOrder};
+use crate::export::CsvExportOptions;
+use crate::mapping::FieldTarget;
+use crate::spreadsheet::HeaderMode;
 
 #[derive(Parser)]
 #[command(name = "chinese-pratice-tool")]
@@ -22,6 +71,22 @@ const DB_LOCATION: &str = "./data";
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Storage backend holding the cards
+    #[arg(long, value_enum, global = true, default_value_t = StorageKind::Flat)]
+    storage: StorageKind,
+    /// Location of the card database, defaults to ./data or ./data.sqlite
+    #[arg(long, global = true)]
+    db: Option<String>,
+    /// Fail instead of waiting when another command is using the database
+    #[arg(long, global = true)]
+    no_wait: bool,
+    /// Local CC-CEDICT file used for pinyin and definitions
+    #[arg(long, global = true, default_value = DEFAULT_DICTIONARY_LOCATION)]
+    dictionary: String,
+    /// How pinyin is written when displaying or exporting cards: tone marks for display and
+    /// tone numbers for Pleco export unless set
+    #[arg(long, value_enum, global = true)]
+    pinyin_style: Option<PinyinStyle>,
 }
 
 #[derive(Subcommand)]
@@ -30,34 +95,299 @@ enum Commands {
     Translate {
         character: String,
     },
+    /// Look a word up in the offline dictionary
+    Lookup {
+        word: String,
+    },
     Delete {
-        category: String,
+        category: Option<String>,
+        /// Delete the cards for one headword instead of a whole category
+        #[arg(long, conflicts_with = "category")]
+        word: Option<String>,
+        /// Which reading of --word to delete when the headword has several
+        #[arg(long, requires = "word")]
+        pinyin: Option<String>,
+    },
+    /// Set the free-form note on every card for a headword
+    Note {
+        character: String,
+        note: String,
+    },
+    /// Add an example sentence to every card for a headword
+    Example {
+        character: String,
+        sentence: String,
+    },
+    Review {
+        category: Option<String>,
+        /// Maximum number of never-seen cards to introduce per day
+        #[arg(long, default_value_t = 20)]
+        new_per_day: usize,
+        /// Maximum number of due cards to review per day
+        #[arg(long, default_value_t = 200)]
+        reviews_per_day: usize,
     },
     #[clap(subcommand)]
     Import(Import),
 
     #[clap(subcommand)]
-    Export(Export)
+    Export(Export),
+
+    #[clap(subcommand)]
+    Db(Db),
+
+    #[clap(subcommand)]
+    Backup(Backup),
+
+    #[clap(subcommand)]
+    Sync(Sync),
+}
+
+impl Commands {
+    /// Whether the command can change the database, and so needs it to itself.
+    fn writes(&self) -> bool {
+        match self {
+            Commands::Greet {} | Commands::Translate { .. } | Commands::Lookup { .. } | Commands::Export(_) => false,
+            Commands::Db(Db::Migrate { dry_run }) => !dry_run,
+            Commands::Import(Import::Pleco { dry_run, .. } | Import::Anki { dry_run, .. } | Import::Csv { dry_run, .. }) => !dry_run,
+            Commands::Sync(Sync::Pleco { dry_run, .. }) => !dry_run,
+            Commands::Backup(Backup::Prune { dry_run }) => !dry_run,
+            Commands::Backup(Backup::List {} | Backup::Diff { .. }) => false,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Backup {
+    /// List backups, newest first
+    List {},
+    /// Show what changed between a backup and the live DB
+    Diff {
+        /// Backup id, or its number in `backup list`
+        id: String,
+    },
+    /// Replace the live DB with a backup
+    Restore {
+        /// Backup id, or its number in `backup list`
+        id: String,
+        /// Restore without asking for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Compress and delete old backups according to the retention policy
+    Prune {
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Show or change the retention policy used when pruning
+    Policy {
+        /// Number of most recent backups kept as they are
+        #[arg(long)]
+        keep_last: Option<usize>,
+        /// Number of days for which the newest backup of each day is kept compressed
+        #[arg(long)]
+        keep_daily: Option<u32>,
+    },
+}
+
+#[derive(Subcommand)]
+enum Sync {
+    /// Sync with a Pleco flashcard export, writing a Pleco import file with what changed here
+    Pleco {
+        export_file: String,
+        /// Which side wins when a card changed on both since the last sync
+        #[arg(long, value_enum, default_value_t = ConflictPolicy::Union)]
+        conflicts: ConflictPolicy,
+        /// The export holds every card in Pleco, so cards missing from it were deleted there.
+        /// Without it, only cards in the export's categories count as deleted
+        #[arg(long)]
+        full_export: bool,
+        /// Delete the cards that were deleted in Pleco without asking
+        #[arg(long)]
+        confirm: bool,
+        /// Report what would change without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum Db {
+    /// Copy every card into another storage backend
+    Convert {
+        #[arg(value_enum)]
+        to: StorageKind,
+        path: Option<String>,
+    },
+    /// Upgrade the stored cards to the current format version
+    Migrate {
+        /// Report what would change without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
 }
 
 #[derive(Subcommand)]
 enum Import {
     Pleco {
         file_location: String,
+        /// How to combine imported cards with cards that already exist
+        #[arg(long, value_enum, default_value_t = MergePolicy::Union)]
+        merge: MergePolicy,
+        /// Print what would be added and changed without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Import an Anki package (.apkg, .colpkg) or an Anki plain text export
+    Anki {
+        file_location: String,
+        /// Which Anki field goes into which part of the card, like `--field pinyin=Reading` or
+        /// `--field meaning=3` for a text export's third column. Unmapped parts are guessed
+        /// from the field names
+        #[arg(long = "field")]
+        fields: Vec<FieldAssignment>,
+        /// Turn the note's decks, tags or both into categories
+        #[arg(long, value_enum, default_value_t = CategorySource::Deck)]
+        categories: CategorySource,
+        /// Add every card to this category as well
+        #[arg(long)]
+        category: Option<String>,
+        /// Keep the review intervals and due dates from Anki
+        #[arg(long)]
+        intervals: bool,
+        /// How to combine imported cards with cards that already exist
+        #[arg(long, value_enum, default_value_t = MergePolicy::Union)]
+        merge: MergePolicy,
+        /// Print what would be added and changed without writing anything
+        #[arg(long)]
+        dry_run: bool,
     },
+    /// Import a vocabulary list saved as CSV or TSV
+    Csv {
+        file_location: String,
+        /// Which column goes into which part of the card, by header name or 1-based number, like
+        /// `--column hanzi=Word` or `--column meaning=3`. Unmapped parts are guessed from the header
+        #[arg(long = "column")]
+        columns: Vec<FieldAssignment>,
+        /// Column delimiter, a character or tab, comma, semicolon. Detected when not given
+        #[arg(long)]
+        delimiter: Option<String>,
+        /// Whether the first row names the columns
+        #[arg(long, value_enum, default_value_t = HeaderMode::Auto)]
+        header: HeaderMode,
+        /// The file's encoding, like utf-8, gb18030 or big5. Detected when not given
+        #[arg(long)]
+        encoding: Option<String>,
+        /// Add every card to this category as well
+        #[arg(long)]
+        category: Option<String>,
+        /// How to combine imported cards with cards that already exist
+        #[arg(long, value_enum, default_value_t = MergePolicy::Union)]
+        merge: MergePolicy,
+        /// Print what would be added and changed without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Import the words in a PDF, from its text layer or by OCR for scanned pages
     PDF {
-        category: String
+        file_location: String,
+        category: String,
+        /// Only these pages, like 1-3,5,8-
+        #[arg(long)]
+        pages: Option<PageRanges>,
+        /// Put the words on some pages in another category, like `--page-category "4-6=Lesson 2"`
+        #[arg(long = "page-category")]
+        page_categories: Vec<PageCategory>,
+        /// The script of scanned pages
+        #[arg(long = "lang", value_enum, default_value_t = OcrLanguage::Simplified)]
+        language: OcrLanguage,
+        /// OCRed words with less confidence than this (0-100) are flagged, and queued even with --no-review
+        #[arg(long, default_value_t = 60.0)]
+        min_confidence: f32,
+        /// OCR pages again even when the OCR cache has them
+        #[arg(long)]
+        reocr: bool,
+        /// Add the words straight to the DB instead of queueing them for `import review`
+        #[arg(long)]
+        no_review: bool,
+        #[command(flatten)]
+        chunks: ChunkArgs,
+    },
+    /// Import the words in a directory of page images (PNG, JPEG, TIFF or WebP), asking for the directory
+    #[command(alias = "png")]
+    Images {
+        category: String,
+        /// The script of the pages
+        #[arg(long = "lang", value_enum, default_value_t = OcrLanguage::Simplified)]
+        language: OcrLanguage,
+        /// Words with less confidence than this (0-100) are flagged, and queued even with --no-review
+        #[arg(long, default_value_t = 60.0)]
+        min_confidence: f32,
+        /// OCR pages again even when the OCR cache has them
+        #[arg(long)]
+        reocr: bool,
+        /// Add the words straight to the DB instead of queueing them for `import review`
+        #[arg(long)]
+        no_review: bool,
+        #[command(flatten)]
+        chunks: ChunkArgs,
     },
     Text {
         text: String,
         category: String,
+        /// How to split the text into words
+        #[arg(long, value_enum, default_value_t = Segmentation::Dictionary)]
+        segmenter: Segmentation,
+        /// Word frequency list (`word frequency` per line, like jieba's dict.txt) to guide segmentation
+        #[arg(long)]
+        frequencies: Option<String>,
+        /// Add the words straight to the DB instead of queueing them for `import review`
+        #[arg(long)]
+        no_review: bool,
     },
+    /// Accept, edit, split, merge or reject the words text, PDF and image imports queued
+    Review {},
+}
+
+/// How `import pdf` and `import images` file words into numbered chunks of a category.
+#[derive(clap::Args)]
+struct ChunkArgs {
+    /// Words per chunk
+    #[arg(long, default_value_t = 50)]
+    chunk_size: usize,
+    /// The order words are filed into chunks in
+    #[arg(long, value_enum, default_value_t = ChunkOrder::Source)]
+    order: ChunkOrder,
+    /// Seed for `--order shuffle`, to shuffle the same way every time
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Chunk names, with {category} and {n} filled in
+    #[arg(long, default_value = "{category}-{n}")]
+    chunk_name: String,
+    /// Word frequency list (`word frequency` per line, like jieba's dict.txt) for `--order frequency`
+    #[arg(long)]
+    frequencies: Option<String>,
+    /// HSK word list (`word level` per line) for `--order hsk`
+    #[arg(long)]
+    levels: Option<String>,
+}
+
+impl ChunkArgs {
+    fn options(&self) -> Result<ChunkOptions, String> {
+        let frequencies = self.frequencies.as_deref().map(segment::load_frequencies).transpose().map_err(|e| e.to_string())?;
+        let levels = self.levels.as_deref().map(chunking::load_levels).transpose().map_err(|e| e.to_string())?;
+        ChunkOptions::new(self.chunk_size, self.order, self.seed, &self.chunk_name, frequencies, levels).map_err(|e| e.to_string())
+    }
 }
 
 #[derive(Subcommand)]
 enum Export {
     Pleco {
         category: String,
+        /// Write plecoflash XML or Pleco's tab separated text format
+        #[arg(long, value_enum, default_value_t = PlecoFormat::Xml)]
+        format: PlecoFormat,
     },
     Text {
         category: String,
@@ -65,41 +395,264 @@ enum Export {
     Examples {
         category: String
     },
+    /// Write a category as an Anki package, <category>.apkg
+    Anki {
+        category: String,
+        /// Which cards each word gets
+        #[arg(long, value_enum, default_value_t = NoteType::Basic)]
+        note_type: NoteType,
+        /// Put the words' example sentences on the back of the cards
+        #[arg(long)]
+        examples: bool,
+    },
+    /// Write a category as <category>.csv, or .tsv when tab separated
+    Csv {
+        category: String,
+        /// The columns to write, in order
+        #[arg(long, value_enum, value_delimiter = ',', default_value = "hanzi,traditional,pinyin,meaning,examples,notes,category")]
+        columns: Vec<FieldTarget>,
+        /// Column delimiter, a character or tab, comma, semicolon
+        #[arg(long, default_value = ",")]
+        delimiter: String,
+        /// Leave out the header row naming the columns
+        #[arg(long)]
+        no_header: bool,
+        /// The encoding to write, like utf-8 or gb18030
+        #[arg(long, default_value = "utf-8")]
+        encoding: String,
+    },
 }
 
 #[tokio::main]
 async fn main() {
-    // Fine to panic here
-    let mut db = db::load_db(DB_LOCATION).expect("Unable to load db.");
     let cli = Cli::parse();
+    let db_location = cli.db.clone().unwrap_or(cli.storage.default_location().to_string());
+    let writes = cli.command.writes();
+    let _lock = match db::lock_db(&db_location, writes, !cli.no_wait) {
+        Ok(lock) => lock,
+        Err(e) => return println!("Failed to lock db: {}", e),
+    };
+    // Fine to panic here
+    let storage = open_storage(cli.storage, &db_location, writes).expect("Unable to open db.");
+    let storage = storage.as_ref();
+    // Backups used to share one directory whatever the DB. They were all of the flat file DB.
+    if cli.storage == StorageKind::Flat && writes {
+        let directory = backup::backup_dir(&db_location);
+        match backup::adopt_legacy_backups(&directory) {
+            Ok(0) => {},
+            Ok(moved) => println!("Moved {} backups from ./backups to {}", moved, directory.display()),
+            Err(e) => println!("Failed to move the backups in ./backups: {}", e),
+        }
+    }
+    // Migrating has to happen before loading, which would otherwise upgrade the cards itself.
+    if let Commands::Db(Db::Migrate { dry_run }) = &cli.command {
+        migrate(storage, *dry_run);
+        return;
+    }
+    let mut db = match storage.load() {
+        Ok(db) => db,
+        Err(e) => return println!("Failed to load db: {}", e),
+    };
+    let display_style = cli.pinyin_style.unwrap_or(PinyinStyle::Marks);
     match &cli.command {
         Commands::Greet {} => greet(&db),
-        Commands::Delete { category } => delete(category, &mut db),
+        Commands::Delete { category, word, pinyin } => match (category, word) {
+            (Some(category), _) => delete(category, storage, &mut db),
+            (None, Some(word)) => delete_word(word, pinyin.as_deref(), display_style, storage, &mut db),
+            (None, None) => println!("Pass a category or --word to delete"),
+        },
         Commands::Translate { character } => generate_translation(character).await,
+        Commands::Lookup { word } => lookup(word, &cli.dictionary, display_style, &db),
+        Commands::Note { character, note } => set_note(character, note, storage, &mut db),
+        Commands::Example { character, sentence } => add_example(character, sentence, storage, &mut db),
+        Commands::Review { category, new_per_day, reviews_per_day } => {
+            let limits = DailyLimits { new_cards: *new_per_day, reviews: *reviews_per_day };
+            if let Err(e) = review(category.as_deref(), &limits, display_style, &mut db, storage) {
+                println!("Failed to review cards: {}", e);
+            }
+        },
         Commands::Import(import) => match import {
-            Import::Pleco { file_location } => {
-                let import_result = import_pleco(file_location, DB_LOCATION, &mut db);
+            Import::Pleco { file_location, merge, dry_run } => {
+                let dictionary = load_dictionary(&cli.dictionary);
+                let import_result = import_pleco(file_location, *merge, *dry_run, storage, &mut db, dictionary.as_ref());
                 if let Err(e) = import_result {
-                    println!("Failed to import pleco xml file: {}", e);
-                } else {
-                    println!("Imported pleco xml file successfully.");
+                    println!("Failed to import pleco file: {}", e);
+                } else if !dry_run {
+                    println!("Imported pleco file successfully.");
                 }
             },
-            Import::PDF {category} => {
-                let import_result = import_png(category, DB_LOCATION, &mut db).await;
+            Import::Anki { file_location, fields, categories, category, intervals, merge, dry_run } => {
+                let dictionary = load_dictionary(&cli.dictionary);
+                let options = AnkiImportOptions {
+                    mapping: fields.clone(),
+                    categories: *categories,
+                    category: category.clone(),
+                    intervals: *intervals,
+                };
+                let import_result = import_anki(file_location, &options, *merge, *dry_run, storage, &mut db, dictionary.as_ref());
                 if let Err(e) = import_result {
-                    println!("Failed to import png file: {}", e);
+                    println!("Failed to import anki file: {}", e);
+                } else if !dry_run {
+                    println!("Imported anki file successfully.");
+                }
+            },
+            Import::Csv { file_location, columns, delimiter, header, encoding, category, merge, dry_run } => {
+                let dictionary = load_dictionary(&cli.dictionary);
+                let options = CsvImportOptions {
+                    mapping: columns.clone(),
+                    delimiter: delimiter.clone(),
+                    header: *header,
+                    encoding: encoding.clone(),
+                    category: category.clone(),
+                };
+                let import_result = import_csv(file_location, &options, *merge, *dry_run, storage, &mut db, dictionary.as_ref());
+                if let Err(e) = import_result {
+                    println!("Failed to import csv file: {}", e);
+                } else if !dry_run {
+                    println!("Imported csv file successfully.");
+                }
+            },
+            Import::PDF { file_location, category, pages, page_categories, language, min_confidence, reocr, no_review, chunks } => {
+                let chunks = match chunks.options() {
+                    Ok(chunks) => chunks,
+                    Err(e) => return println!("Failed to import pdf file: {}", e),
+                };
+                let dictionary = load_dictionary(&cli.dictionary);
+                let options = PdfImportOptions {
+                    category: category.clone(),
+                    pages: pages.clone(),
+                    page_categories: page_categories.clone(),
+                    ocr: OcrOptions { language: *language, min_confidence: *min_confidence, cache: Arc::new(OcrCache::new(&db_location, *reocr)) },
+                    chunks,
+                };
+                let staging = Staging { queue: staging::queue_path(&db_location), review: !no_review };
+                let import_result = import_pdf(file_location, &options, &staging, storage, &mut db, dictionary.as_ref()).await;
+                if let Err(e) = import_result {
+                    println!("Failed to import pdf file: {}", e);
+                } else {
+                    println!("Imported pdf file successfully.");
+                }
+            }
+            Import::Images { category, language, min_confidence, reocr, no_review, chunks } => {
+                let chunks = match chunks.options() {
+                    Ok(chunks) => chunks,
+                    Err(e) => return println!("Failed to import images: {}", e),
+                };
+                let dictionary = load_dictionary(&cli.dictionary);
+                let options = OcrOptions { language: *language, min_confidence: *min_confidence, cache: Arc::new(OcrCache::new(&db_location, *reocr)) };
+                let staging = Staging { queue: staging::queue_path(&db_location), review: !no_review };
+                let import_result = import_images(category, &options, &chunks, &staging, storage, &mut db, dictionary.as_ref()).await;
+                if let Err(e) = import_result {
+                    println!("Failed to import images: {}", e);
                 } else {
-                    println!("Imported png file successfully.");
+                    println!("Imported images successfully.");
                 }
             }
-            Import::Text { text, category } => import_text(category, text, &mut db, DB_LOCATION).await,
+            Import::Text { text, category, segmenter, frequencies, no_review } => {
+                let dictionary = load_dictionary(&cli.dictionary);
+                let frequencies = frequencies.as_deref().and_then(|path| {
+                    segment::load_frequencies(path).map_err(|e| println!("{}. Segmenting without frequencies.", e)).ok()
+                });
+                let options = TextImportOptions { category: category.clone(), segmentation: *segmenter, frequencies };
+                let staging = Staging { queue: staging::queue_path(&db_location), review: !no_review };
+                import_text(text, &options, &staging, storage, &mut db, dictionary.as_ref()).await
+            },
+            Import::Review {} => {
+                let dictionary = load_dictionary(&cli.dictionary);
+                let staging = Staging { queue: staging::queue_path(&db_location), review: true };
+                if let Err(e) = review_queue(&staging, display_style, storage, &mut db, dictionary.as_ref()) {
+                    println!("Failed to review imported words: {}", e);
+                }
+            },
         },
         Commands::Export(export) => match export {
-            Export::Pleco{category}=> export_pleco(category, &db),
-            Export::Text{category}=> describe_category(category.to_string(), &db),
+            Export::Pleco{category, format}=> match export_pleco(category, cli.pinyin_style.unwrap_or(PinyinStyle::Numbers), *format, &db) {
+                Ok(file_name) => println!("Exported {} to {}", category, file_name),
+                Err(e) => println!("Failed to export pleco file: {}", e),
+            },
+            Export::Text{category}=> describe_category(category.to_string(), display_style, storage),
             Export::Examples { category } => generate_translation_category(category, &db).await,
-        }
+            Export::Anki { category, note_type, examples } => {
+                let options = anki::AnkiOptions { note_type: *note_type, examples: *examples, style: display_style };
+                match export::export_anki(category, &options, &db) {
+                    Ok(file_name) => println!("Exported {} to {}", category, file_name),
+                    Err(e) => println!("Failed to export anki package: {}", e),
+                }
+            },
+            Export::Csv { category, columns, delimiter, no_header, encoding } => {
+                let options = CsvExportOptions {
+                    columns: columns.clone(),
+                    delimiter: delimiter.clone(),
+                    header: !no_header,
+                    encoding: encoding.clone(),
+                    style: display_style,
+                };
+                match export::export_csv(category, &options, &db) {
+                    Ok(file_name) => println!("Exported {} to {}", category, file_name),
+                    Err(e) => println!("Failed to export csv file: {}", e),
+                }
+            },
+        },
+        Commands::Db(command) => match command {
+            Db::Convert { to, path } => {
+                let path = path.clone().unwrap_or(to.default_location().to_string());
+                let converted = open_storage(*to, &path, true).and_then(|target| target.save(&db));
+                match converted {
+                    Ok(_) => println!("Copied {} cards to {}", db.len(), path),
+                    Err(e) => println!("Failed to convert db: {}", e),
+                }
+            },
+            Db::Migrate { .. } => unreachable!("migrate runs before the db is loaded"),
+        },
+        Commands::Backup(command) => match command {
+            Backup::List {} => list_backups(&backup::backup_dir(&db_location), &db),
+            Backup::Diff { id } => diff_backup(&backup::backup_dir(&db_location), id, &db),
+            Backup::Restore { id, yes } => restore_backup(&backup::backup_dir(&db_location), id, *yes, storage, &db),
+            Backup::Prune { dry_run } => prune_backups(&backup::backup_dir(&db_location), *dry_run),
+            Backup::Policy { keep_last, keep_daily } => set_backup_policy(&backup::backup_dir(&db_location), *keep_last, *keep_daily),
+        },
+        Commands::Sync(Sync::Pleco { export_file, conflicts, full_export, confirm, dry_run }) => {
+            let style = cli.pinyin_style.unwrap_or(PinyinStyle::Numbers);
+            let options = sync::SyncOptions { conflicts: *conflicts, style, full_export: *full_export, confirm: *confirm, dry_run: *dry_run };
+            match sync::sync_pleco(export_file, &db_location, &options, storage, &mut db) {
+                Ok(report) => {
+                    print!("{}", report);
+                    if *dry_run {
+                        println!("Dry run, nothing was written.");
+                    }
+                },
+                Err(e) => println!("Failed to sync with pleco: {}", e),
+            }
+        },
+    }
+}
+
+/// Imports still work without a dictionary, they just leave pinyin and definitions empty.
+fn load_dictionary(path: &str) -> Option<Dictionary> {
+    match Dictionary::load(path) {
+        Ok(dictionary) => Some(dictionary),
+        Err(e) => {
+            println!("{}. Pinyin and definitions will be left empty.", e);
+            None
+        },
+    }
+}
+
+fn lookup(word: &str, dictionary_location: &str, style: PinyinStyle, db: &db::DB) {
+    let dictionary = match Dictionary::load(dictionary_location) {
+        Ok(dictionary) => dictionary,
+        Err(e) => return println!("{}", e),
+    };
+    let entries = dictionary.lookup(word);
+    if entries.is_empty() {
+        println!("{} is not in the dictionary", word);
+    }
+    for entry in entries {
+        let reading = pinyin::display(&entry.pinyin, style);
+        println!("{} [{}] {}: {}", entry.simplified, entry.traditional, reading, entry.definitions.join(" / "));
+    }
+    for (_, card) in db::find_cards(db, word) {
+        println!("In your cards: {} ({})", describe_card(card, style), card.category.join(", "));
     }
 }
 
@@ -110,34 +663,212 @@ fn greet(db: &db::DB) {
     });
 }
 
-fn describe_category(category: String, db: &db::DB) {
-    let category_count = db::get_category_cards(db);
-    let category_result = category_count.get(&category.to_lowercase());
-    if let Some(category_cards) = category_result {
-        println!("{}", category_cards.len());
-        category_cards.iter().enumerate().for_each(|(i, card   )| {
-            print!("{} {},", card.character, card.pinyin)
-        });
+fn describe_category(category: String, style: PinyinStyle, storage: &dyn Storage) {
+    match storage.category_cards(&category) {
+        Ok(category_cards) if !category_cards.is_empty() => {
+            println!("{}", category_cards.len());
+            category_cards.iter().for_each(|card| println!("{}", describe_card(card, style)));
+        },
+        Ok(_) => println!("Could not find category"),
+        Err(e) => println!("Failed to read category: {}", e),
+    }
+}
+
+fn describe_card(card: &db::Card, style: PinyinStyle) -> String {
+    let mut line = card.character.clone();
+    if let Some(traditional) = &card.traditional {
+        line.push_str(&format!("[{}]", traditional));
+    }
+    if !card.pinyin.is_empty() {
+        line.push_str(&format!(" {}", pinyin::display(&card.pinyin, style)));
+    }
+    if !card.definitions.is_empty() {
+        line.push_str(&format!(" - {}", card.definitions.join("; ")));
+    }
+    if !card.notes.is_empty() {
+        line.push_str(&format!(" ({})", card.notes));
+    }
+    if let Some(source) = &card.source {
+        line.push_str(&format!(" <{}", source.command));
+        if let Some(file) = &source.file {
+            line.push_str(&format!(" {}", file));
+        }
+        if let Some(page) = source.page {
+            line.push_str(&format!(" p{}", page));
+        }
+        line.push('>');
+    }
+    line
+}
+
+fn set_note(character: &str, note: &str, storage: &dyn Storage, db: &mut db::DB) {
+    let updated: Vec<String> = db::find_cards(db, character).into_iter().map(|(key, _)| key.clone()).collect();
+    for key in &updated {
+        if let Some(card) = db.get_mut(key) {
+            card.notes = note.to_string();
+            card.touch();
+        }
+    }
+    if updated.is_empty() {
+        println!("Could not find {}", character);
+    } else if let Err(e) = storage.save_cards(db, &updated) {
+        println!("Failed to save note: {}", e);
+    } else {
+        println!("Note saved");
+    }
+}
+
+fn add_example(character: &str, sentence: &str, storage: &dyn Storage, db: &mut db::DB) {
+    let updated: Vec<String> = db::find_cards(db, character).into_iter().map(|(key, _)| key.clone()).collect();
+    for key in &updated {
+        if let Some(card) = db.get_mut(key) {
+            if !card.examples.iter().any(|example| example == sentence) {
+                card.examples.push(sentence.to_string());
+                card.touch();
+            }
+        }
+    }
+    if updated.is_empty() {
+        println!("Could not find {}", character);
+    } else if let Err(e) = storage.save_cards(db, &updated) {
+        println!("Failed to save example: {}", e);
     } else {
-        println!("Could not find category");
+        println!("Example saved");
     }
 }
 
-fn delete(category: &str, db: &mut db::DB) {
+fn migrate(storage: &dyn Storage, dry_run: bool) {
+    match storage.migrate(dry_run) {
+        Ok(report) => {
+            print!("{}", report);
+            if dry_run && !report.is_empty() {
+                println!("Dry run, nothing was written.");
+            }
+        },
+        Err(e) => println!("Failed to migrate db: {}", e),
+    }
+}
+
+fn delete(category: &str, storage: &dyn Storage, db: &mut db::DB) {
+    // Removed by the key each card is stored under, which needn't be what `card.key()` gives.
     let mut cards_to_remove: Vec<String> = Vec::new();
-    for card in db.values() {
+    for (key, card) in db.iter() {
         if card.category.contains(&category.to_string()) {
-            cards_to_remove.push(card.character.clone());
+            cards_to_remove.push(key.clone());
         }
     }
-    for character in cards_to_remove {
-        db.remove(&character);
+    for key in &cards_to_remove {
+        db.remove(key);
     }
 
-    let db_saved = save_db(DB_LOCATION, db);
+    let db_saved = storage.remove_cards(db, &cards_to_remove);
     if let Ok(_) = db_saved {
         println!("Category deleted");
     } else {
         println!("Failed to delete category")
     }
-}
\ No newline at end of file
+}
+fn delete_word(word: &str, pinyin: Option<&str>, style: PinyinStyle, storage: &dyn Storage, db: &mut db::DB) {
+    let readings: Vec<String> = db::find_cards(db, word)
+        .into_iter()
+        .filter(|(key, card)| pinyin.map(|pinyin| **key == db::card_key(&card.character, pinyin)).unwrap_or(true))
+        .map(|(key, _)| key.clone())
+        .collect();
+    if readings.is_empty() {
+        println!("Could not find {}", word);
+        return;
+    }
+    if readings.len() > 1 {
+        println!("{} has several readings, pick one with --pinyin:", word);
+        readings.iter().filter_map(|key| db.get(key)).for_each(|card| println!("  {}", describe_card(card, style)));
+        return;
+    }
+    for key in &readings {
+        db.remove(key);
+    }
+    match storage.remove_cards(db, &readings) {
+        Ok(_) => println!("Card deleted"),
+        Err(e) => println!("Failed to delete card: {}", e),
+    }
+}
+
+fn list_backups(directory: &Path, db: &db::DB) {
+    match backup::list_backups(directory) {
+        Ok(backups) if backups.is_empty() => println!("No backups yet"),
+        Ok(backups) => backups.iter().enumerate().for_each(|(i, entry)| {
+            let cards = entry.read().map(|backup| backup.len().to_string()).unwrap_or("?".to_string());
+            let compressed = if entry.compressed { " compressed" } else { "" };
+            println!("{:>3}. {} {} cards, {} bytes{}", i + 1, entry.id, cards, entry.size(), compressed);
+        }),
+        Err(e) => println!("Failed to list backups: {}", e),
+    }
+    if let Ok(legacy) = backup::legacy_backups() {
+        if !legacy.is_empty() {
+            println!("{} older backups of the flat file DB are still in ./backups, and move next to it the next time it is changed", legacy.len());
+        }
+    }
+    println!("Live DB: {} cards", db.len());
+}
+
+fn diff_backup(directory: &Path, id: &str, db: &db::DB) {
+    let diff = backup::find_backup(directory, id)
+        .and_then(|entry| entry.read())
+        .and_then(|backup_db| backup::diff(&backup_db, db));
+    match diff {
+        Ok(diff) => print!("{}", diff),
+        Err(e) => println!("Failed to diff backup: {}", e),
+    }
+}
+
+fn restore_backup(directory: &Path, id: &str, yes: bool, storage: &dyn Storage, db: &db::DB) {
+    let entry = match backup::find_backup(directory, id) {
+        Ok(entry) => entry,
+        Err(e) => return println!("Failed to find backup: {}", e),
+    };
+    let backup_db = match entry.read() {
+        Ok(backup_db) => backup_db,
+        Err(e) => return println!("Failed to read backup {}: {}", entry.id, e),
+    };
+    match backup::diff(&backup_db, db) {
+        Ok(diff) => print!("{}", diff),
+        Err(e) => return println!("Failed to diff backup: {}", e),
+    }
+    let confirmed = yes || inquire::Confirm::new(&format!("Restore {}?", entry.id))
+        .with_default(false)
+        .prompt()
+        .unwrap_or(false);
+    if !confirmed {
+        return println!("Restore cancelled");
+    }
+    match storage.save(&backup_db) {
+        Ok(_) => println!("Restored {} cards from {}", backup_db.len(), entry.id),
+        Err(e) => println!("Failed to restore backup: {}", e),
+    }
+}
+
+fn prune_backups(directory: &Path, dry_run: bool) {
+    let report = backup::load_policy(directory).and_then(|policy| backup::prune(directory, &policy, dry_run));
+    match report {
+        Ok(report) => {
+            report.compressed.iter().for_each(|id| println!("compress {}", id));
+            report.deleted.iter().for_each(|id| println!("delete {}", id));
+            println!("{} compressed, {} deleted{}", report.compressed.len(), report.deleted.len(), if dry_run { " (dry run)" } else { "" });
+        },
+        Err(e) => println!("Failed to prune backups: {}", e),
+    }
+}
+
+fn set_backup_policy(directory: &Path, keep_last: Option<usize>, keep_daily: Option<u32>) {
+    let mut policy = match backup::load_policy(directory) {
+        Ok(policy) => policy,
+        Err(e) => return println!("Failed to read retention policy: {}", e),
+    };
+    if keep_last.is_some() || keep_daily.is_some() {
+        policy.keep_last = keep_last.unwrap_or(policy.keep_last);
+        policy.keep_daily_days = keep_daily.unwrap_or(policy.keep_daily_days);
+        if let Err(e) = backup::save_policy(directory, &policy) {
+            return println!("Failed to save retention policy: {}", e);
+        }
+    }
+    println!("Keep the last {} backups, plus one compressed backup per day for {} days", policy.keep_last, policy.keep_daily_days);
+}
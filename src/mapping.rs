@@ -0,0 +1,145 @@
+use std::str::FromStr;
+
+/// A part of a card that an imported column or field can fill, or that an export can write.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum FieldTarget {
+    Hanzi,
+    Traditional,
+    Pinyin,
+    Meaning,
+    Examples,
+    Notes,
+    Category,
+}
+
+pub const TARGETS: [FieldTarget; 7] = [
+    FieldTarget::Hanzi,
+    FieldTarget::Traditional,
+    FieldTarget::Pinyin,
+    FieldTarget::Meaning,
+    FieldTarget::Examples,
+    FieldTarget::Notes,
+    FieldTarget::Category,
+];
+
+impl FieldTarget {
+    pub fn name(self) -> &'static str {
+        match self {
+            FieldTarget::Hanzi => "hanzi",
+            FieldTarget::Traditional => "traditional",
+            FieldTarget::Pinyin => "pinyin",
+            FieldTarget::Meaning => "meaning",
+            FieldTarget::Examples => "examples",
+            FieldTarget::Notes => "notes",
+            FieldTarget::Category => "category",
+        }
+    }
+
+    /// Lowercased field or column names taken for this target when no mapping names one.
+    fn aliases(self) -> &'static [&'static str] {
+        match self {
+            FieldTarget::Hanzi => &["hanzi", "simplified", "chinese", "characters", "character", "word", "front", "汉字", "简体", "词语", "生词"],
+            FieldTarget::Traditional => &["traditional", "繁体"],
+            FieldTarget::Pinyin => &["pinyin", "reading", "pronunciation", "拼音"],
+            FieldTarget::Meaning => &["meaning", "english", "definition", "definitions", "translation", "back", "英文", "意思", "释义", "翻译"],
+            FieldTarget::Examples => &["examples", "example", "sentences", "sentence", "例句"],
+            FieldTarget::Notes => &["notes", "note", "extra", "备注"],
+            FieldTarget::Category => &["category", "categories", "lesson", "课"],
+        }
+    }
+}
+
+/// A field or column, by name, or by its 1-based position.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldRef {
+    Name(String),
+    Column(usize),
+}
+
+/// A mapping such as `pinyin=Reading` or `meaning=3`.
+#[derive(Clone, Debug)]
+pub struct FieldAssignment {
+    pub target: FieldTarget,
+    pub source: FieldRef,
+}
+
+impl FromStr for FieldAssignment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (target, source) = s.split_once('=').ok_or(format!("Expected <card field>=<name or column>, like pinyin=Reading, got {}", s))?;
+        let target = TARGETS
+            .into_iter()
+            .find(|candidate| candidate.name().eq_ignore_ascii_case(target.trim()))
+            .ok_or(format!("Unknown card field {}, expected one of {}", target, TARGETS.map(FieldTarget::name).join(", ")))?;
+        let source = match source.trim().parse::<usize>() {
+            Ok(0) => return Err("Columns are numbered from 1".to_string()),
+            Ok(column) => FieldRef::Column(column),
+            Err(_) => FieldRef::Name(source.trim().to_string()),
+        };
+        Ok(FieldAssignment { target, source })
+    }
+}
+
+/// One value of an imported row or note.
+#[derive(Debug, Clone)]
+pub struct Field {
+    /// The field's name, from an Anki note type or a header row. Not every format has one.
+    pub name: Option<String>,
+    /// 1-based, the field's position in the note type or its column in the file.
+    pub column: usize,
+    pub value: String,
+}
+
+fn matches(field: &Field, source: &FieldRef) -> bool {
+    match source {
+        FieldRef::Name(name) => field.name.as_deref().is_some_and(|field_name| field_name.trim().eq_ignore_ascii_case(name)),
+        FieldRef::Column(column) => field.column == *column,
+    }
+}
+
+fn known(field: &Field, target: FieldTarget) -> bool {
+    field.name.as_deref().is_some_and(|name| target.aliases().contains(&name.trim().to_lowercase().as_str()))
+}
+
+/// Whether `name` is a familiar name for some part of a card, like `Pinyin` or `拼音`.
+pub fn recognized(name: &str) -> bool {
+    TARGETS.into_iter().any(|target| target.aliases().contains(&name.trim().to_lowercase().as_str()))
+}
+
+/// The field that fills `target`: the one `mapping` names, else one with a familiar name.
+/// When no field has a familiar name they go by position: hanzi first, then pinyin and
+/// meaning, or only the meaning when there are two fields.
+pub fn find<'a>(fields: &'a [Field], target: FieldTarget, mapping: &[FieldAssignment]) -> Option<&'a Field> {
+    if let Some(assignment) = mapping.iter().find(|assignment| assignment.target == target) {
+        return fields.iter().find(|field| matches(field, &assignment.source));
+    }
+    let unclaimed = |field: &Field| !mapping.iter().any(|assignment| matches(field, &assignment.source));
+    if let Some(field) = fields.iter().find(|field| unclaimed(field) && known(field, target)) {
+        return Some(field);
+    }
+    if fields.iter().any(|field| TARGETS.into_iter().any(|target| known(field, target))) {
+        return None;
+    }
+    let position = match (target, fields.len()) {
+        (FieldTarget::Hanzi, _) => 0,
+        (FieldTarget::Meaning, 2) => 1,
+        (FieldTarget::Pinyin, n) if n >= 3 => 1,
+        (FieldTarget::Meaning, n) if n >= 3 => 2,
+        _ => return None,
+    };
+    fields.get(position).filter(|field| unclaimed(field))
+}
+
+/// Which field fills which part of the card, like `Front -> hanzi, Back -> meaning`.
+pub fn describe(fields: &[Field], mapping: &[FieldAssignment]) -> String {
+    let described: Vec<String> = TARGETS
+        .into_iter()
+        .filter_map(|target| {
+            let field = find(fields, target, mapping)?;
+            let name = field.name.clone().unwrap_or(format!("column {}", field.column));
+            Some(format!("{} -> {}", name, target.name()))
+        })
+        .collect();
+    described.join(", ")
+}
@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use crate::db::DBError;
+use crate::pinyin;
+
+/// The format version written by this build. Bump it and append to `MIGRATIONS` whenever
+/// stored cards need reshaping.
+pub const CURRENT_VERSION: u32 = 7;
+
+/// A stored card as its key and raw JSON, before it is deserialized into a `Card`.
+pub type Record = (String, Value);
+
+struct Migration {
+    from: u32,
+    description: &'static str,
+    apply: fn(Vec<Record>) -> Result<Vec<Record>, DBError>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "add an empty review schedule to cards saved before review scheduling",
+        apply: add_schedule,
+    },
+    Migration {
+        from: 1,
+        description: "add empty definitions, traditional form, notes, timestamps and import source",
+        apply: add_card_details,
+    },
+    Migration {
+        from: 2,
+        description: "key cards by headword and reading instead of headword alone",
+        apply: key_by_reading,
+    },
+    Migration {
+        from: 3,
+        description: "store pinyin as tone numbers and rekey cards whose reading was written another way",
+        apply: normalize_pinyin,
+    },
+    Migration {
+        from: 4,
+        description: "add empty Pleco dictionary references and scores",
+        apply: add_pleco_details,
+    },
+    Migration {
+        from: 5,
+        description: "add empty example sentences",
+        apply: add_examples,
+    },
+    Migration {
+        from: 6,
+        description: "add empty textbook entry numbers to import sources",
+        apply: add_source_entries,
+    },
+];
+
+pub struct StepReport {
+    pub from: u32,
+    pub description: &'static str,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+pub struct MigrationReport {
+    pub from: u32,
+    pub to: u32,
+    pub steps: Vec<StepReport>,
+}
+
+impl MigrationReport {
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+impl std::fmt::Display for MigrationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "Already at format version {}", self.to);
+        }
+        writeln!(f, "Format version {} -> {}", self.from, self.to)?;
+        for step in &self.steps {
+            writeln!(f, "  v{} -> v{}: {}", step.from, step.from + 1, step.description)?;
+            writeln!(f, "    {} cards changed, {} removed", step.changed.len(), step.removed.len())?;
+            for key in step.changed.iter().take(10) {
+                writeln!(f, "    ~ {}", key)?;
+            }
+            for key in step.removed.iter().take(10) {
+                writeln!(f, "    - {}", key)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs every migration newer than `version` over `records`, reporting what each one changed.
+pub fn migrate(records: Vec<Record>, version: u32) -> Result<(Vec<Record>, MigrationReport), DBError> {
+    if version > CURRENT_VERSION {
+        return Err(DBError(format!(
+            "DB is format version {} but this build only understands up to {}",
+            version, CURRENT_VERSION
+        )));
+    }
+    let mut records = records;
+    let mut report = MigrationReport { from: version, to: CURRENT_VERSION, steps: Vec::new() };
+    for migration in MIGRATIONS.iter().filter(|migration| migration.from >= version) {
+        let before: HashMap<String, Value> = records.iter().cloned().collect();
+        records = (migration.apply)(records)?;
+        let after: HashMap<&str, &Value> = records.iter().map(|(k, v)| (k.as_str(), v)).collect();
+
+        let mut changed: Vec<String> = records
+            .iter()
+            .filter(|(key, value)| before.get(key) != Some(value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut removed: Vec<String> = before
+            .keys()
+            .filter(|key| !after.contains_key(key.as_str()))
+            .cloned()
+            .collect();
+        changed.sort();
+        removed.sort();
+        report.steps.push(StepReport { from: migration.from, description: migration.description, changed, removed });
+    }
+    Ok((records, report))
+}
+
+fn add_schedule(records: Vec<Record>) -> Result<Vec<Record>, DBError> {
+    Ok(records
+        .into_iter()
+        .map(|(key, mut value)| {
+            if let Some(card) = value.as_object_mut() {
+                card.entry("schedule").or_insert_with(|| json!({
+                    "due": null,
+                    "interval_days": 0,
+                    "ease": 2.5,
+                    "repetitions": 0,
+                    "lapses": 0,
+                    "history": [],
+                }));
+            }
+            (key, value)
+        })
+        .collect())
+}
+
+fn add_card_details(records: Vec<Record>) -> Result<Vec<Record>, DBError> {
+    Ok(records
+        .into_iter()
+        .map(|(key, mut value)| {
+            if let Some(card) = value.as_object_mut() {
+                card.entry("definitions").or_insert_with(|| json!([]));
+                card.entry("traditional").or_insert(Value::Null);
+                card.entry("notes").or_insert_with(|| json!(""));
+                card.entry("created").or_insert(Value::Null);
+                card.entry("modified").or_insert(Value::Null);
+                card.entry("source").or_insert(Value::Null);
+            }
+            (key, value)
+        })
+        .collect())
+}
+
+fn key_by_reading(records: Vec<Record>) -> Result<Vec<Record>, DBError> {
+    Ok(records
+        .into_iter()
+        .map(|(key, value)| {
+            let character = value.get("character").and_then(Value::as_str).unwrap_or(&key);
+            let reading: String = value
+                .get("pinyin")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .split_whitespace()
+                .collect::<String>()
+                .to_lowercase();
+            let new_key = if reading.is_empty() {
+                character.to_string()
+            } else {
+                format!("{}|{}", character, reading)
+            };
+            (new_key, value)
+        })
+        .collect())
+}
+
+/// Cards whose readings only differed in how they were written, like `nǐhǎo` and `ni3hao3`,
+/// end up under the same key. The one with the longer review history is kept and the
+/// other's categories are added to it.
+fn normalize_pinyin(records: Vec<Record>) -> Result<Vec<Record>, DBError> {
+    let mut normalized: Vec<Record> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for (key, mut value) in records {
+        let character = value.get("character").and_then(Value::as_str).unwrap_or(&key).to_string();
+        let reading = pinyin::canonical(value.get("pinyin").and_then(Value::as_str).unwrap_or_default());
+        let new_key = if reading.is_empty() { character } else { format!("{}|{}", character, reading) };
+        if let Some(card) = value.as_object_mut() {
+            card.insert("pinyin".to_string(), json!(reading));
+        }
+        match positions.get(&new_key) {
+            Some(position) => {
+                let existing = &mut normalized[*position].1;
+                if history_len(&value) > history_len(existing) {
+                    std::mem::swap(existing, &mut value);
+                }
+                merge_categories(existing, &value);
+            },
+            None => {
+                positions.insert(new_key.clone(), normalized.len());
+                normalized.push((new_key, value));
+            },
+        }
+    }
+    Ok(normalized)
+}
+
+fn history_len(card: &Value) -> usize {
+    card.pointer("/schedule/history").and_then(Value::as_array).map(Vec::len).unwrap_or(0)
+}
+
+fn merge_categories(into: &mut Value, from: &Value) {
+    let Some(from) = from.get("category").and_then(Value::as_array) else {
+        return;
+    };
+    if let Some(categories) = into.get_mut("category").and_then(Value::as_array_mut) {
+        for category in from {
+            if !categories.contains(category) {
+                categories.push(category.clone());
+            }
+        }
+    }
+}
+
+fn add_pleco_details(records: Vec<Record>) -> Result<Vec<Record>, DBError> {
+    Ok(records
+        .into_iter()
+        .map(|(key, mut value)| {
+            if let Some(card) = value.as_object_mut() {
+                card.entry("dictrefs").or_insert_with(|| json!([]));
+                card.entry("pleco_scores").or_insert_with(|| json!([]));
+            }
+            (key, value)
+        })
+        .collect())
+}
+
+fn add_examples(records: Vec<Record>) -> Result<Vec<Record>, DBError> {
+    Ok(records
+        .into_iter()
+        .map(|(key, mut value)| {
+            if let Some(card) = value.as_object_mut() {
+                card.entry("examples").or_insert_with(|| json!([]));
+            }
+            (key, value)
+        })
+        .collect())
+}
+
+fn add_source_entries(records: Vec<Record>) -> Result<Vec<Record>, DBError> {
+    Ok(records
+        .into_iter()
+        .map(|(key, mut value)| {
+            if let Some(source) = value.get_mut("source").and_then(Value::as_object_mut) {
+                source.entry("entry").or_insert(Value::Null);
+            }
+            (key, value)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(report: &MigrationReport, from: u32) -> &StepReport {
+        report.steps.iter().find(|step| step.from == from).expect("Step ran")
+    }
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    fn schedule(history: Value) -> Value {
+        json!({ "due": null, "interval_days": 0, "ease": 2.5, "repetitions": 0, "lapses": 0, "history": history })
+    }
+
+    fn review(grade: &str) -> Value {
+        json!({ "reviewed_at": "2024-01-01T00:00:00Z", "grade": grade, "interval_days": 1, "ease": 2.5 })
+    }
+
+    /// A card as the current version stores it.
+    fn current(character: &str, pinyin: &str, categories: Value, history: Value, source: Value) -> Value {
+        json!({
+            "character": character,
+            "category": categories,
+            "pinyin": pinyin,
+            "schedule": schedule(history),
+            "definitions": [],
+            "traditional": null,
+            "notes": "",
+            "created": null,
+            "modified": null,
+            "source": source,
+            "dictrefs": [],
+            "pleco_scores": [],
+            "examples": [],
+        })
+    }
+
+    /// `card` as it was stored before `fields` were added.
+    fn without(mut card: Value, fields: &[&str]) -> Value {
+        for field in fields {
+            card.as_object_mut().expect("Cards are objects").remove(*field);
+        }
+        card
+    }
+
+    const PLECO_FIELDS: [&str; 3] = ["dictrefs", "pleco_scores", "examples"];
+
+    #[test]
+    fn version_0_card_is_upgraded_all_the_way() {
+        let records = vec![("你好".to_string(), json!({ "character": "你好", "category": ["HSK1"], "pinyin": "nǐ hǎo" }))];
+        let (records, report) = migrate(records, 0).unwrap();
+        assert_eq!(records, vec![("你好|ni3hao3".to_string(), current("你好", "ni3hao3", json!(["HSK1"]), json!([]), Value::Null))]);
+        assert_eq!((report.from, report.to, report.steps.len()), (0, CURRENT_VERSION, MIGRATIONS.len()));
+        assert_eq!(step(&report, 0).changed, keys(&["你好"]));
+        assert_eq!(step(&report, 1).changed, keys(&["你好"]));
+        assert_eq!(step(&report, 2).changed, keys(&["你好|nǐhǎo"]));
+        assert_eq!(step(&report, 2).removed, keys(&["你好"]));
+        assert_eq!(step(&report, 3).changed, keys(&["你好|ni3hao3"]));
+        assert_eq!(step(&report, 3).removed, keys(&["你好|nǐhǎo"]));
+        assert_eq!(step(&report, 6).changed, keys(&[]));
+        assert!(records_to_cards(records));
+    }
+
+    #[test]
+    fn version_1_card_gets_its_details() {
+        let records = vec![("好".to_string(), json!({ "character": "好", "category": [], "pinyin": "", "schedule": schedule(json!([review("Good")])) }))];
+        let (records, report) = migrate(records, 1).unwrap();
+        assert_eq!(records, vec![("好".to_string(), current("好", "", json!([]), json!([review("Good")]), Value::Null))]);
+        assert!(report.steps.iter().all(|step| step.from >= 1));
+        assert_eq!(step(&report, 1).changed, keys(&["好"]));
+        // A card without a reading keeps its bare key.
+        assert_eq!(step(&report, 2).changed, keys(&[]));
+        assert_eq!(step(&report, 2).removed, keys(&[]));
+    }
+
+    #[test]
+    fn version_2_cards_are_keyed_by_reading() {
+        let records = vec![("行".to_string(), without(current("行", "Hang2", json!([]), json!([]), Value::Null), &PLECO_FIELDS))];
+        let (records, report) = migrate(records, 2).unwrap();
+        assert_eq!(records[0].0, "行|hang2");
+        assert_eq!(step(&report, 2).changed, keys(&["行|hang2"]));
+        assert_eq!(step(&report, 2).removed, keys(&["行"]));
+        // Normalizing lowercases the stored reading too, without rekeying it again.
+        assert_eq!(records[0].1["pinyin"], "hang2");
+        assert_eq!(step(&report, 3).changed, keys(&["行|hang2"]));
+        assert_eq!(step(&report, 3).removed, keys(&[]));
+    }
+
+    #[test]
+    fn version_3_readings_written_two_ways_are_merged() {
+        let card = |pinyin: &str, categories: Value, history: Value| without(current("你好", pinyin, categories, history, Value::Null), &PLECO_FIELDS);
+        let records = vec![
+            ("你好|nǐhǎo".to_string(), card("nǐhǎo", json!(["Marks"]), json!([]))),
+            ("你好|ni3hao3".to_string(), card("ni3hao3", json!(["Numbers"]), json!([review("Good")]))),
+        ];
+        let (records, report) = migrate(records, 3).unwrap();
+        // The card with the longer history wins and picks up the other's categories.
+        assert_eq!(records, vec![("你好|ni3hao3".to_string(), current("你好", "ni3hao3", json!(["Numbers", "Marks"]), json!([review("Good")]), Value::Null))]);
+        assert_eq!(step(&report, 3).changed, keys(&["你好|ni3hao3"]));
+        assert_eq!(step(&report, 3).removed, keys(&["你好|nǐhǎo"]));
+    }
+
+    #[test]
+    fn version_4_card_gets_pleco_details() {
+        let card = without(current("好", "hao3", json!([]), json!([]), Value::Null), &PLECO_FIELDS);
+        let (records, report) = migrate(vec![("好|hao3".to_string(), card)], 4).unwrap();
+        assert_eq!(records, vec![("好|hao3".to_string(), current("好", "hao3", json!([]), json!([]), Value::Null))]);
+        assert_eq!(step(&report, 4).changed, keys(&["好|hao3"]));
+        assert_eq!(step(&report, 5).changed, keys(&["好|hao3"]));
+    }
+
+    #[test]
+    fn version_5_card_gets_examples() {
+        let card = without(current("好", "hao3", json!([]), json!([]), Value::Null), &["examples"]);
+        let (records, report) = migrate(vec![("好|hao3".to_string(), card)], 5).unwrap();
+        assert_eq!(records, vec![("好|hao3".to_string(), current("好", "hao3", json!([]), json!([]), Value::Null))]);
+        assert_eq!(report.steps.len(), 2);
+        assert_eq!(step(&report, 5).changed, keys(&["好|hao3"]));
+    }
+
+    #[test]
+    fn version_6_source_gets_an_entry_number() {
+        let old_source = json!({ "command": "import pdf", "file": "book.pdf", "page": 3 });
+        let records = vec![
+            ("好|hao3".to_string(), current("好", "hao3", json!([]), json!([]), old_source)),
+            ("他|ta1".to_string(), current("他", "ta1", json!([]), json!([]), Value::Null)),
+        ];
+        let (records, report) = migrate(records, 6).unwrap();
+        let new_source = json!({ "command": "import pdf", "file": "book.pdf", "page": 3, "entry": null });
+        assert_eq!(records[0].1, current("好", "hao3", json!([]), json!([]), new_source));
+        assert_eq!(records[1].1, current("他", "ta1", json!([]), json!([]), Value::Null));
+        assert_eq!(step(&report, 6).changed, keys(&["好|hao3"]));
+        assert!(records_to_cards(records));
+    }
+
+    #[test]
+    fn current_version_is_left_alone() {
+        let records = vec![("好|hao3".to_string(), current("好", "hao3", json!([]), json!([]), Value::Null))];
+        let (migrated, report) = migrate(records.clone(), CURRENT_VERSION).unwrap();
+        assert_eq!(migrated, records);
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn newer_version_is_refused() {
+        assert!(migrate(Vec::new(), CURRENT_VERSION + 1).is_err());
+    }
+
+    fn records_to_cards(records: Vec<Record>) -> bool {
+        crate::db::records_to_db(records).is_ok()
+    }
+}
@@ -0,0 +1,301 @@
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use leptess::leptonica::PixError;
+use leptess::tesseract::TessInitError;
+use leptess::LepTess;
+use regex::Regex;
+
+use crate::ocr_cache::OcrCache;
+use crate::pdf::PdfError;
+use crate::vocab_table::{self, Token};
+
+#[derive(Debug)]
+pub struct OcrError(String);
+
+impl std::fmt::Display for OcrError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for OcrError {}
+
+impl From<std::io::Error> for OcrError {
+    fn from(e: std::io::Error) -> Self {
+        OcrError(format!("IO error: {}", e))
+    }
+}
+
+impl From<TessInitError> for OcrError {
+    fn from(e: TessInitError) -> Self {
+        OcrError(format!("Failed to start Tesseract, is its language data installed? {}", e))
+    }
+}
+
+impl From<PixError> for OcrError {
+    fn from(e: PixError) -> Self {
+        OcrError(format!("Failed to read the image: {}", e))
+    }
+}
+
+impl From<std::str::Utf8Error> for OcrError {
+    fn from(e: std::str::Utf8Error) -> Self {
+        OcrError(format!("Tesseract returned invalid text: {}", e))
+    }
+}
+
+impl From<PdfError> for OcrError {
+    fn from(e: PdfError) -> Self {
+        OcrError(format!("Failed to render the page: {}", e))
+    }
+}
+
+/// The Tesseract language data pages are read with.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OcrLanguage {
+    /// Simplified characters
+    #[value(name = "chi_sim")]
+    Simplified,
+    /// Traditional characters
+    #[value(name = "chi_tra")]
+    Traditional,
+    /// Pages that mix both scripts. Slower
+    #[value(name = "chi_sim+chi_tra")]
+    Both,
+}
+
+impl OcrLanguage {
+    pub fn code(self) -> &'static str {
+        match self {
+            OcrLanguage::Simplified => "chi_sim",
+            OcrLanguage::Traditional => "chi_tra",
+            OcrLanguage::Both => "chi_sim+chi_tra",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct OcrOptions {
+    pub language: OcrLanguage,
+    /// Words Tesseract is less sure of than this, from 0 to 100, are queued for review.
+    pub min_confidence: f32,
+    pub cache: Arc<OcrCache>,
+}
+
+/// Page image formats leptonica reads.
+const IMAGE_EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "tif", "tiff", "webp"];
+
+pub fn is_image(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| IMAGE_EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str()))
+}
+
+/// A word read off a page, with the confidence of its least certain character. Words from a
+/// vocabulary table also have the pinyin and gloss printed next to them, and the entry number.
+#[derive(Debug, Clone, Default)]
+pub struct OcrWord {
+    pub text: String,
+    pub confidence: f32,
+    pub pinyin: String,
+    pub gloss: String,
+    pub entry: Option<u32>,
+    /// The 1-based line of the page the word was on.
+    pub line: Option<usize>,
+}
+
+/// A worker's Tesseract, started the first time a page isn't in the cache and kept for the pages
+/// after it.
+struct Engine {
+    language: OcrLanguage,
+    tess: Option<LepTess>,
+}
+
+impl Engine {
+    fn tsv(&mut self, path: &Path) -> Result<String, OcrError> {
+        if self.tess.is_none() {
+            self.tess = Some(LepTess::new(None, self.language.code())?);
+        }
+        let tess = self.tess.as_mut().expect("Tesseract was just started");
+        tess.set_image(path)?;
+        // Photographed pages rarely record a resolution, and Tesseract's guess of 70 dpi is far too low.
+        tess.set_fallback_source_resolution(300);
+        Ok(tess.get_tsv_text(0)?)
+    }
+}
+
+/// OCRs a page image into the entries of its vocabulary table, or when it has none, the runs
+/// of Chinese characters on each of its lines. Pages OCRed before with the same settings are
+/// read from the cache.
+fn recognize(engine: &mut Engine, path: &Path, options: &OcrOptions, progress: &ProgressBar) -> Result<Vec<OcrWord>, OcrError> {
+    let key = OcrCache::key(&fs::read(path)?, options.language);
+    let tsv = match options.cache.get(&key)? {
+        Some(tsv) => tsv,
+        None => {
+            let tsv = engine.tsv(path)?;
+            // The page was read all the same, so a cache that can't be written only costs time later.
+            if let Err(e) = options.cache.put(&key, &tsv) {
+                progress.suspend(|| eprintln!("Failed to cache the OCR of {}: {}", path.display(), e));
+            }
+            tsv
+        },
+    };
+    let boxes = word_boxes(&tsv);
+    Ok(vocab_table::entries(rows(&boxes)).unwrap_or_else(|| han_runs(&boxes)))
+}
+
+/// What OCRing one page gave.
+pub type PageResult = Result<Vec<OcrWord>, OcrError>;
+
+/// How many pages to OCR at once. Tesseract runs up to 4 OpenMP threads of its own per page
+/// unless `OMP_THREAD_LIMIT` lowers that, and OpenMP reads it as the library loads, so the pool
+/// is sized around it rather than setting it here.
+fn worker_count(page_count: usize) -> usize {
+    let cores = std::thread::available_parallelism().map(|cores| cores.get()).unwrap_or(1);
+    let threads_per_engine = std::env::var("OMP_THREAD_LIMIT")
+        .ok()
+        .and_then(|limit| limit.parse::<usize>().ok())
+        .filter(|limit| *limit > 0)
+        .unwrap_or(4)
+        .min(cores);
+    (cores / threads_per_engine).max(1).min(page_count)
+}
+
+/// OCRs the pages on a pool of blocking workers, each with its own Tesseract, showing how far
+/// along it is. `render` gives the image of a page, rendering it first if need be. Each page's
+/// words or error come back in the order of `pages`, so a bad page can be skipped.
+pub async fn recognize_pages<P, R>(pages: Vec<P>, options: &OcrOptions, render: R) -> Vec<PageResult>
+where
+    P: Send + Sync + 'static,
+    R: Fn(&P) -> Result<PathBuf, OcrError> + Send + Sync + 'static,
+{
+    let page_count = pages.len();
+    if page_count == 0 {
+        return Vec::new();
+    }
+    let progress = ProgressBar::new(page_count as u64).with_style(
+        ProgressStyle::with_template("OCR {bar:40} {pos}/{len} pages, {eta} left").expect("Progress template is valid"),
+    );
+    let pages = Arc::new(pages);
+    let render = Arc::new(render);
+    let next_page = Arc::new(AtomicUsize::new(0));
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<(usize, PageResult)>();
+
+    for _ in 0..worker_count(page_count) {
+        let (pages, render, next_page, sender, options, progress) =
+            (pages.clone(), render.clone(), next_page.clone(), sender.clone(), options.clone(), progress.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut engine = Engine { language: options.language, tess: None };
+            loop {
+                let index = next_page.fetch_add(1, Ordering::Relaxed);
+                let Some(page) = pages.get(index) else { break };
+                let result = panic::catch_unwind(AssertUnwindSafe(|| render(page).and_then(|image| recognize(&mut engine, &image, &options, &progress))))
+                    .unwrap_or_else(|_| {
+                        // Start Tesseract afresh rather than trusting it after a panic.
+                        engine = Engine { language: options.language, tess: None };
+                        Err(OcrError("OCR failed unexpectedly on this page".to_string()))
+                    });
+                if sender.send((index, result)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(sender);
+
+    let mut results: Vec<Option<PageResult>> = (0..page_count).map(|_| None).collect();
+    while let Some((index, result)) = receiver.recv().await {
+        results[index] = Some(result);
+        progress.inc(1);
+    }
+    progress.finish_and_clear();
+    results.into_iter().map(|result| result.unwrap_or_else(|| Err(OcrError("The OCR worker stopped unexpectedly".to_string())))).collect()
+}
+
+/// A word Tesseract found, with the block, paragraph and line it put it in.
+struct WordBox {
+    line: (u32, u32, u32),
+    left: i32,
+    top: i32,
+    height: i32,
+    confidence: f32,
+    text: String,
+}
+
+/// Reads Tesseract's TSV output, one row per word: level, page, block, paragraph, line, word,
+/// left, top, width, height, confidence and text.
+fn word_boxes(tsv: &str) -> Vec<WordBox> {
+    tsv.lines()
+        .filter_map(|row| {
+            let columns: Vec<&str> = row.split('\t').collect();
+            if columns.len() < 12 || columns[0] != "5" || columns[11].trim().is_empty() {
+                return None;
+            }
+            let number = |index: usize| columns[index].trim().parse::<i32>().unwrap_or_default();
+            Some(WordBox {
+                line: (number(2) as u32, number(3) as u32, number(4) as u32),
+                left: number(6),
+                top: number(7),
+                height: number(9),
+                confidence: columns[10].trim().parse::<f32>().unwrap_or(0.0),
+                text: columns[11].trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Groups boxes into the rows they sit on across the whole page, left to right. Tesseract's
+/// own lines stop at gaps, so the columns of a table come out as separate blocks.
+fn rows(boxes: &[WordBox]) -> Vec<Vec<Token>> {
+    let mut sorted: Vec<&WordBox> = boxes.iter().collect();
+    sorted.sort_by_key(|word| word.top + word.height / 2);
+    let mut rows: Vec<(i32, Vec<&WordBox>)> = Vec::new();
+    for word in sorted {
+        let middle = word.top + word.height / 2;
+        match rows.last_mut() {
+            Some((bottom, row)) if middle < *bottom => {
+                row.push(word);
+                *bottom = (*bottom).max(word.top + word.height);
+            },
+            _ => rows.push((word.top + word.height, vec![word])),
+        }
+    }
+    rows.into_iter()
+        .map(|(_, mut row)| {
+            row.sort_by_key(|word| word.left);
+            row.into_iter().map(|word| Token { text: word.text.clone(), confidence: word.confidence, left: Some(word.left) }).collect()
+        })
+        .collect()
+}
+
+/// The runs of Chinese characters on each line. Chinese comes out a character or two per word,
+/// so the Han characters on a line are joined until something else comes between them.
+fn han_runs(boxes: &[WordBox]) -> Vec<OcrWord> {
+    let han = Regex::new(r"^\p{Han}$").expect("Regex failed to initialize");
+    let mut runs = Vec::new();
+    let mut line = None;
+    let mut line_number = 0;
+    let mut current: Option<OcrWord> = None;
+    for word in boxes {
+        if line != Some(word.line) {
+            runs.extend(current.take());
+            line = Some(word.line);
+            line_number += 1;
+        }
+        for character in word.text.chars() {
+            if han.is_match(character.encode_utf8(&mut [0; 4])) {
+                let run = current.get_or_insert(OcrWord { confidence: word.confidence, line: Some(line_number), ..OcrWord::default() });
+                run.text.push(character);
+                run.confidence = run.confidence.min(word.confidence);
+            } else {
+                runs.extend(current.take());
+            }
+        }
+    }
+    runs.extend(current);
+    runs
+}
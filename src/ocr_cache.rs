@@ -0,0 +1,70 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use sha2::{Digest, Sha256};
+
+use crate::ocr::{OcrError, OcrLanguage};
+
+/// Bump when the way pages are OCRed changes, so pages are read again rather than coming from
+/// the cache as the old settings read them.
+const CACHE_VERSION: u32 = 1;
+
+/// Tesseract's output for page images it has read, kept next to the DB so that unchanged pages
+/// aren't OCRed again. Entries are keyed by the SHA-256 of the image and the OCR settings.
+pub struct OcrCache {
+    directory: PathBuf,
+    /// OCR every page again, replacing what is cached.
+    reocr: bool,
+    hits: AtomicUsize,
+    /// Numbers each write's temp file, so workers caching the same page at once don't clash.
+    writes: AtomicUsize,
+}
+
+impl OcrCache {
+    pub fn new(db_location: &str, reocr: bool) -> Self {
+        OcrCache { directory: PathBuf::from(format!("{}.ocr-cache", db_location)), reocr, hits: AtomicUsize::new(0), writes: AtomicUsize::new(0) }
+    }
+
+    pub fn key(image: &[u8], language: OcrLanguage) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(image);
+        hasher.update(format!("\0{}\0{}", language.code(), CACHE_VERSION).as_bytes());
+        hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.directory.join(format!("{}.tsv", key))
+    }
+
+    /// The cached output for `key`, unless re-OCRing.
+    pub fn get(&self, key: &str) -> Result<Option<String>, OcrError> {
+        if self.reocr {
+            return Ok(None);
+        }
+        match fs::read_to_string(self.path(key)) {
+            Ok(tsv) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Ok(Some(tsv))
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn put(&self, key: &str, tsv: &str) -> Result<(), OcrError> {
+        fs::create_dir_all(&self.directory)?;
+        let path = self.path(key);
+        let write = self.writes.fetch_add(1, Ordering::Relaxed);
+        let temp_path = self.directory.join(format!("{}.{}-{}.temp", key, std::process::id(), write));
+        fs::write(&temp_path, tsv)?;
+        fs::rename(temp_path, path)?;
+        Ok(())
+    }
+
+    /// How many pages came from the cache so far.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,177 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use lopdf::content::Content;
+use lopdf::{Document, Encoding, Object};
+
+#[derive(Debug)]
+pub struct PdfError(String);
+
+impl std::fmt::Display for PdfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for PdfError {}
+
+impl From<std::io::Error> for PdfError {
+    fn from(e: std::io::Error) -> Self {
+        PdfError(format!("IO error: {}", e))
+    }
+}
+
+impl From<lopdf::Error> for PdfError {
+    fn from(e: lopdf::Error) -> Self {
+        PdfError(format!("PDF error: {}", e))
+    }
+}
+
+/// A set of 1-based pages such as `1-3,5,8-`, where an open end runs to the last page.
+#[derive(Clone, Debug)]
+pub struct PageRanges(Vec<(u32, Option<u32>)>);
+
+impl FromStr for PageRanges {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut ranges = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let page = |number: &str| number.trim().parse::<u32>().ok().filter(|page| *page > 0);
+            let range = match part.split_once('-') {
+                Some((first, "")) => page(first).map(|first| (first, None)),
+                Some((first, last)) => page(first).zip(page(last)).filter(|(first, last)| first <= last).map(|(first, last)| (first, Some(last))),
+                None => page(part).map(|page| (page, Some(page))),
+            };
+            ranges.push(range.ok_or(format!("Invalid page range {}, expected pages like 1-3,5,8-", part))?);
+        }
+        if ranges.is_empty() {
+            return Err("No pages given".to_string());
+        }
+        Ok(PageRanges(ranges))
+    }
+}
+
+impl PageRanges {
+    pub fn contains(&self, page: u32) -> bool {
+        self.0.iter().any(|(first, last)| page >= *first && last.is_none_or(|last| page <= last))
+    }
+}
+
+/// `--page-category 1-3=Lesson 1`: the category for the words on those pages.
+#[derive(Clone, Debug)]
+pub struct PageCategory {
+    pub pages: PageRanges,
+    pub category: String,
+}
+
+impl FromStr for PageCategory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (pages, category) = s.split_once('=').ok_or(format!("Expected <pages>=<category>, like 1-3=Lesson 1, got {}", s))?;
+        if category.trim().is_empty() {
+            return Err(format!("No category for pages {}", pages));
+        }
+        Ok(PageCategory { pages: pages.parse()?, category: category.trim().to_string() })
+    }
+}
+
+pub struct Pdf {
+    path: PathBuf,
+    document: Document,
+}
+
+impl Pdf {
+    pub fn open(path: &str) -> Result<Self, PdfError> {
+        let document = Document::load(path).map_err(|e| PdfError(format!("Failed to open {}: {}", path, e)))?;
+        Ok(Pdf { path: PathBuf::from(path), document })
+    }
+
+    pub fn page_count(&self) -> u32 {
+        self.document.get_pages().len() as u32
+    }
+
+    /// The text of a page's text layer. Empty for scans and pages the text can't be decoded from.
+    pub fn text(&self, page: u32) -> String {
+        self.page_text(page).unwrap_or_default()
+    }
+
+    /// Like lopdf's `extract_text`, but every move to a new position starts a new line, so the
+    /// words of a vocabulary list laid out with `Td` don't run together.
+    fn page_text(&self, page: u32) -> Result<String, lopdf::Error> {
+        let page_id = *self.document.get_pages().get(&page).ok_or(lopdf::Error::PageNumberNotFound(page))?;
+        let encodings: Vec<(Vec<u8>, Encoding)> = self
+            .document
+            .get_page_fonts(page_id)?
+            .into_iter()
+            .filter_map(|(name, font)| font.get_font_encoding(&self.document).ok().map(|encoding| (name, encoding)))
+            .collect();
+        let content = Content::decode(&self.document.get_page_content(page_id))?;
+
+        let mut text = String::new();
+        let mut encoding = None;
+        let decode = |bytes: &[u8], encoding: Option<&Encoding>, text: &mut String| {
+            if let Some(decoded) = encoding.and_then(|encoding| Document::decode_text(encoding, bytes).ok()) {
+                text.push_str(&decoded);
+            }
+        };
+        for operation in &content.operations {
+            match operation.operator.as_str() {
+                "Tf" => {
+                    let font = operation.operands.first().and_then(|operand| operand.as_name().ok());
+                    encoding = font.and_then(|font| encodings.iter().position(|(name, _)| name == font));
+                },
+                "Td" | "TD" | "Tm" | "T*" | "ET" if !text.is_empty() && !text.ends_with('\n') => text.push('\n'),
+                "Tj" | "'" | "\"" => {
+                    if let Some(Object::String(bytes, _)) = operation.operands.last() {
+                        decode(bytes, encoding.map(|index| &encodings[index].1), &mut text);
+                    }
+                },
+                "TJ" => {
+                    for element in operation.operands.iter().filter_map(|operand| operand.as_array().ok()).flatten() {
+                        if let Object::String(bytes, _) = element {
+                            decode(bytes, encoding.map(|index| &encodings[index].1), &mut text);
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+        Ok(text)
+    }
+
+    /// Renders a page to an image in `directory` for OCR, with `pdftoppm` from poppler when it is
+    /// installed. Otherwise a scanned page's embedded JPEG is used as it is.
+    pub fn rasterize(&self, page: u32, directory: &Path) -> Result<PathBuf, PdfError> {
+        let prefix = directory.join(format!("page-{}", page));
+        let rendered = Command::new("pdftoppm")
+            .args(["-r", "300", "-gray", "-png", "-singlefile", "-f", &page.to_string(), "-l", &page.to_string()])
+            .arg(&self.path)
+            .arg(&prefix)
+            .output();
+        match rendered {
+            Ok(output) if output.status.success() => return Ok(prefix.with_extension("png")),
+            Ok(output) => return Err(PdfError(format!("pdftoppm failed: {}", String::from_utf8_lossy(&output.stderr).trim()))),
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(e.into()),
+        }
+
+        let page_id = *self.document.get_pages().get(&page).ok_or(PdfError(format!("No page {}", page)))?;
+        let images = self.document.get_page_images(page_id)?;
+        let scan = images
+            .iter()
+            .filter(|image| image.filters.as_ref().is_some_and(|filters| filters.iter().any(|filter| filter == "DCTDecode")))
+            .max_by_key(|image| image.width * image.height)
+            .ok_or(PdfError(format!(
+                "Page {} has no text layer or scanned JPEG, install pdftoppm (poppler-utils) to OCR it",
+                page
+            )))?;
+        let path = prefix.with_extension("jpg");
+        fs::write(&path, scan.content)?;
+        Ok(path)
+    }
+}
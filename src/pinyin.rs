@@ -0,0 +1,497 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Every standard Hanyu Pinyin syllable without its tone, plus the interjections and the
+/// erhua `r` that CC-CEDICT writes as syllables of their own.
+const SYLLABLES: &str = "
+a ai an ang ao e ei en eng er o ou ê m n ng hm hng r
+yi ya yo yao ye you yan yin yang ying yong wu wa wo wai wei wan wen wang weng yu yue yuan yun
+ba bai ban bang bao bei ben beng bi bian biao bie bin bing bo bu
+pa pai pan pang pao pei pen peng pi pian piao pie pin ping po pou pu
+ma mai man mang mao me mei men meng mi mian miao mie min ming miu mo mou mu
+fa fan fang fei fen feng fo fou fu
+da dai dan dang dao de dei den deng di dia dian diao die ding diu dong dou du duan dui dun duo
+ta tai tan tang tao te tei teng ti tian tiao tie ting tong tou tu tuan tui tun tuo
+na nai nan nang nao ne nei nen neng ni nian niang niao nie nin ning niu nong nou nu nuan nuo nü nüe
+la lai lan lang lao le lei leng li lia lian liang liao lie lin ling liu lo long lou lu luan lun luo lü lüe
+ga gai gan gang gao ge gei gen geng gong gou gu gua guai guan guang gui gun guo
+ka kai kan kang kao ke kei ken keng kong kou ku kua kuai kuan kuang kui kun kuo
+ha hai han hang hao he hei hen heng hong hou hu hua huai huan huang hui hun huo
+ji jia jian jiang jiao jie jin jing jiong jiu ju juan jue jun
+qi qia qian qiang qiao qie qin qing qiong qiu qu quan que qun
+xi xia xian xiang xiao xie xin xing xiong xiu xu xuan xue xun
+zha zhai zhan zhang zhao zhe zhei zhen zheng zhi zhong zhou zhu zhua zhuai zhuan zhuang zhui zhun zhuo
+cha chai chan chang chao che chen cheng chi chong chou chu chua chuai chuan chuang chui chun chuo
+sha shai shan shang shao she shei shen sheng shi shou shu shua shuai shuan shuang shui shun shuo
+ran rang rao re ren reng ri rong rou ru rua ruan rui run ruo
+za zai zan zang zao ze zei zen zeng zi zong zou zu zuan zui zun zuo
+ca cai can cang cao ce cen ceng ci cong cou cu cuan cui cun cuo
+sa sai san sang sao se sen seng si song sou su suan sui sun suo
+";
+
+const INITIALS: &[(&str, &str)] = &[
+    ("zh", "ㄓ"), ("ch", "ㄔ"), ("sh", "ㄕ"),
+    ("b", "ㄅ"), ("p", "ㄆ"), ("m", "ㄇ"), ("f", "ㄈ"), ("d", "ㄉ"), ("t", "ㄊ"), ("n", "ㄋ"), ("l", "ㄌ"),
+    ("g", "ㄍ"), ("k", "ㄎ"), ("h", "ㄏ"), ("j", "ㄐ"), ("q", "ㄑ"), ("x", "ㄒ"),
+    ("r", "ㄖ"), ("z", "ㄗ"), ("c", "ㄘ"), ("s", "ㄙ"),
+];
+
+/// Finals as spelled after an initial, with `iu`, `ui` and `un` written out in full.
+const FINALS: &[(&str, &str)] = &[
+    ("", ""), ("a", "ㄚ"), ("o", "ㄛ"), ("e", "ㄜ"), ("ê", "ㄝ"), ("ai", "ㄞ"), ("ei", "ㄟ"), ("ao", "ㄠ"),
+    ("ou", "ㄡ"), ("an", "ㄢ"), ("en", "ㄣ"), ("ang", "ㄤ"), ("eng", "ㄥ"), ("ong", "ㄨㄥ"),
+    ("i", "ㄧ"), ("ia", "ㄧㄚ"), ("io", "ㄧㄛ"), ("ie", "ㄧㄝ"), ("iao", "ㄧㄠ"), ("iou", "ㄧㄡ"),
+    ("ian", "ㄧㄢ"), ("in", "ㄧㄣ"), ("iang", "ㄧㄤ"), ("ing", "ㄧㄥ"), ("iong", "ㄩㄥ"),
+    ("u", "ㄨ"), ("ua", "ㄨㄚ"), ("uo", "ㄨㄛ"), ("uai", "ㄨㄞ"), ("uei", "ㄨㄟ"), ("uan", "ㄨㄢ"),
+    ("uen", "ㄨㄣ"), ("uang", "ㄨㄤ"), ("ueng", "ㄨㄥ"),
+    ("ü", "ㄩ"), ("üe", "ㄩㄝ"), ("üan", "ㄩㄢ"), ("ün", "ㄩㄣ"),
+];
+
+const TONE_MARKS: &[(char, [char; 4])] = &[
+    ('a', ['ā', 'á', 'ǎ', 'à']),
+    ('e', ['ē', 'é', 'ě', 'è']),
+    ('i', ['ī', 'í', 'ǐ', 'ì']),
+    ('o', ['ō', 'ó', 'ǒ', 'ò']),
+    ('u', ['ū', 'ú', 'ǔ', 'ù']),
+    ('ü', ['ǖ', 'ǘ', 'ǚ', 'ǜ']),
+];
+
+const ZHUYIN_TONES: [char; 4] = ['ˉ', 'ˊ', 'ˇ', 'ˋ'];
+const ZHUYIN_NEUTRAL: char = '˙';
+
+const NEUTRAL_TONE: u8 = 5;
+
+#[derive(Debug)]
+pub struct PinyinError(String);
+
+impl std::fmt::Display for PinyinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for PinyinError {}
+
+/// How pinyin is written out for display and export.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum PinyinStyle {
+    /// Tone marks, `nǐhǎo`
+    Marks,
+    /// Tone numbers, `ni3hao3`, the form cards are stored in
+    Numbers,
+    /// Bopomofo, `ㄋㄧˇ ㄏㄠˇ`
+    Zhuyin,
+}
+
+/// One syllable: its letters without a tone, with `ü` for ü/v/u:, and a tone from 1 to 5
+/// where 5 is the neutral tone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Syllable {
+    pub letters: String,
+    pub tone: u8,
+}
+
+impl Syllable {
+    pub fn numbers(&self) -> String {
+        format!("{}{}", self.letters, self.tone)
+    }
+
+    /// Marks the vowel Hanyu Pinyin puts the tone on: `a` or `e` when present, the `o` of
+    /// `ou`, otherwise the last vowel. Syllables without a vowel, like `ng`, stay unmarked.
+    pub fn marks(&self) -> String {
+        if self.tone == NEUTRAL_TONE {
+            return self.letters.clone();
+        }
+        let chars: Vec<char> = self.letters.chars().collect();
+        let position = chars.iter().position(|c| *c == 'a' || *c == 'e')
+            .or_else(|| chars.windows(2).position(|pair| pair == ['o', 'u']))
+            .or_else(|| chars.iter().rposition(|c| is_vowel(*c)));
+        let Some(position) = position else {
+            return self.letters.clone();
+        };
+        chars
+            .iter()
+            .enumerate()
+            .map(|(i, c)| if i == position { mark(*c, self.tone) } else { *c })
+            .collect()
+    }
+
+    pub fn zhuyin(&self) -> String {
+        let symbols = syllable_table().get(self.letters.as_str()).cloned().unwrap_or_default();
+        match self.tone {
+            1 => symbols,
+            NEUTRAL_TONE => format!("{}{}", ZHUYIN_NEUTRAL, symbols),
+            tone => format!("{}{}", symbols, ZHUYIN_TONES[tone as usize - 1]),
+        }
+    }
+}
+
+fn is_vowel(c: char) -> bool {
+    matches!(c, 'a' | 'e' | 'ê' | 'i' | 'o' | 'u' | 'ü')
+}
+
+fn mark(vowel: char, tone: u8) -> char {
+    TONE_MARKS
+        .iter()
+        .find(|(base, _)| *base == vowel)
+        .map(|(_, marked)| marked[tone as usize - 1])
+        .unwrap_or(vowel)
+}
+
+/// Every valid syllable mapped to its zhuyin.
+fn syllable_table() -> &'static HashMap<&'static str, String> {
+    static TABLE: OnceLock<HashMap<&'static str, String>> = OnceLock::new();
+    TABLE.get_or_init(|| SYLLABLES.split_whitespace().map(|syllable| (syllable, to_zhuyin(syllable))).collect())
+}
+
+/// Zhuyin back to pinyin. `er` is listed before the erhua `r`, so ㄦ reads as `er`.
+fn zhuyin_table() -> &'static HashMap<String, &'static str> {
+    static TABLE: OnceLock<HashMap<String, &'static str>> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = HashMap::new();
+        for syllable in SYLLABLES.split_whitespace() {
+            table.entry(to_zhuyin(syllable)).or_insert(syllable);
+        }
+        table
+    })
+}
+
+fn to_zhuyin(syllable: &str) -> String {
+    match syllable {
+        "er" | "r" => return "ㄦ".to_string(),
+        "m" => return "ㄇ".to_string(),
+        "n" => return "ㄋ".to_string(),
+        "ng" => return "ㄫ".to_string(),
+        "hm" => return "ㄏㄇ".to_string(),
+        "hng" => return "ㄏㄫ".to_string(),
+        _ => {},
+    }
+    let (initial, symbol, rest) = match INITIALS.iter().find(|(initial, _)| syllable.starts_with(initial)) {
+        Some((initial, symbol)) => (*initial, *symbol, &syllable[initial.len()..]),
+        None => ("", "", syllable),
+    };
+    // Undo the spelling rules to get back to the written-out final.
+    let final_ = if let Some(rest) = rest.strip_prefix('y').filter(|_| initial.is_empty()) {
+        if let Some(rest) = rest.strip_prefix('u') {
+            format!("ü{}", rest)
+        } else if rest.starts_with('i') {
+            rest.to_string()
+        } else {
+            format!("i{}", rest)
+        }
+    } else if let Some(rest) = rest.strip_prefix('w').filter(|_| initial.is_empty()) {
+        if rest.starts_with('u') { rest.to_string() } else { format!("u{}", rest) }
+    } else if matches!(initial, "j" | "q" | "x") && rest.starts_with('u') {
+        format!("ü{}", &rest[1..])
+    } else if rest == "i" && matches!(initial, "zh" | "ch" | "sh" | "r" | "z" | "c" | "s") {
+        String::new()
+    } else {
+        match rest {
+            "iu" => "iou".to_string(),
+            "ui" => "uei".to_string(),
+            "un" => "uen".to_string(),
+            _ => rest.to_string(),
+        }
+    };
+    let final_symbols = FINALS.iter().find(|(f, _)| *f == final_).map(|(_, symbols)| *symbols).unwrap_or_default();
+    format!("{}{}", symbol, final_symbols)
+}
+
+/// Parses pinyin written with tone marks (`nǐ hǎo`, `Xī'ān`), tone numbers (`ni3hao3`,
+/// `lv4`, `lu:4`) or zhuyin (`ㄋㄧˇ ㄏㄠˇ`). Syllables without a tone are neutral.
+pub fn parse(input: &str) -> Result<Vec<Syllable>, PinyinError> {
+    if input.chars().any(is_zhuyin) {
+        return parse_zhuyin(input);
+    }
+    let mut syllables = Vec::new();
+    // A run of letters between separators, each letter with the tone its mark carried.
+    let mut run: Vec<(char, Option<u8>)> = Vec::new();
+    let lowercase = input.to_lowercase();
+    for c in lowercase.chars() {
+        if let Some((base, tone)) = unmark(c) {
+            run.push((base, tone));
+        } else if let Some(tone) = combining_tone(c) {
+            match run.last_mut() {
+                Some(last) if tone == 0 => last.0 = if last.0 == 'u' { 'ü' } else { last.0 },
+                Some(last) => last.1 = Some(tone),
+                None => return Err(PinyinError(format!("Stray tone mark in {}", input))),
+            }
+        } else if c == 'v' {
+            run.push(('ü', None));
+        } else if c == ':' && run.last().map(|last| last.0 == 'u').unwrap_or(false) {
+            if let Some(last) = run.last_mut() {
+                last.0 = 'ü';
+            }
+        } else if c.is_ascii_alphabetic() || c == 'ü' || c == 'ê' {
+            run.push((c, None));
+        } else if let Some(digit) = c.to_digit(10) {
+            let tone = match digit {
+                1..=4 => digit as u8,
+                0 | 5 => NEUTRAL_TONE,
+                _ => return Err(PinyinError(format!("Invalid tone number {} in {}", digit, input))),
+            };
+            syllables.extend(split_run(&run, Some(tone), input)?);
+            run.clear();
+        } else if c.is_whitespace() || matches!(c, '\'' | '’' | '-' | ',' | '·') {
+            syllables.extend(split_run(&run, None, input)?);
+            run.clear();
+        } else {
+            return Err(PinyinError(format!("Unexpected {} in pinyin {}", c, input)));
+        }
+    }
+    syllables.extend(split_run(&run, None, input)?);
+    Ok(syllables)
+}
+
+/// Splits `len` letters or symbols into syllables, preferring the longest syllable that still
+/// lets the rest split, so `xianzai` is `xian zai` rather than `xi an zai`. Returns where each
+/// syllable ends, or where the part that doesn't split starts.
+fn segment(len: usize, longest: usize, is_syllable: impl Fn(usize, usize) -> bool) -> Result<Vec<usize>, usize> {
+    // next[i] is the end of the syllable starting at i on a route that reaches the end.
+    let mut next: Vec<Option<usize>> = vec![None; len + 1];
+    for start in (0..len).rev() {
+        next[start] = (start + 1..=len.min(start + longest))
+            .rev()
+            .find(|end| (*end == len || next[*end].is_some()) && is_syllable(start, *end));
+    }
+    let mut ends = Vec::new();
+    let mut start = 0;
+    while start < len {
+        let end = next[start].ok_or(start)?;
+        ends.push(end);
+        start = end;
+    }
+    Ok(ends)
+}
+
+/// Splits a run of letters into syllables. A tone number after the run goes to its last
+/// syllable, or when that is an erhua `r`, to the syllable before, so `nar3` and `nǎr` are
+/// both `na3r5`.
+fn split_run(run: &[(char, Option<u8>)], trailing_tone: Option<u8>, input: &str) -> Result<Vec<Syllable>, PinyinError> {
+    let letters: Vec<char> = run.iter().map(|(c, _)| *c).collect();
+    let ends = segment(letters.len(), 6, |start, end| is_syllable(&letters[start..end])).map_err(|start| {
+        PinyinError(format!("{} is not valid pinyin in {}", letters[start..].iter().collect::<String>(), input))
+    })?;
+    let mut syllables = Vec::new();
+    let mut marks = Vec::new();
+    let mut start = 0;
+    for end in ends {
+        let mark = run[start..end].iter().find_map(|(_, tone)| *tone);
+        let tone = match (mark, end == letters.len()) {
+            (Some(tone), _) => tone,
+            (None, true) => trailing_tone.unwrap_or(NEUTRAL_TONE),
+            (None, false) => NEUTRAL_TONE,
+        };
+        syllables.push(Syllable { letters: letters[start..end].iter().collect(), tone });
+        marks.push(mark);
+        start = end;
+    }
+    let count = syllables.len();
+    if count >= 2 && syllables[count - 1].letters == "r" {
+        if marks[count - 2].is_none() {
+            syllables[count - 2].tone = syllables[count - 1].tone;
+        }
+        syllables[count - 1].tone = NEUTRAL_TONE;
+    }
+    Ok(syllables)
+}
+
+fn is_syllable(letters: &[char]) -> bool {
+    syllable_table().contains_key(letters.iter().collect::<String>().as_str())
+}
+
+fn unmark(c: char) -> Option<(char, Option<u8>)> {
+    TONE_MARKS.iter().find_map(|(base, marked)| {
+        marked.iter().position(|m| *m == c).map(|i| (*base, Some(i as u8 + 1)))
+    })
+}
+
+/// Decomposed tone marks. Returns 0 for a combining diaeresis, which turns u into ü.
+fn combining_tone(c: char) -> Option<u8> {
+    match c {
+        '\u{0304}' => Some(1),
+        '\u{0301}' => Some(2),
+        '\u{030C}' => Some(3),
+        '\u{0300}' => Some(4),
+        '\u{0308}' => Some(0),
+        _ => None,
+    }
+}
+
+fn is_zhuyin(c: char) -> bool {
+    ('\u{3105}'..='\u{312F}').contains(&c)
+}
+
+/// Parses zhuyin, where a tone mark follows its syllable and ˙ goes before a neutral one.
+/// Syllables between marks are split like pinyin, so `ㄇㄚㄇㄚ` is `ma1ma1`. A ㄦ written onto the
+/// syllable before it, as in `ㄋㄚˇㄦ`, is erhua.
+fn parse_zhuyin(input: &str) -> Result<Vec<Syllable>, PinyinError> {
+    let mut syllables = Vec::new();
+    for word in input.split_whitespace() {
+        let mut run: Vec<char> = Vec::new();
+        let mut neutral = false;
+        let mut in_word = false;
+        for c in word.chars() {
+            let tone = if c == ZHUYIN_NEUTRAL {
+                None
+            } else if let Some(i) = ZHUYIN_TONES.iter().position(|tone| *tone == c) {
+                Some(i as u8 + 1)
+            } else if is_zhuyin(c) {
+                run.push(c);
+                continue;
+            } else {
+                return Err(PinyinError(format!("Unexpected {} in zhuyin {}", c, input)));
+            };
+            let split = split_zhuyin_run(&run, neutral, tone, in_word, input)?;
+            in_word |= !split.is_empty();
+            syllables.extend(split);
+            run.clear();
+            neutral = c == ZHUYIN_NEUTRAL;
+        }
+        syllables.extend(split_zhuyin_run(&run, neutral, None, in_word, input)?);
+    }
+    Ok(syllables)
+}
+
+/// Splits a run of zhuyin symbols into first tone syllables, the first neutral when a ˙ came
+/// before the run and the last taking the tone mark after it. A trailing ㄦ is erhua when it
+/// follows another syllable of the word without a tone mark of its own.
+fn split_zhuyin_run(run: &[char], neutral: bool, tone: Option<u8>, in_word: bool, input: &str) -> Result<Vec<Syllable>, PinyinError> {
+    let table = zhuyin_table();
+    let ends = segment(run.len(), 4, |start, end| table.contains_key(&run[start..end].iter().collect::<String>()))
+        .map_err(|start| PinyinError(format!("{} is not valid zhuyin in {}", run[start..].iter().collect::<String>(), input)))?;
+    let mut syllables = Vec::new();
+    let mut start = 0;
+    for end in ends {
+        let symbols: String = run[start..end].iter().collect();
+        let tone = if start == 0 && neutral { NEUTRAL_TONE } else { 1 };
+        syllables.push(Syllable { letters: table[&symbols].to_string(), tone });
+        start = end;
+    }
+    let count = syllables.len();
+    let erhua = count > 0 && syllables[count - 1].letters == "er" && (count >= 2 || (in_word && tone.is_none()));
+    if erhua {
+        syllables[count - 1] = Syllable { letters: "r".to_string(), tone: NEUTRAL_TONE };
+        if let (Some(tone), true) = (tone, count >= 2) {
+            syllables[count - 2].tone = tone;
+        }
+    } else if let (Some(tone), Some(last)) = (tone, syllables.last_mut()) {
+        last.tone = tone;
+    }
+    Ok(syllables)
+}
+
+pub fn format(syllables: &[Syllable], style: PinyinStyle) -> String {
+    match style {
+        PinyinStyle::Numbers => syllables.iter().map(Syllable::numbers).collect(),
+        PinyinStyle::Zhuyin => {
+            // Erhua is written onto the syllable before it, 哪儿 as ㄋㄚˇㄦ.
+            let mut words: Vec<String> = Vec::new();
+            for syllable in syllables {
+                match words.last_mut() {
+                    Some(last) if syllable.letters == "r" => last.push('ㄦ'),
+                    _ => words.push(syllable.zhuyin()),
+                }
+            }
+            words.join(" ")
+        },
+        PinyinStyle::Marks => {
+            // An apostrophe keeps a syllable starting with a, e or o from running into the
+            // one before it, as in xī'ān.
+            let mut marked = String::new();
+            for (i, syllable) in syllables.iter().enumerate() {
+                if i > 0 && syllable.letters.starts_with(['a', 'e', 'o']) {
+                    marked.push('\'');
+                }
+                marked.push_str(&syllable.marks());
+            }
+            marked
+        },
+    }
+}
+
+/// The form cards store their reading in: lowercase tone numbers with ü, syllables joined,
+/// like `ni3hao3` or `lü4`.
+pub fn normalize(input: &str) -> Result<String, PinyinError> {
+    Ok(format(&parse(input)?, PinyinStyle::Numbers))
+}
+
+/// `normalize`, keeping readings it can't parse as they are, minus whitespace.
+pub fn canonical(input: &str) -> String {
+    normalize(input).unwrap_or_else(|_| input.split_whitespace().collect::<String>().to_lowercase())
+}
+
+/// Writes a stored reading in `style`, leaving readings that don't parse untouched.
+pub fn display(pinyin: &str, style: PinyinStyle) -> String {
+    match parse(pinyin) {
+        Ok(syllables) => format(&syllables, style),
+        Err(_) => pinyin.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zhuyin(input: &str) -> String {
+        format(&parse(input).unwrap(), PinyinStyle::Zhuyin)
+    }
+
+    #[test]
+    fn erhua_takes_no_tone() {
+        assert_eq!(normalize("nar3").unwrap(), "na3r5");
+        assert_eq!(normalize("nǎr").unwrap(), "na3r5");
+        assert_eq!(normalize("na3 r5").unwrap(), "na3r5");
+        assert_eq!(normalize("yi4dian3r").unwrap(), "yi4dian3r5");
+        assert_eq!(normalize("yīdiǎnr").unwrap(), "yi1dian3r5");
+        assert_eq!(normalize("er2").unwrap(), "er2");
+    }
+
+    #[test]
+    fn u_umlaut_spellings() {
+        assert_eq!(normalize("lü4").unwrap(), "lü4");
+        assert_eq!(normalize("lv4").unwrap(), "lü4");
+        assert_eq!(normalize("lu:4").unwrap(), "lü4");
+        assert_eq!(normalize("lǜ").unwrap(), "lü4");
+        assert_eq!(normalize("nu\u{0308}\u{030C}").unwrap(), "nü3");
+        assert_eq!(format(&parse("lü4").unwrap(), PinyinStyle::Marks), "lǜ");
+    }
+
+    #[test]
+    fn neutral_tones() {
+        assert_eq!(normalize("ma").unwrap(), "ma5");
+        assert_eq!(normalize("ma0").unwrap(), "ma5");
+        assert_eq!(normalize("māma").unwrap(), "ma1ma5");
+        assert_eq!(normalize("ma1 ma").unwrap(), "ma1ma5");
+        assert_eq!(format(&parse("ma1ma5").unwrap(), PinyinStyle::Marks), "māma");
+    }
+
+    #[test]
+    fn splits_runs_by_longest_syllable() {
+        assert_eq!(normalize("xianzai4").unwrap(), "xian5zai4");
+        assert_eq!(normalize("Xī'ān").unwrap(), "xi1an1");
+        assert!(parse("xyz3").is_err());
+    }
+
+    #[test]
+    fn zhuyin_round_trips() {
+        for numbers in ["ni3hao3", "ma1ma5", "lü4", "xi1an1", "nü3er2", "na3r5", "yi1dian3r5", "zhi1dao5", "er4"] {
+            let written = zhuyin(numbers);
+            assert_eq!(normalize(&written).unwrap(), numbers, "{} was written as {}", numbers, written);
+        }
+        assert_eq!(zhuyin("ni3hao3"), "ㄋㄧˇ ㄏㄠˇ");
+        assert_eq!(zhuyin("na3r5"), "ㄋㄚˇㄦ");
+        assert_eq!(zhuyin("ma1ma5"), "ㄇㄚ ˙ㄇㄚ");
+    }
+
+    #[test]
+    fn unspaced_zhuyin() {
+        assert_eq!(normalize("ㄇㄚㄇㄚ").unwrap(), "ma1ma1");
+        assert_eq!(normalize("ㄇㄚ˙ㄇㄚ").unwrap(), "ma1ma5");
+        assert_eq!(normalize("ㄒㄧㄢˋㄗㄞˋ").unwrap(), "xian4zai4");
+        assert_eq!(normalize("ㄓㄨㄥㄨㄣˊ").unwrap(), "zhong1wen2");
+        assert_eq!(normalize("ㄋㄚㄦˇ").unwrap(), "na3r5");
+        assert_eq!(normalize("ㄋㄩˇㄦˊ").unwrap(), "nü3er2");
+        assert!(parse("ㄅㄅ").is_err());
+    }
+}
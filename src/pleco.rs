@@ -0,0 +1,511 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Utc};
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde::{Deserialize, Serialize};
+
+use crate::db::{Card, Source};
+
+/// A malformed plecoflash file, with the 1-based line and column of the offending markup.
+#[derive(Debug)]
+pub struct PlecoError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for PlecoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for PlecoError {}
+
+/// A card's link to the Pleco dictionary entry it was added from.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DictRef {
+    pub dictid: String,
+    pub entryid: String,
+}
+
+/// A Pleco `scoreinfo` element: the score file it belongs to and its remaining attributes
+/// (score, difficulty, history, correct, incorrect, ...) as Pleco wrote them.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ScoreInfo {
+    pub scorefile: String,
+    pub fields: BTreeMap<String, String>,
+}
+
+/// The attributes of the root `plecoflash` element.
+#[derive(Debug, Default, Clone)]
+pub struct PlecoHeader {
+    pub formatversion: Option<String>,
+    pub creator: Option<String>,
+    pub generator: Option<String>,
+    pub platform: Option<String>,
+    pub created: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Pron {
+    /// Romanization system, `hypy` for Hanyu Pinyin.
+    pub system: Option<String>,
+    pub text: String,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct PlecoCard {
+    pub language: Option<String>,
+    pub created: Option<DateTime<Utc>>,
+    pub modified: Option<DateTime<Utc>>,
+    pub simplified: Option<String>,
+    pub traditional: Option<String>,
+    pub pron: Option<Pron>,
+    pub definition: Option<String>,
+    pub dictrefs: Vec<DictRef>,
+    pub categories: Vec<String>,
+    pub scores: Vec<ScoreInfo>,
+    /// Line of the `<card>` tag, for messages about this card.
+    pub line: usize,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct PlecoFile {
+    pub header: PlecoHeader,
+    /// Every category listed in the `categories` section, including empty ones.
+    pub categories: Vec<String>,
+    pub cards: Vec<PlecoCard>,
+}
+
+/// Pleco's two flashcard file formats.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum PlecoFormat {
+    /// plecoflash XML, which keeps everything a card carries
+    Xml,
+    /// Tab separated text with `//Category/Sub` lines, easy to diff and edit by hand
+    Text,
+}
+
+impl PlecoHeader {
+    pub fn describe(&self) -> String {
+        if self.creator.is_none() && self.generator.is_none() && self.platform.is_none() && self.created.is_none() {
+            return "a file without a header".to_string();
+        }
+        let mut description = self.creator.clone().unwrap_or("an unknown creator".to_string());
+        for detail in [&self.generator, &self.platform].into_iter().flatten() {
+            description.push_str(&format!(", {}", detail));
+        }
+        if let Some(created) = self.created {
+            description.push_str(&format!(", exported {}", created.format("%Y-%m-%d")));
+        }
+        if let Some(version) = &self.formatversion {
+            description.push_str(&format!(", format version {}", version));
+        }
+        description
+    }
+}
+
+impl PlecoCard {
+    pub fn headword(&self) -> &str {
+        self.simplified.as_deref().or(self.traditional.as_deref()).unwrap_or_default()
+    }
+
+    pub fn into_card(self, source: Source) -> Card {
+        let pinyin = self.pron.as_ref().map(|pron| pron.text.as_str()).unwrap_or_default();
+        let mut card = Card::new(self.headword(), self.categories.clone(), pinyin, source);
+        // Exports join a card's definitions with semicolons.
+        card.definitions = self
+            .definition
+            .iter()
+            .flat_map(|definition| definition.split("; "))
+            .map(str::trim)
+            .filter(|definition| !definition.is_empty())
+            .map(str::to_string)
+            .collect();
+        card.traditional = self.traditional.filter(|traditional| *traditional != card.character);
+        card.created = self.created.or(card.created);
+        card.modified = self.modified.or(card.modified);
+        card.dictrefs = self.dictrefs;
+        card.pleco_scores = self.scores;
+        card
+    }
+}
+
+/// Parses a Pleco export in either format, telling them apart by whether it starts with markup.
+pub fn parse_any(contents: &str) -> Result<PlecoFile, PlecoError> {
+    if contents.trim_start_matches('\u{feff}').trim_start().starts_with('<') {
+        parse(contents)
+    } else {
+        parse_text(contents)
+    }
+}
+
+/// Parses Pleco's text format: `headword<TAB>pinyin<TAB>definition` card lines, filed under
+/// the category of the last `//Category/Sub` line above them. The headword can carry its
+/// traditional form in brackets, `学习[學習]`, and pinyin and definition can be left out.
+pub fn parse_text(text: &str) -> Result<PlecoFile, PlecoError> {
+    let mut file = PlecoFile::default();
+    let mut category: Option<String> = None;
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim_start_matches('\u{feff}').trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(path) = line.strip_prefix("//") {
+            // A bare `//` goes back to cards without a category.
+            category = Some(path.trim().to_string()).filter(|path| !path.is_empty());
+            if let Some(category) = category.as_ref().filter(|category| !file.categories.contains(category)) {
+                file.categories.push(category.clone());
+            }
+            continue;
+        }
+        let mut columns = line.splitn(3, '\t').map(str::trim);
+        let headword = columns.next().unwrap_or_default();
+        let (simplified, traditional) = split_headword(headword).map_err(|(column, message)| PlecoError { message, line: line_number, column })?;
+        file.cards.push(PlecoCard {
+            simplified: Some(simplified),
+            traditional,
+            pron: columns.next().filter(|pron| !pron.is_empty()).map(|pron| Pron { system: Some("hypy".to_string()), text: pron.to_string() }),
+            definition: columns.next().filter(|definition| !definition.is_empty()).map(str::to_string),
+            categories: category.iter().cloned().collect(),
+            line: line_number,
+            ..PlecoCard::default()
+        });
+    }
+    Ok(file)
+}
+
+/// Splits `学习[學習]` into its simplified and traditional forms, or reports the 1-based column
+/// of what's wrong with it.
+fn split_headword(headword: &str) -> Result<(String, Option<String>), (usize, String)> {
+    if headword.is_empty() {
+        return Err((1, "Card without a headword".to_string()));
+    }
+    let Some((simplified, rest)) = headword.split_once('[') else {
+        return Ok((headword.to_string(), None));
+    };
+    let bracket = simplified.chars().count() + 1;
+    let Some(traditional) = rest.strip_suffix(']').filter(|traditional| !traditional.contains(['[', ']'])) else {
+        return Err((bracket, format!("Unclosed traditional form in {}", headword)));
+    };
+    if simplified.is_empty() {
+        return Err((1, "Card without a headword".to_string()));
+    }
+    Ok((simplified.to_string(), Some(traditional.to_string()).filter(|traditional| !traditional.is_empty())))
+}
+
+/// Parses a plecoflash XML export, keeping everything a card carries.
+pub fn parse(xml: &str) -> Result<PlecoFile, PlecoError> {
+    let mut reader = Reader::from_str(xml);
+    let mut file = PlecoFile::default();
+    let mut stack: Vec<String> = Vec::new();
+    let mut card: Option<PlecoCard> = None;
+    let mut text = String::new();
+    // The charset of the headword being read, `sc` or `tc`.
+    let mut charset = String::new();
+
+    loop {
+        let start = reader.buffer_position() as usize;
+        let at = |message: String| error_at(xml, start, message);
+        let event = reader
+            .read_event()
+            .map_err(|e| error_at(xml, reader.error_position() as usize, e.to_string()))?;
+        match event {
+            Event::Start(ref e) | Event::Empty(ref e) => {
+                let name = String::from_utf8_lossy(e.name().as_ref()).to_string();
+                let attributes = attributes(e).map_err(at)?;
+                let parent = stack.last().map(String::as_str);
+                match (parent, name.as_str()) {
+                    (None, "plecoflash") => {
+                        file.header = PlecoHeader {
+                            formatversion: attributes.get("formatversion").cloned(),
+                            creator: attributes.get("creator").cloned(),
+                            generator: attributes.get("generator").cloned(),
+                            platform: attributes.get("platform").cloned(),
+                            created: timestamp(&attributes, "created").map_err(at)?,
+                        };
+                    },
+                    (None, other) => return Err(at(format!("Expected a plecoflash file, found <{}>", other))),
+                    (Some("categories"), "category") => {
+                        file.categories.push(required(&attributes, "category", "name").map_err(at)?);
+                    },
+                    (Some("cards"), "card") => {
+                        card = Some(PlecoCard {
+                            language: attributes.get("language").cloned(),
+                            created: timestamp(&attributes, "created").map_err(at)?,
+                            modified: timestamp(&attributes, "modified").map_err(at)?,
+                            line: error_at(xml, start, String::new()).line,
+                            ..PlecoCard::default()
+                        });
+                    },
+                    (_, "card") => return Err(at("<card> outside of <cards>".to_string())),
+                    (Some("entry"), "headword") => {
+                        charset = attributes.get("charset").cloned().unwrap_or("sc".to_string());
+                    },
+                    (Some("entry"), "pron") => {
+                        if let Some(card) = card.as_mut() {
+                            card.pron = Some(Pron {
+                                system: attributes.get("type").cloned(),
+                                text: String::new(),
+                            });
+                        }
+                    },
+                    (Some("card"), "dictref") => {
+                        if let Some(card) = card.as_mut() {
+                            card.dictrefs.push(DictRef {
+                                dictid: required(&attributes, "dictref", "dictid").map_err(at)?,
+                                entryid: required(&attributes, "dictref", "entryid").map_err(at)?,
+                            });
+                        }
+                    },
+                    (Some("card"), "catassign") => {
+                        if let Some(card) = card.as_mut() {
+                            card.categories.push(required(&attributes, "catassign", "category").map_err(at)?);
+                        }
+                    },
+                    (Some("card"), "scoreinfo") => {
+                        if let Some(card) = card.as_mut() {
+                            let mut fields: BTreeMap<String, String> = attributes.into_iter().collect();
+                            let scorefile = fields.remove("scorefile").unwrap_or_default();
+                            card.scores.push(ScoreInfo { scorefile, fields });
+                        }
+                    },
+                    // Anything else, like the card's <entry>, only matters for what it contains.
+                    _ => {},
+                }
+                if matches!(event, Event::Start(_)) {
+                    stack.push(name);
+                    text.clear();
+                }
+            },
+            Event::Text(e) => text.push_str(&e.xml_content().map_err(|e| at(e.to_string()))?),
+            Event::CData(e) => text.push_str(&e.decode().map_err(|e| at(e.to_string()))?),
+            Event::GeneralRef(e) => {
+                if let Some(c) = e.resolve_char_ref().map_err(|e| at(e.to_string()))? {
+                    text.push(c);
+                } else {
+                    let name = e.decode().map_err(|e| at(e.to_string()))?;
+                    let resolved = resolve_predefined_entity(&name)
+                        .ok_or(at(format!("Unknown entity &{};", name)))?;
+                    text.push_str(resolved);
+                }
+            },
+            Event::End(ref e) => {
+                let name = String::from_utf8_lossy(e.name().as_ref()).to_string();
+                stack.pop();
+                let value = text.trim().to_string();
+                match name.as_str() {
+                    "headword" => {
+                        if let Some(card) = card.as_mut() {
+                            if charset == "tc" {
+                                card.traditional = Some(value);
+                            } else {
+                                card.simplified = Some(value);
+                            }
+                        }
+                    },
+                    "pron" => {
+                        if let Some(pron) = card.as_mut().and_then(|card| card.pron.as_mut()) {
+                            pron.text = value;
+                        }
+                    },
+                    "defn" => {
+                        if let Some(card) = card.as_mut() {
+                            card.definition = Some(value);
+                        }
+                    },
+                    "card" => {
+                        if let Some(card) = card.take() {
+                            if card.headword().is_empty() {
+                                return Err(error_at(xml, start, "Card without a headword".to_string()));
+                            }
+                            file.cards.push(card);
+                        }
+                    },
+                    _ => {},
+                }
+                text.clear();
+            },
+            Event::Eof => break,
+            _ => {},
+        }
+    }
+    if let Some(open) = stack.last() {
+        return Err(error_at(xml, xml.len(), format!("File ends inside <{}>", open)));
+    }
+    Ok(file)
+}
+
+fn attributes(element: &BytesStart) -> Result<HashMap<String, String>, String> {
+    let mut attributes = HashMap::new();
+    for attribute in element.attributes() {
+        let attribute = attribute.map_err(|e| e.to_string())?;
+        let key = String::from_utf8_lossy(attribute.key.as_ref()).to_string();
+        let value = attribute.unescape_value().map_err(|e| e.to_string())?;
+        attributes.insert(key, value.to_string());
+    }
+    Ok(attributes)
+}
+
+fn required(attributes: &HashMap<String, String>, element: &str, name: &str) -> Result<String, String> {
+    attributes.get(name).cloned().ok_or(format!("<{}> is missing its {} attribute", element, name))
+}
+
+/// Pleco writes times as unix seconds, such as `created="1757266198"`.
+fn timestamp(attributes: &HashMap<String, String>, name: &str) -> Result<Option<DateTime<Utc>>, String> {
+    let Some(value) = attributes.get(name) else {
+        return Ok(None);
+    };
+    value
+        .parse::<i64>()
+        .ok()
+        .and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+        .map(Some)
+        .ok_or(format!("Invalid {} timestamp {}", name, value))
+}
+
+fn error_at(xml: &str, offset: usize, message: String) -> PlecoError {
+    let before = &xml[..xml.floor_char_boundary(offset.min(xml.len()))];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map(|last| last.chars().count()).unwrap_or(0) + 1;
+    PlecoError { message, line, column }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FULL: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plecoflash formatversion="2" creator="Pleco User 123" generator="Pleco 3.2" platform="iPhone OS" created="1757266198">
+  <categories>
+    <category name="HSK/Level 1"/>
+    <category name="Empty"/>
+  </categories>
+  <cards>
+    <card language="chinese" created="1757266100" modified="1757266150">
+      <entry>
+        <headword charset="sc">学习</headword>
+        <headword charset="tc">學習</headword>
+        <pron type="hypy" tones="numbers">xue2xi2</pron>
+        <defn>to learn; to study &amp; practise</defn>
+      </entry>
+      <dictref dictid="PACE" entryid="12345"/>
+      <catassign category="HSK/Level 1"/>
+      <scoreinfo scorefile="Default" score="150" difficulty="100" correct="3" incorrect="1"/>
+    </card>
+  </cards>
+</plecoflash>
+"#;
+
+    fn timestamp(seconds: i64) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp(seconds, 0)
+    }
+
+    fn error(xml: &str) -> (usize, usize, String) {
+        let e = parse(xml).unwrap_err();
+        (e.line, e.column, e.message)
+    }
+
+    #[test]
+    fn full_card_is_read() {
+        let file = parse(FULL).unwrap();
+        assert_eq!(file.header.formatversion.as_deref(), Some("2"));
+        assert_eq!(file.header.creator.as_deref(), Some("Pleco User 123"));
+        assert_eq!(file.header.generator.as_deref(), Some("Pleco 3.2"));
+        assert_eq!(file.header.platform.as_deref(), Some("iPhone OS"));
+        assert_eq!(file.header.created, timestamp(1757266198));
+        assert_eq!(file.categories, vec!["HSK/Level 1", "Empty"]);
+
+        let [card] = file.cards.as_slice() else { panic!("Expected one card, got {}", file.cards.len()) };
+        assert_eq!(card.line, 8);
+        assert_eq!(card.language.as_deref(), Some("chinese"));
+        assert_eq!((card.created, card.modified), (timestamp(1757266100), timestamp(1757266150)));
+        assert_eq!(card.simplified.as_deref(), Some("学习"));
+        assert_eq!(card.traditional.as_deref(), Some("學習"));
+        let pron = card.pron.as_ref().unwrap();
+        assert_eq!((pron.system.as_deref(), pron.text.as_str()), (Some("hypy"), "xue2xi2"));
+        assert_eq!(card.definition.as_deref(), Some("to learn; to study & practise"));
+        assert_eq!(card.dictrefs, vec![DictRef { dictid: "PACE".to_string(), entryid: "12345".to_string() }]);
+        assert_eq!(card.categories, vec!["HSK/Level 1"]);
+        let fields = [("correct", "3"), ("difficulty", "100"), ("incorrect", "1"), ("score", "150")]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(card.scores, vec![ScoreInfo { scorefile: "Default".to_string(), fields }]);
+
+        let card = card.clone().into_card(Source::new("test", None, None));
+        assert_eq!(card.key(), "学习|xue2xi2");
+        assert_eq!(card.definitions, vec!["to learn", "to study & practise"]);
+        assert_eq!(card.traditional.as_deref(), Some("學習"));
+    }
+
+    #[test]
+    fn wrong_root_element() {
+        assert_eq!(error("<flashcards/>"), (1, 1, "Expected a plecoflash file, found <flashcards>".to_string()));
+    }
+
+    #[test]
+    fn card_outside_cards() {
+        assert_eq!(error("<plecoflash>\n  <card/>\n</plecoflash>"), (2, 3, "<card> outside of <cards>".to_string()));
+    }
+
+    #[test]
+    fn missing_required_attribute() {
+        let xml = "<plecoflash><cards>\n<card>\n    <dictref dictid=\"PACE\"/>\n</card></cards></plecoflash>";
+        assert_eq!(error(xml), (3, 5, "<dictref> is missing its entryid attribute".to_string()));
+    }
+
+    #[test]
+    fn invalid_timestamp() {
+        let xml = "<plecoflash>\n<cards><card created=\"yesterday\">";
+        assert_eq!(error(xml), (2, 8, "Invalid created timestamp yesterday".to_string()));
+    }
+
+    #[test]
+    fn card_without_headword() {
+        let xml = "<plecoflash><cards>\n<card><entry><pron>xue2</pron></entry></card>\n</cards></plecoflash>";
+        assert_eq!(error(xml), (2, 39, "Card without a headword".to_string()));
+    }
+
+    #[test]
+    fn unknown_entity() {
+        let xml = "<plecoflash><cards><card><entry>\n<defn>&nbsp;</defn>";
+        assert_eq!(error(xml), (2, 7, "Unknown entity &nbsp;".to_string()));
+    }
+
+    #[test]
+    fn truncated_file() {
+        let xml = "<plecoflash>\n  <cards>\n  ";
+        assert_eq!(error(xml), (3, 3, "File ends inside <cards>".to_string()));
+    }
+
+    #[test]
+    fn text_format_card_lines() {
+        let file = parse_text("学习[學習]\txue2xi2\tto learn\n//HSK/Level 1\n好\thao3\n//\n他\n").unwrap();
+        assert_eq!(file.categories, vec!["HSK/Level 1"]);
+        let cards: Vec<(&str, Option<&str>, Vec<String>, usize)> = file
+            .cards
+            .iter()
+            .map(|card| (card.headword(), card.traditional.as_deref(), card.categories.clone(), card.line))
+            .collect();
+        assert_eq!(cards, vec![
+            ("学习", Some("學習"), vec![], 1),
+            ("好", None, vec!["HSK/Level 1".to_string()], 3),
+            ("他", None, vec![], 5),
+        ]);
+        assert_eq!(file.cards[0].definition.as_deref(), Some("to learn"));
+        assert!(file.cards[2].pron.is_none());
+    }
+
+    #[test]
+    fn text_format_errors() {
+        let e = parse_text("好\thao3\n学习[學習\txue2xi2\n").unwrap_err();
+        assert_eq!((e.line, e.column, e.message.as_str()), (2, 3, "Unclosed traditional form in 学习[學習"));
+        let e = parse_text("//Lesson 1\n\tni3\n").unwrap_err();
+        assert_eq!((e.line, e.column, e.message.as_str()), (2, 1, "Card without a headword"));
+    }
+}
@@ -0,0 +1,267 @@
+use chrono::{DateTime, Duration, Local, Utc};
+use inquire::{Select, Text};
+use serde::{Deserialize, Serialize};
+
+use crate::db::Storage;
+use crate::db::DB;
+use crate::db::DBError;
+use crate::pinyin::{self, PinyinStyle};
+
+const MIN_EASE: f32 = 1.3;
+const DEFAULT_EASE: f32 = 2.5;
+
+#[derive(Debug)]
+pub struct ReviewError(String);
+
+impl std::fmt::Display for ReviewError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ReviewError {}
+
+impl From<DBError> for ReviewError {
+    fn from(e: DBError) -> Self {
+        ReviewError(format!("DB error: {}", e))
+    }
+}
+
+/// How well a card was remembered, mapped onto the SM-2 quality scale.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Grade {
+    Again,
+    Hard,
+    Good,
+    Easy,
+}
+
+impl Grade {
+    fn quality(&self) -> f32 {
+        match self {
+            Grade::Again => 1.0,
+            Grade::Hard => 3.0,
+            Grade::Good => 4.0,
+            Grade::Easy => 5.0,
+        }
+    }
+}
+
+impl std::fmt::Display for Grade {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self {
+            Grade::Again => "Again",
+            Grade::Hard => "Hard",
+            Grade::Good => "Good",
+            Grade::Easy => "Easy",
+        };
+        write!(f, "{}", label)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReviewLog {
+    pub reviewed_at: DateTime<Utc>,
+    pub grade: Grade,
+    pub interval_days: u32,
+    pub ease: f32,
+}
+
+/// SM-2 scheduling state for a card. A card without a due date has never been reviewed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Schedule {
+    pub due: Option<DateTime<Utc>>,
+    pub interval_days: u32,
+    pub ease: f32,
+    pub repetitions: u32,
+    pub lapses: u32,
+    pub history: Vec<ReviewLog>,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule {
+            due: None,
+            interval_days: 0,
+            ease: DEFAULT_EASE,
+            repetitions: 0,
+            lapses: 0,
+            history: Vec::new(),
+        }
+    }
+}
+
+impl Schedule {
+    pub fn is_new(&self) -> bool {
+        self.due.is_none()
+    }
+
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.due.map(|due| due <= now).unwrap_or(false)
+    }
+
+    pub fn grade(&mut self, grade: Grade, now: DateTime<Utc>) {
+        let quality = grade.quality();
+        if quality < 3.0 {
+            if self.repetitions > 0 {
+                self.lapses += 1;
+            }
+            self.repetitions = 0;
+            self.interval_days = 1;
+        } else {
+            self.interval_days = match self.repetitions {
+                0 => 1,
+                1 => 6,
+                _ => (self.interval_days as f32 * self.ease).round() as u32,
+            };
+            self.repetitions += 1;
+        }
+        self.ease = (self.ease + 0.1 - (5.0 - quality) * (0.08 + (5.0 - quality) * 0.02)).max(MIN_EASE);
+        self.due = Some(now + Duration::days(self.interval_days as i64));
+        self.history.push(ReviewLog {
+            reviewed_at: now,
+            grade,
+            interval_days: self.interval_days,
+            ease: self.ease,
+        });
+    }
+}
+
+/// Caps on how many cards a single day of reviewing will show.
+pub struct DailyLimits {
+    pub new_cards: usize,
+    pub reviews: usize,
+}
+
+pub fn review(category: Option<&str>, limits: &DailyLimits, style: PinyinStyle, db: &mut DB, storage: &dyn Storage) -> Result<(), ReviewError> {
+    let now = Utc::now();
+    let (new_today, reviewed_today) = reviewed_today(db);
+    let new_remaining = limits.new_cards.saturating_sub(new_today);
+    let review_remaining = limits.reviews.saturating_sub(reviewed_today);
+
+    let mut due: Vec<(String, DateTime<Utc>)> = Vec::new();
+    let mut new: Vec<String> = Vec::new();
+    for (key, card) in db.iter() {
+        if let Some(category) = category {
+            if !card.category.iter().any(|c| c.to_lowercase() == category.to_lowercase()) {
+                continue;
+            }
+        }
+        if card.schedule.is_new() {
+            new.push(key.clone());
+        } else if card.schedule.is_due(now) {
+            due.push((key.clone(), card.schedule.due.unwrap_or(now)));
+        }
+    }
+    due.sort_by_key(|(_, due_at)| *due_at);
+    new.sort();
+
+    let mut queue: Vec<String> = due.into_iter().take(review_remaining).map(|(key, _)| key).collect();
+    queue.extend(new.into_iter().take(new_remaining));
+    if queue.is_empty() {
+        println!("Nothing to review right now.");
+        return Ok(());
+    }
+
+    println!("{} cards to review ({} new left today, {} reviews left today)", queue.len(), new_remaining, review_remaining);
+    let grades = vec![Grade::Again, Grade::Hard, Grade::Good, Grade::Easy];
+    let mut reviewed: Vec<String> = Vec::new();
+    for (i, key) in queue.iter().enumerate() {
+        let card = match db.get(key) {
+            Some(card) => card,
+            None => continue,
+        };
+        println!("\n[{}/{}] {}", i + 1, queue.len(), card.character);
+        // Escape or ctrl-c ends the session, keeping the grades given so far.
+        match Text::new("Press enter to show the answer, or type q to stop").prompt() {
+            Ok(answer) if answer.trim() != "q" => {},
+            _ => break,
+        }
+        println!("{} {}", card.character, pinyin::display(&card.pinyin, style));
+        let grade = match Select::new("How well did you remember it?", grades.clone()).prompt() {
+            Ok(grade) => grade,
+            Err(_) => break,
+        };
+        if let Some(card) = db.get_mut(key) {
+            card.schedule.grade(grade, Utc::now());
+            reviewed.push(key.clone());
+        }
+    }
+
+    if !reviewed.is_empty() {
+        storage.save_cards(db, &reviewed)?;
+    }
+    println!("Reviewed {} cards.", reviewed.len());
+    Ok(())
+}
+
+/// Counts the new cards introduced and the reviews done so far today, local time.
+fn reviewed_today(db: &DB) -> (usize, usize) {
+    let today = Local::now().date_naive();
+    let mut new_cards = 0;
+    let mut reviews = 0;
+    for card in db.values() {
+        for (i, log) in card.schedule.history.iter().enumerate() {
+            if log.reviewed_at.with_timezone(&Local).date_naive() != today {
+                continue;
+            }
+            if i == 0 {
+                new_cards += 1;
+            } else {
+                reviews += 1;
+            }
+        }
+    }
+    (new_cards, reviews)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRADES: [Grade; 4] = [Grade::Again, Grade::Hard, Grade::Good, Grade::Easy];
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-03-01T12:00:00Z").unwrap().with_timezone(&Utc)
+    }
+
+    fn mature() -> Schedule {
+        Schedule { due: Some(now()), interval_days: 10, ease: DEFAULT_EASE, repetitions: 3, lapses: 1, history: Vec::new() }
+    }
+
+    /// The interval, ease, repetitions and lapses after grading `schedule` with `grade`.
+    fn graded(mut schedule: Schedule, grade: Grade) -> (u32, f32, u32, u32) {
+        schedule.grade(grade, now());
+        assert_eq!(schedule.due, Some(now() + Duration::days(schedule.interval_days as i64)));
+        let log = schedule.history.last().unwrap();
+        assert_eq!((log.reviewed_at, log.grade, log.interval_days, log.ease), (now(), grade, schedule.interval_days, schedule.ease));
+        (schedule.interval_days, (schedule.ease * 100.0).round() / 100.0, schedule.repetitions, schedule.lapses)
+    }
+
+    #[test]
+    fn grading_a_new_card() {
+        let results: Vec<_> = GRADES.iter().map(|grade| graded(Schedule::default(), *grade)).collect();
+        assert_eq!(results, vec![(1, 1.96, 0, 0), (1, 2.36, 1, 0), (1, 2.5, 1, 0), (1, 2.6, 1, 0)]);
+    }
+
+    #[test]
+    fn second_review_is_six_days_later() {
+        let mut schedule = Schedule::default();
+        schedule.grade(Grade::Good, now());
+        assert_eq!(graded(schedule, Grade::Good), (6, 2.5, 2, 0));
+    }
+
+    #[test]
+    fn grading_a_mature_card() {
+        let results: Vec<_> = GRADES.iter().map(|grade| graded(mature(), *grade)).collect();
+        // Forgetting it starts it over and counts a lapse; otherwise the interval grows by the old ease.
+        assert_eq!(results, vec![(1, 1.96, 0, 2), (25, 2.36, 4, 1), (25, 2.5, 4, 1), (25, 2.6, 4, 1)]);
+    }
+
+    #[test]
+    fn ease_does_not_drop_below_the_minimum() {
+        let schedule = Schedule { ease: MIN_EASE, ..mature() };
+        assert_eq!(graded(schedule, Grade::Again).1, MIN_EASE);
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::dictionary::Dictionary;
+
+/// How `import text` splits its input into words.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Segmentation {
+    /// Offline, using the CC-CEDICT headwords and optional word frequencies
+    Dictionary,
+    /// Ask OpenAI to split the text
+    Llm,
+}
+
+#[derive(Debug)]
+pub struct SegmentError(String);
+
+impl std::fmt::Display for SegmentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SegmentError {}
+
+/// Reads a jieba style frequency list, one `word frequency [tag]` per line.
+pub fn load_frequencies(path: &str) -> Result<HashMap<String, u64>, SegmentError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| SegmentError(format!("Failed to read frequency file {}: {}", path, e)))?;
+    let mut frequencies = HashMap::new();
+    for line in contents.lines() {
+        let mut fields = line.split_whitespace();
+        if let (Some(word), Some(frequency)) = (fields.next(), fields.next()) {
+            if let Ok(frequency) = frequency.parse::<u64>() {
+                frequencies.insert(word.to_string(), frequency);
+            }
+        }
+    }
+    Ok(frequencies)
+}
+
+/// Jieba style segmentation: every dictionary word starting at each character forms a DAG,
+/// and the route with the highest product of word probabilities wins. Without a frequency
+/// list every word is equally likely, which picks the split into the fewest words.
+pub struct Segmenter {
+    frequencies: HashMap<String, u64>,
+    total: f64,
+    max_word_len: usize,
+}
+
+impl Segmenter {
+    pub fn new(dictionary: &Dictionary, frequencies: Option<&HashMap<String, u64>>) -> Self {
+        let mut words: HashMap<String, u64> = HashMap::new();
+        for headword in dictionary.headwords() {
+            let frequency = frequencies.and_then(|frequencies| frequencies.get(headword)).copied().unwrap_or(1);
+            words.insert(headword.to_string(), frequency.max(1));
+        }
+        if let Some(frequencies) = frequencies {
+            for (word, frequency) in frequencies {
+                words.entry(word.clone()).or_insert((*frequency).max(1));
+            }
+        }
+        let total = words.values().sum::<u64>().max(1) as f64;
+        let max_word_len = words.keys().map(|word| word.chars().count()).max().unwrap_or(1);
+        Segmenter { frequencies: words, total, max_word_len }
+    }
+
+    /// Splits every run of Han characters in `text` into words, dropping everything else.
+    pub fn segment(&self, text: &str) -> Vec<String> {
+        static HAN_RUN: OnceLock<Regex> = OnceLock::new();
+        let re = HAN_RUN.get_or_init(|| Regex::new(r"[\p{Han}]+").expect("Regex failed to initialize"));
+        re.find_iter(text).flat_map(|run| self.segment_run(run.as_str())).collect()
+    }
+
+    fn segment_run(&self, run: &str) -> Vec<String> {
+        let chars: Vec<char> = run.chars().collect();
+        let n = chars.len();
+        // Unknown single characters get less weight than the rarest known word.
+        let unknown = (0.5 / self.total).ln();
+        let mut best: Vec<(f64, usize)> = vec![(0.0, n); n + 1];
+        for start in (0..n).rev() {
+            best[start] = (f64::NEG_INFINITY, start + 1);
+            for end in start + 1..=n.min(start + self.max_word_len) {
+                let word: String = chars[start..end].iter().collect();
+                let log_probability = match self.frequencies.get(&word) {
+                    Some(frequency) => (*frequency as f64 / self.total).ln(),
+                    None if end == start + 1 => unknown,
+                    None => continue,
+                };
+                let score = log_probability + best[end].0;
+                if score > best[start].0 {
+                    best[start] = (score, end);
+                }
+            }
+        }
+
+        let mut words = Vec::new();
+        let mut start = 0;
+        while start < n {
+            let end = best[start].1;
+            words.push(chars[start..end].iter().collect());
+            start = end;
+        }
+        words
+    }
+}
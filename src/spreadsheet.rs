@@ -0,0 +1,143 @@
+use std::path::Path;
+
+use encoding_rs::{Encoding, GB18030, UTF_8};
+use regex::Regex;
+
+use crate::mapping::{self, Field};
+
+/// Whether the first row of a file names its columns.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum HeaderMode {
+    /// A header when the first row names familiar columns, or has no Chinese while the next row does
+    Auto,
+    Yes,
+    No,
+}
+
+/// A row of a CSV or TSV file, with its 1-based line for messages.
+pub struct Row {
+    pub line: usize,
+    pub fields: Vec<Field>,
+}
+
+/// Reads a delimiter given by name (`tab`, `comma`, ...) or as the character itself.
+pub fn parse_delimiter(name: &str) -> Result<u8, String> {
+    match name.to_lowercase().as_str() {
+        "tab" | "\\t" => Ok(b'\t'),
+        "comma" => Ok(b','),
+        "semicolon" => Ok(b';'),
+        "space" => Ok(b' '),
+        "pipe" => Ok(b'|'),
+        "colon" => Ok(b':'),
+        other if other.len() == 1 => Ok(other.as_bytes()[0]),
+        other => Err(format!("Unknown delimiter {}, use a single character or tab, comma, semicolon, space, pipe or colon", other)),
+    }
+}
+
+/// Tab for `.tsv` files, otherwise whichever of tab, comma and semicolon the first line has most of.
+fn detect_delimiter(path: &str, text: &str) -> u8 {
+    let extension = Path::new(path).extension().map(|extension| extension.to_string_lossy().to_lowercase());
+    if matches!(extension.as_deref(), Some("tsv") | Some("tab")) {
+        return b'\t';
+    }
+    let first_line = text.lines().next().unwrap_or_default();
+    [b',', b'\t', b';']
+        .into_iter()
+        .max_by_key(|delimiter| first_line.bytes().filter(|byte| byte == delimiter).count())
+        .unwrap_or(b',')
+}
+
+/// Decodes `bytes` in the encoding named by `label`, or when there's none, the one its byte
+/// order mark gives, else UTF-8 if it is valid UTF-8, else GB18030, which spreadsheets saved
+/// on Chinese Windows tend to use.
+pub fn decode(bytes: &[u8], label: Option<&str>) -> Result<String, String> {
+    let encoding = match label {
+        Some(label) => Encoding::for_label(label.trim().as_bytes()).ok_or(format!("Unknown encoding {}", label))?,
+        None => match Encoding::for_bom(bytes) {
+            Some((encoding, _)) => encoding,
+            None if std::str::from_utf8(bytes).is_ok() => UTF_8,
+            None => GB18030,
+        },
+    };
+    let (text, used, had_errors) = encoding.decode(bytes);
+    if had_errors {
+        return Err(format!("The file is not valid {}, pass --encoding to read it as something else", used.name()));
+    }
+    if label.is_none() && used != UTF_8 {
+        println!("Reading the file as {}", used.name());
+    }
+    Ok(text.into_owned())
+}
+
+/// Encodes `text` for writing. Encodings that can't be written, like UTF-16, fall back to UTF-8.
+pub fn encode(text: &str, label: &str) -> Result<Vec<u8>, String> {
+    let encoding = Encoding::for_label(label.trim().as_bytes()).ok_or(format!("Unknown encoding {}", label))?;
+    let (bytes, used, had_unmappable) = encoding.encode(text);
+    if used != encoding {
+        println!("Can't write {}, writing {} instead", encoding.name(), used.name());
+    }
+    if had_unmappable {
+        println!("Some characters have no {} form and were written as HTML character references", used.name());
+    }
+    Ok(bytes.into_owned())
+}
+
+/// Reads every non-empty row of a CSV or TSV file. With a header, the fields are named after it.
+pub fn read_rows(path: &str, delimiter: Option<&str>, header: HeaderMode, encoding: Option<&str>) -> Result<Vec<Row>, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let text = decode(&bytes, encoding)?;
+    let delimiter = match delimiter {
+        Some(delimiter) => parse_delimiter(delimiter)?,
+        None => detect_delimiter(path, &text),
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let mut records = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| format!("Malformed file: {}", e))?;
+        if record.iter().all(|value| value.trim().is_empty()) {
+            continue;
+        }
+        let line = record.position().map(|position| position.line() as usize).unwrap_or_default();
+        records.push((line, record.iter().map(str::to_string).collect::<Vec<String>>()));
+    }
+
+    let has_header = match header {
+        HeaderMode::Yes => true,
+        HeaderMode::No => false,
+        HeaderMode::Auto => match records.as_slice() {
+            [(_, first), rest @ ..] => {
+                let han = Regex::new(r"\p{Han}").expect("Regex failed to initialize");
+                let has_chinese = |values: &Vec<String>| values.iter().any(|value| han.is_match(value));
+                first.iter().any(|value| mapping::recognized(value))
+                    || (!has_chinese(first) && rest.first().is_some_and(|(_, second)| has_chinese(second)))
+            },
+            [] => false,
+        },
+    };
+    let names: Vec<String> = if has_header { records.remove(0).1 } else { Vec::new() };
+    Ok(records
+        .into_iter()
+        .map(|(line, values)| Row {
+            line,
+            fields: values
+                .into_iter()
+                .enumerate()
+                .map(|(index, value)| Field { name: names.get(index).cloned(), column: index + 1, value })
+                .collect(),
+        })
+        .collect())
+}
+
+/// Writes `rows` as CSV with `delimiter`, `header` first when given.
+pub fn write_rows(header: Option<Vec<String>>, rows: Vec<Vec<String>>, delimiter: u8) -> Result<String, String> {
+    let mut writer = csv::WriterBuilder::new().delimiter(delimiter).from_writer(Vec::new());
+    for row in header.into_iter().chain(rows) {
+        writer.write_record(&row).map_err(|e| e.to_string())?;
+    }
+    let bytes = writer.into_inner().map_err(|e| e.to_string())?;
+    String::from_utf8(bytes).map_err(|e| e.to_string())
+}
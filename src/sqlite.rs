@@ -0,0 +1,366 @@
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde_json::{json, Value};
+
+use crate::backup::create_backup;
+use crate::db::{records_to_db, Card, DBError, Storage, DB};
+use crate::migrations::{migrate, MigrationReport, Record, CURRENT_VERSION};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS cards (
+    key TEXT PRIMARY KEY,
+    character TEXT NOT NULL,
+    pinyin TEXT NOT NULL,
+    due TEXT,
+    interval_days INTEGER NOT NULL DEFAULT 0,
+    ease REAL NOT NULL DEFAULT 2.5,
+    repetitions INTEGER NOT NULL DEFAULT 0,
+    lapses INTEGER NOT NULL DEFAULT 0,
+    data TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS cards_character ON cards(character);
+CREATE INDEX IF NOT EXISTS cards_due ON cards(due);
+
+CREATE TABLE IF NOT EXISTS card_categories (
+    card_key TEXT NOT NULL REFERENCES cards(key) ON DELETE CASCADE,
+    category TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    PRIMARY KEY (card_key, category)
+);
+CREATE INDEX IF NOT EXISTS card_categories_category ON card_categories(category COLLATE NOCASE);
+
+CREATE TABLE IF NOT EXISTS reviews (
+    id INTEGER PRIMARY KEY,
+    card_key TEXT NOT NULL REFERENCES cards(key) ON DELETE CASCADE,
+    reviewed_at TEXT NOT NULL,
+    grade TEXT NOT NULL,
+    interval_days INTEGER NOT NULL,
+    ease REAL NOT NULL
+);
+CREATE INDEX IF NOT EXISTS reviews_card ON reviews(card_key, reviewed_at);
+";
+
+/// Cards, their categories and their review history as indexed SQLite tables, so single
+/// cards can be read and written without touching the rest of the deck.
+pub struct SqliteStorage {
+    conn: Connection,
+    path: String,
+    /// Whether this run has backed the database up yet.
+    backed_up: Cell<bool>,
+    /// Whether the command holds the DB to itself, and so may upgrade an older database.
+    upgradable: bool,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`. Only an `upgradable` one is stamped with its format version
+    /// or migrated, since a command sharing the DB with others mustn't write to it.
+    pub fn open(path: &str, upgradable: bool) -> Result<Self, DBError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)?;
+        let storage = SqliteStorage { conn, path: path.to_string(), backed_up: Cell::new(false), upgradable };
+        if upgradable && storage.stored_version()? == 0 {
+            storage.set_version(storage.version()?)?;
+        }
+        Ok(storage)
+    }
+
+    /// Opens a database that is never backed up, such as the temporary copy a backup is read from.
+    pub fn open_without_backups(path: &str) -> Result<Self, DBError> {
+        let storage = Self::open(path, true)?;
+        storage.backed_up.set(true);
+        Ok(storage)
+    }
+
+    /// Reads a card as raw JSON, so rows written by older versions can be migrated before
+    /// they are deserialized.
+    fn read_record(&self, key: &str) -> Result<Option<Value>, DBError> {
+        let row = self.conn.query_row(
+            "SELECT data, due, interval_days, ease, repetitions, lapses FROM cards WHERE key = ?1",
+            params![key],
+            |row| Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<DateTime<Utc>>>(1)?,
+                row.get::<_, u32>(2)?,
+                row.get::<_, f32>(3)?,
+                row.get::<_, u32>(4)?,
+                row.get::<_, u32>(5)?,
+            )),
+        ).optional()?;
+        let Some((data, due, interval_days, ease, repetitions, lapses)) = row else {
+            return Ok(None);
+        };
+
+        let mut categories = self.conn.prepare_cached(
+            "SELECT category FROM card_categories WHERE card_key = ?1 ORDER BY position",
+        )?;
+        let categories = categories
+            .query_map(params![key], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut reviews = self.conn.prepare_cached(
+            "SELECT reviewed_at, grade, interval_days, ease FROM reviews WHERE card_key = ?1 ORDER BY reviewed_at, id",
+        )?;
+        let history = reviews
+            .query_map(params![key], |row| Ok(json!({
+                "reviewed_at": row.get::<_, DateTime<Utc>>(0)?,
+                "grade": row.get::<_, String>(1)?,
+                "interval_days": row.get::<_, u32>(2)?,
+                "ease": row.get::<_, f32>(3)?,
+            })))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut record: Value = serde_json::from_str(&data)?;
+        if let Some(card) = record.as_object_mut() {
+            card.insert("category".to_string(), json!(categories));
+            card.insert("schedule".to_string(), json!({
+                "due": due,
+                "interval_days": interval_days,
+                "ease": ease,
+                "repetitions": repetitions,
+                "lapses": lapses,
+                "history": history,
+            }));
+        }
+        Ok(Some(record))
+    }
+
+    fn read_card(&self, key: &str) -> Result<Option<Card>, DBError> {
+        match self.read_record(key)? {
+            Some(record) => Ok(Some(serde_json::from_value(record)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Backs the database up before the first write of a run. Writes only touch the cards
+    /// that changed, so backing up before each one would copy the whole deck every time.
+    fn back_up_once(&self) -> Result<(), DBError> {
+        if !self.backed_up.replace(true) {
+            create_backup(self, &self.path).map_err(|e| DBError(format!("Backup failed: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    fn has_cards(&self) -> Result<bool, DBError> {
+        Ok(self.conn.query_row("SELECT EXISTS(SELECT 1 FROM cards)", [], |row| row.get(0))?)
+    }
+
+    fn stored_version(&self) -> Result<u32, DBError> {
+        Ok(self.conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+    }
+
+    /// The format version of the stored cards. An unversioned file with cards in it predates
+    /// versioning, and the SQLite schema was introduced at format version 1. A new or empty one
+    /// is in the current format.
+    fn version(&self) -> Result<u32, DBError> {
+        match self.stored_version()? {
+            0 if self.has_cards()? => Ok(1),
+            0 => Ok(CURRENT_VERSION),
+            version => Ok(version),
+        }
+    }
+
+    fn set_version(&self, version: u32) -> Result<(), DBError> {
+        self.conn.execute_batch(&format!("PRAGMA user_version = {};", version))?;
+        Ok(())
+    }
+
+    fn keys(&self, sql: &str, param: Option<&str>) -> Result<Vec<String>, DBError> {
+        let mut statement = self.conn.prepare(sql)?;
+        let rows = match param {
+            Some(param) => statement.query_map(params![param], |row| row.get::<_, String>(0))?.collect::<Result<Vec<_>, _>>()?,
+            None => statement.query_map([], |row| row.get::<_, String>(0))?.collect::<Result<Vec<_>, _>>()?,
+        };
+        Ok(rows)
+    }
+}
+
+fn write_card(tx: &Transaction, key: &str, card: &Card) -> Result<(), DBError> {
+    // Categories and history live in their own tables; `data` keeps the remaining fields.
+    let mut data = card.clone();
+    data.category.clear();
+    data.schedule.history.clear();
+    let schedule = &card.schedule;
+    tx.execute(
+        "INSERT INTO cards (key, character, pinyin, due, interval_days, ease, repetitions, lapses, data)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+         ON CONFLICT(key) DO UPDATE SET character = ?2, pinyin = ?3, due = ?4, interval_days = ?5,
+             ease = ?6, repetitions = ?7, lapses = ?8, data = ?9",
+        params![
+            key,
+            card.character,
+            card.pinyin,
+            schedule.due,
+            schedule.interval_days,
+            schedule.ease,
+            schedule.repetitions,
+            schedule.lapses,
+            serde_json::to_string(&data)?,
+        ],
+    )?;
+
+    tx.execute("DELETE FROM card_categories WHERE card_key = ?1", params![key])?;
+    let mut seen: HashSet<&str> = HashSet::new();
+    for (position, category) in card.category.iter().enumerate() {
+        if seen.insert(category.as_str()) {
+            tx.execute(
+                "INSERT INTO card_categories (card_key, category, position) VALUES (?1, ?2, ?3)",
+                params![key, category, position],
+            )?;
+        }
+    }
+
+    // History is append-only, so only reviews beyond what is already stored are inserted.
+    let stored: usize = tx.query_row("SELECT COUNT(*) FROM reviews WHERE card_key = ?1", params![key], |row| row.get(0))?;
+    let start = if stored > schedule.history.len() {
+        tx.execute("DELETE FROM reviews WHERE card_key = ?1", params![key])?;
+        0
+    } else {
+        stored
+    };
+    for log in &schedule.history[start..] {
+        tx.execute(
+            "INSERT INTO reviews (card_key, reviewed_at, grade, interval_days, ease) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![key, log.reviewed_at, log.grade.to_string(), log.interval_days, log.ease],
+        )?;
+    }
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn load(&self) -> Result<DB, DBError> {
+        let version = self.version()?;
+        if version < CURRENT_VERSION {
+            if !self.upgradable {
+                return Err(DBError(format!(
+                    "DB is format version {} and needs upgrading to {}, run `db migrate` first",
+                    version, CURRENT_VERSION
+                )));
+            }
+            self.migrate(false)?;
+        }
+        let mut db: DB = HashMap::new();
+        for key in self.keys("SELECT key FROM cards", None)? {
+            if let Some(card) = self.read_card(&key)? {
+                db.insert(key, card);
+            }
+        }
+        Ok(db)
+    }
+
+    fn save(&self, db: &DB) -> Result<(), DBError> {
+        let stale: Vec<String> = self
+            .keys("SELECT key FROM cards", None)?
+            .into_iter()
+            .filter(|key| !db.contains_key(key))
+            .collect();
+        let keys: Vec<String> = db.keys().cloned().collect();
+        self.remove_cards(db, &stale)?;
+        self.save_cards(db, &keys)
+    }
+
+    fn save_cards(&self, db: &DB, keys: &[String]) -> Result<(), DBError> {
+        self.back_up_once()?;
+        let tx = self.conn.unchecked_transaction()?;
+        for key in keys {
+            if let Some(card) = db.get(key) {
+                write_card(&tx, key, card)?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn remove_cards(&self, _db: &DB, keys: &[String]) -> Result<(), DBError> {
+        self.back_up_once()?;
+        let tx = self.conn.unchecked_transaction()?;
+        for key in keys {
+            tx.execute("DELETE FROM cards WHERE key = ?1", params![key])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn category_cards(&self, category: &str) -> Result<Vec<Card>, DBError> {
+        let keys = self.keys(
+            "SELECT card_key FROM card_categories WHERE category = ?1 COLLATE NOCASE",
+            Some(category),
+        )?;
+        let mut cards = Vec::new();
+        for key in keys {
+            if let Some(card) = self.read_card(&key)? {
+                cards.push(card);
+            }
+        }
+        Ok(cards)
+    }
+
+    fn migrate(&self, dry_run: bool) -> Result<MigrationReport, DBError> {
+        let mut records: Vec<Record> = Vec::new();
+        for key in self.keys("SELECT key FROM cards", None)? {
+            if let Some(record) = self.read_record(&key)? {
+                records.push((key, record));
+            }
+        }
+        let (records, report) = migrate(records, self.version()?)?;
+        if !dry_run {
+            if !report.is_empty() {
+                self.save(&records_to_db(records)?)?;
+            }
+            self.set_version(CURRENT_VERSION)?;
+        }
+        Ok(report)
+    }
+
+    fn backup(&self, path: &Path) -> Result<bool, DBError> {
+        let has_cards = self.has_cards()?;
+        if has_cards {
+            // A consistent copy even while another connection reads, unlike copying the file.
+            self.conn.execute("VACUUM INTO ?1", params![path.to_string_lossy()])?;
+        }
+        Ok(has_cards)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outdated_db(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}.sqlite", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_string_lossy().to_string();
+        let storage = SqliteStorage::open_without_backups(&path).unwrap();
+        storage.conn.execute(
+            "INSERT INTO cards (key, character, pinyin, interval_days, ease, repetitions, lapses, data) VALUES ('好', '好', '', 0, 2.5, 0, 0, '{\"character\":\"好\",\"pinyin\":\"\"}')",
+            [],
+        ).unwrap();
+        storage.set_version(5).unwrap();
+        path
+    }
+
+    #[test]
+    fn shared_open_leaves_an_outdated_db_alone() {
+        let path = outdated_db("shared-open");
+        let storage = SqliteStorage::open(&path, false).unwrap();
+        assert!(storage.load().unwrap_err().to_string().contains("db migrate"));
+        assert_eq!(storage.stored_version().unwrap(), 5);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn migrate_stamps_the_current_version() {
+        let path = outdated_db("migrate");
+        let storage = SqliteStorage::open_without_backups(&path).unwrap();
+        assert!(!storage.migrate(true).unwrap().is_empty());
+        assert_eq!(storage.stored_version().unwrap(), 5, "a dry run writes nothing");
+        storage.migrate(false).unwrap();
+        assert_eq!(storage.stored_version().unwrap(), CURRENT_VERSION);
+        assert_eq!(storage.load().unwrap().len(), 1);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::sync::OnceLock;
+
+use inquire::validator::Validation;
+use inquire::{CustomUserError, Select, Text};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::db::Source;
+use crate::dictionary::Dictionary;
+use crate::pinyin::{self, PinyinStyle};
+
+#[derive(Debug)]
+pub struct StagingError(String);
+
+impl std::fmt::Display for StagingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for StagingError {}
+
+impl From<std::io::Error> for StagingError {
+    fn from(e: std::io::Error) -> Self {
+        StagingError(format!("IO error: {}", e))
+    }
+}
+
+impl From<serde_json::Error> for StagingError {
+    fn from(e: serde_json::Error) -> Self {
+        StagingError(format!("Malformed import queue: {}", e))
+    }
+}
+
+/// A word an import found, waiting to be looked over before it becomes a card.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Candidate {
+    pub word: String,
+    /// The pinyin printed next to the word, if any, in whatever form it was printed.
+    pub pinyin: String,
+    /// The gloss printed next to the word, definitions separated by semicolons.
+    pub meaning: String,
+    pub category: String,
+    pub source: Option<Source>,
+    /// The 1-based line of the page or text the word was on.
+    pub line: Option<usize>,
+    /// How sure OCR was of the word, from 0 to 100.
+    pub confidence: Option<f32>,
+    /// Read with less confidence than the import asked for.
+    pub unsure: bool,
+}
+
+/// Where imported words go: the queue at `queue`, or with `review` off, straight into the DB
+/// apart from unsure ones, which are queued all the same.
+pub struct Staging {
+    pub queue: String,
+    pub review: bool,
+}
+
+/// The queue is kept next to the DB.
+pub fn queue_path(db_location: &str) -> String {
+    format!("{}.import-queue", db_location)
+}
+
+pub fn load_queue(path: &str) -> Result<Vec<Candidate>, StagingError> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(serde_json::from_str(&contents)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes the queue, removing the file once nothing is left in it.
+pub fn save_queue(path: &str, candidates: &[Candidate]) -> Result<(), StagingError> {
+    if candidates.is_empty() {
+        return match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    }
+    let temp_path = format!("{}.temp", path);
+    fs::write(&temp_path, serde_json::to_string_pretty(candidates)?)?;
+    fs::rename(temp_path, path)?;
+    Ok(())
+}
+
+/// Adds `candidates` to the end of the queue.
+pub fn enqueue(path: &str, candidates: Vec<Candidate>) -> Result<(), StagingError> {
+    let mut queue = load_queue(path)?;
+    queue.extend(candidates);
+    save_queue(path, &queue)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Decision {
+    Accept,
+    Edit,
+    Split,
+    MergeWithNext,
+    Reject,
+    Skip,
+    AcceptRest,
+    Stop,
+}
+
+impl std::fmt::Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self {
+            Decision::Accept => "Accept",
+            Decision::Edit => "Edit",
+            Decision::Split => "Split into several words",
+            Decision::MergeWithNext => "Merge with the next word",
+            Decision::Reject => "Reject",
+            Decision::Skip => "Skip, leaving it queued",
+            Decision::AcceptRest => "Accept it and everything after it",
+            Decision::Stop => "Stop for now",
+        };
+        write!(f, "{}", label)
+    }
+}
+
+const DECISIONS: [Decision; 8] = [
+    Decision::Accept,
+    Decision::Edit,
+    Decision::Split,
+    Decision::MergeWithNext,
+    Decision::Reject,
+    Decision::Skip,
+    Decision::AcceptRest,
+    Decision::Stop,
+];
+
+/// What a review session decided: the words to add, and those still waiting.
+pub struct Reviewed {
+    pub accepted: Vec<Candidate>,
+    pub remaining: Vec<Candidate>,
+}
+
+/// Where the candidate was found, like `page-003.png, line 12`, and how sure OCR was of it.
+fn describe_source(candidate: &Candidate) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    if let Some(source) = &candidate.source {
+        parts.push(source.file.clone().unwrap_or(source.command.clone()));
+        if let Some(page) = source.page.filter(|_| source.file.as_deref().is_some_and(|file| file.to_lowercase().ends_with(".pdf"))) {
+            parts.push(format!("page {}", page));
+        }
+        if let Some(entry) = source.entry {
+            parts.push(format!("entry {}", entry));
+        }
+    }
+    if let Some(line) = candidate.line {
+        parts.push(format!("line {}", line));
+    }
+    if let Some(confidence) = candidate.confidence {
+        parts.push(format!("confidence {:.0}{}", confidence, if candidate.unsure { ", low" } else { "" }));
+    }
+    parts.join(", ")
+}
+
+fn describe(candidate: &Candidate, dictionary: Option<&Dictionary>, style: PinyinStyle) -> String {
+    let mut description = format!("{} ({})", candidate.word, candidate.category);
+    if !candidate.pinyin.is_empty() || !candidate.meaning.is_empty() {
+        description.push_str(&format!("\n  read: {} {}", pinyin::display(&pinyin::canonical(&candidate.pinyin), style), candidate.meaning));
+    }
+    match dictionary.and_then(|dictionary| dictionary.best_entry(&candidate.word)) {
+        Some(entry) => description.push_str(&format!("\n  dictionary: {} {}", pinyin::display(&entry.pinyin, style), entry.definitions.join("; "))),
+        None if dictionary.is_some() => description.push_str("\n  not in the dictionary"),
+        None => {},
+    }
+    description.push_str(&format!("\n  from {}", describe_source(candidate)));
+    description
+}
+
+fn has_chinese(word: &str) -> bool {
+    static HAN: OnceLock<Regex> = OnceLock::new();
+    HAN.get_or_init(|| Regex::new(r"\p{Han}").expect("Regex failed to initialize")).is_match(word)
+}
+
+/// Keeps a word without Chinese, which would make a card with a garbage headword, from
+/// leaving the prompt.
+fn chinese_word(input: &str) -> Result<Validation, CustomUserError> {
+    Ok(if has_chinese(input) { Validation::Valid } else { Validation::Invalid("The word needs Chinese characters".into()) })
+}
+
+fn chinese_words(input: &str) -> Result<Validation, CustomUserError> {
+    let words: Vec<&str> = input.split_whitespace().collect();
+    Ok(match words.iter().find(|word| !has_chinese(word)) {
+        _ if words.is_empty() => Validation::Invalid("Give at least one word".into()),
+        Some(word) => Validation::Invalid(format!("{} has no Chinese characters", word).into()),
+        None => Validation::Valid,
+    })
+}
+
+/// Asks for each field of the candidate, starting from what it has.
+fn edit(candidate: &Candidate) -> Option<Candidate> {
+    let word = Text::new("Word:").with_initial_value(&candidate.word).with_validator(chinese_word).prompt().ok()?;
+    let pinyin = Text::new("Pinyin:").with_initial_value(&candidate.pinyin).prompt().ok()?;
+    let meaning = Text::new("Meaning, definitions separated by ;").with_initial_value(&candidate.meaning).prompt().ok()?;
+    let category = Text::new("Category:").with_initial_value(&candidate.category).prompt().ok()?;
+    Some(Candidate { word: word.trim().to_string(), pinyin, meaning, category: category.trim().to_string(), ..candidate.clone() })
+}
+
+/// Splits the candidate into the words given, separated by spaces. The pieces lose the pinyin
+/// and meaning read for the whole.
+fn split(candidate: &Candidate) -> Option<Vec<Candidate>> {
+    let words = Text::new("Words, separated by spaces:").with_initial_value(&candidate.word).with_validator(chinese_words).prompt().ok()?;
+    let pieces: Vec<Candidate> = words
+        .split_whitespace()
+        .map(|word| Candidate { word: word.to_string(), pinyin: String::new(), meaning: String::new(), ..candidate.clone() })
+        .collect();
+    (!pieces.is_empty()).then_some(pieces)
+}
+
+/// Joins two candidates OCR or segmentation split apart, like 学 and 习.
+fn merge(first: Candidate, second: Candidate) -> Candidate {
+    let confidence = match (first.confidence, second.confidence) {
+        (Some(first), Some(second)) => Some(first.min(second)),
+        (first, second) => first.or(second),
+    };
+    Candidate {
+        word: format!("{}{}", first.word, second.word),
+        pinyin: format!("{} {}", first.pinyin, second.pinyin).trim().to_string(),
+        meaning: String::new(),
+        confidence,
+        unsure: first.unsure || second.unsure,
+        ..first
+    }
+}
+
+/// Goes through `candidates` one at a time, asking whether to accept, edit, split, merge or
+/// reject each. Escape or ctrl-c stops, keeping the decisions made so far.
+pub fn review(candidates: Vec<Candidate>, dictionary: Option<&Dictionary>, style: PinyinStyle) -> Reviewed {
+    let mut pending: VecDeque<Candidate> = candidates.into();
+    let mut accepted: Vec<Candidate> = Vec::new();
+    let mut skipped: Vec<Candidate> = Vec::new();
+    while let Some(candidate) = pending.pop_front() {
+        println!("\n[{} left] {}", pending.len() + 1, describe(&candidate, dictionary, style));
+        let decision = Select::new("What should happen to it?", DECISIONS.to_vec()).prompt().unwrap_or(Decision::Stop);
+        match decision {
+            Decision::Accept => accepted.push(candidate),
+            Decision::Edit => pending.push_front(edit(&candidate).unwrap_or(candidate)),
+            Decision::Split => match split(&candidate) {
+                Some(pieces) => pieces.into_iter().rev().for_each(|piece| pending.push_front(piece)),
+                None => pending.push_front(candidate),
+            },
+            Decision::MergeWithNext => match pending.pop_front() {
+                Some(next) => pending.push_front(merge(candidate, next)),
+                None => {
+                    println!("There is nothing after it to merge with");
+                    pending.push_front(candidate);
+                },
+            },
+            Decision::Reject => {},
+            Decision::Skip => skipped.push(candidate),
+            Decision::AcceptRest => {
+                accepted.push(candidate);
+                accepted.extend(pending.drain(..));
+            },
+            Decision::Stop => {
+                pending.push_front(candidate);
+                break;
+            },
+        }
+    }
+    skipped.extend(pending);
+    Reviewed { accepted, remaining: skipped }
+}
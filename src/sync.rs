@@ -0,0 +1,545 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::db::{insert_card, Card, CardChanges, DBError, MergePolicy, Source, Storage, DB};
+use crate::export::{export_cards_to_pleco, ExportError};
+use crate::pinyin::PinyinStyle;
+use crate::pleco::{self, PlecoError};
+
+#[derive(Debug)]
+pub struct SyncError(String);
+
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SyncError {}
+
+impl From<std::io::Error> for SyncError {
+    fn from(e: std::io::Error) -> Self {
+        SyncError(format!("IO error: {}", e))
+    }
+}
+
+impl From<serde_json::Error> for SyncError {
+    fn from(e: serde_json::Error) -> Self {
+        SyncError(format!("Serde error: {}", e))
+    }
+}
+
+impl From<DBError> for SyncError {
+    fn from(e: DBError) -> Self {
+        SyncError(format!("DB error: {}", e))
+    }
+}
+
+impl From<PlecoError> for SyncError {
+    fn from(e: PlecoError) -> Self {
+        SyncError(format!("Malformed Pleco file at {}", e))
+    }
+}
+
+impl From<ExportError> for SyncError {
+    fn from(e: ExportError) -> Self {
+        SyncError(format!("Export error: {}", e))
+    }
+}
+
+/// Which side wins when a card was changed both here and in Pleco since the last sync.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    /// Keep the card as it is in this tool
+    Local,
+    /// Take the card as it is in Pleco
+    Pleco,
+    /// Keep the card, in the categories of both sides
+    Union,
+}
+
+/// The categories of every card as Pleco last reported them, keyed like the DB.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Snapshot {
+    synced_at: Option<DateTime<Utc>>,
+    cards: BTreeMap<String, Vec<String>>,
+}
+
+fn snapshot_path(db_location: &str) -> String {
+    format!("{}.pleco-sync", db_location)
+}
+
+fn load_snapshot(path: &str) -> Result<Snapshot, SyncError> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(serde_json::from_str(&contents)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Snapshot::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub added_locally: Vec<String>,
+    pub deleted_locally: Vec<String>,
+    pub moved_locally: Vec<String>,
+    /// Cards written to the Pleco import file because they are new or recategorized here.
+    pub exported: Vec<String>,
+    /// Cards deleted here, which an import file can't delete from Pleco.
+    pub delete_in_pleco: Vec<String>,
+    /// Categories cards were moved out of here, which an import file can't remove in Pleco.
+    pub uncategorize_in_pleco: Vec<(String, Vec<String>)>,
+    pub conflicts: Vec<String>,
+    /// Cards missing from the export that weren't deleted here, because the export doesn't
+    /// cover their categories or the deletion wasn't confirmed.
+    pub pending_deletion: Vec<String>,
+    pub import_file: Option<String>,
+}
+
+impl std::fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "From Pleco: {} added, {} deleted, {} moved. To Pleco: {} cards. {} conflicts.",
+            self.added_locally.len(), self.deleted_locally.len(), self.moved_locally.len(), self.exported.len(), self.conflicts.len()
+        )?;
+        let sections = [
+            ("+", &self.added_locally),
+            ("-", &self.deleted_locally),
+            ("~", &self.moved_locally),
+            (">", &self.exported),
+            ("!", &self.conflicts),
+        ];
+        for (marker, keys) in sections {
+            for key in keys {
+                writeln!(f, "  {} {}", marker, key)?;
+            }
+        }
+        if !self.pending_deletion.is_empty() {
+            writeln!(
+                f,
+                "{} cards are missing from the export but were kept. If they were deleted in Pleco, sync a full export with --full-export and --confirm:",
+                self.pending_deletion.len()
+            )?;
+            for key in self.pending_deletion.iter().take(10) {
+                writeln!(f, "  ? {}", key)?;
+            }
+            if self.pending_deletion.len() > 10 {
+                writeln!(f, "  ? ... and {} more", self.pending_deletion.len() - 10)?;
+            }
+        }
+        if let Some(import_file) = &self.import_file {
+            writeln!(f, "Import {} into Pleco to bring it up to date.", import_file)?;
+        }
+        if !self.delete_in_pleco.is_empty() {
+            writeln!(f, "Delete these cards in Pleco by hand, an import can't remove them:")?;
+            for key in &self.delete_in_pleco {
+                writeln!(f, "  - {}", key)?;
+            }
+        }
+        if !self.uncategorize_in_pleco.is_empty() {
+            writeln!(f, "Remove these cards from their old categories in Pleco:")?;
+            for (key, categories) in &self.uncategorize_in_pleco {
+                writeln!(f, "  - {} from {}", key, categories.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Which of Pleco's cards an export holds: all of them, or only those in its categories, as
+/// when a single category was exported. A card missing from an export that doesn't cover it
+/// says nothing about whether it was deleted in Pleco.
+pub struct Coverage {
+    pub full: bool,
+    pub categories: BTreeSet<String>,
+}
+
+impl Coverage {
+    fn covers(&self, categories: &[String]) -> bool {
+        self.full || (!categories.is_empty() && categories.iter().all(|category| self.categories.contains(category)))
+    }
+}
+
+/// The outcome of comparing the DB, the export and the last sync, with the changes to the DB
+/// made in memory, apart from deletions, which wait for confirmation.
+struct Reconciled {
+    report: SyncReport,
+    changes: CardChanges,
+    outgoing: Vec<Card>,
+    next: Snapshot,
+    /// Cards deleted in Pleco, with the categories they had at the last sync.
+    deletions: Vec<(String, Vec<String>)>,
+}
+
+impl Reconciled {
+    /// Deletes the cards Pleco deleted, or with `confirmed` false, keeps them and remembers them
+    /// as Pleco had them, so the next sync asks again.
+    fn delete(&mut self, db: &mut DB, confirmed: bool) {
+        for (key, base_categories) in std::mem::take(&mut self.deletions) {
+            if confirmed {
+                db.remove(&key);
+                self.changes.removed.push(key.clone());
+                self.report.deleted_locally.push(key);
+            } else {
+                self.next.cards.insert(key.clone(), base_categories);
+                self.report.pending_deletion.push(key);
+            }
+        }
+    }
+}
+
+pub struct SyncOptions {
+    pub conflicts: ConflictPolicy,
+    pub style: PinyinStyle,
+    /// The export holds every card in Pleco.
+    pub full_export: bool,
+    /// Delete cards deleted in Pleco without asking.
+    pub confirm: bool,
+    pub dry_run: bool,
+}
+
+/// Three-way sync between the DB and a Pleco export, using the state recorded at the last
+/// sync to tell which side added, deleted or recategorized a card. Changes from Pleco are
+/// applied to the DB; changes made here are written to a Pleco import file. Cards deleted in
+/// Pleco are only deleted here once confirmed, by `confirm` or at a prompt, and only when
+/// `full_export` says the export holds every card or it has the card's categories.
+pub fn sync_pleco(export_file: &str, db_location: &str, options: &SyncOptions, storage: &dyn Storage, db: &mut DB) -> Result<SyncReport, SyncError> {
+    let pleco_file = pleco::parse_any(&fs::read_to_string(export_file)?)?;
+    let mut coverage = Coverage { full: options.full_export, categories: pleco_file.categories.iter().cloned().collect() };
+    let mut theirs: BTreeMap<String, Card> = BTreeMap::new();
+    for pleco_card in pleco_file.cards {
+        if pleco_card.language.as_deref().is_some_and(|language| language != "chinese") {
+            continue;
+        }
+        let card = pleco_card.into_card(Source::new("sync pleco", Some(export_file), None));
+        coverage.categories.extend(card.category.iter().cloned());
+        match theirs.get_mut(&card.key()) {
+            Some(existing) => existing.category.extend(card.category),
+            None => {
+                theirs.insert(card.key(), card);
+            },
+        }
+    }
+
+    let snapshot_path = snapshot_path(db_location);
+    let base = load_snapshot(&snapshot_path)?;
+    let mut reconciled = reconcile(theirs, base, &coverage, options.conflicts, db);
+    if options.dry_run {
+        reconciled.delete(db, true);
+        return Ok(reconciled.report);
+    }
+    let confirmed = reconciled.deletions.is_empty()
+        || options.confirm
+        || inquire::Confirm::new(&format!("Delete {} cards that were deleted in Pleco?", reconciled.deletions.len()))
+            .with_default(false)
+            .prompt()
+            .unwrap_or(false);
+    reconciled.delete(db, confirmed);
+
+    let Reconciled { mut report, changes, outgoing, next, .. } = reconciled;
+    changes.persist(storage, db)?;
+    if !outgoing.is_empty() {
+        let import_file = format!("pleco-sync-{}.xml", Local::now().format("%Y-%m-%d_%H:%M:%S"));
+        export_cards_to_pleco(&outgoing, options.style, fs::File::create(&import_file)?)?;
+        report.import_file = Some(import_file);
+    }
+    fs::write(&snapshot_path, serde_json::to_string_pretty(&next)?)?;
+    Ok(report)
+}
+
+/// A local card without a reading is keyed by its bare headword, so it is matched to Pleco's
+/// card for the headword before comparing, as `existing_key` would, and given its reading.
+fn match_readingless(theirs: &BTreeMap<String, Card>, base: &mut Snapshot, db: &mut DB, changes: &mut CardChanges) {
+    for (key, card) in theirs {
+        let bare = &card.character;
+        let readings = theirs.values().filter(|other| other.character == card.character).count();
+        if readings != 1 || db.contains_key(key) || theirs.contains_key(bare) || !db.contains_key(bare) {
+            continue;
+        }
+        if let Some(mut local) = db.remove(bare) {
+            local.pinyin = card.pinyin.clone();
+            local.touch();
+            db.insert(key.clone(), local);
+            changes.removed.push(bare.clone());
+            changes.saved.push(key.clone());
+            if let Some(categories) = base.cards.remove(bare) {
+                base.cards.insert(key.clone(), categories);
+            }
+        }
+    }
+}
+
+fn reconcile(theirs: BTreeMap<String, Card>, mut base: Snapshot, coverage: &Coverage, policy: ConflictPolicy, db: &mut DB) -> Reconciled {
+    let mut next = Snapshot { synced_at: Some(Utc::now()), cards: BTreeMap::new() };
+    let mut report = SyncReport::default();
+    let mut changes = CardChanges::default();
+    let mut outgoing: Vec<Card> = Vec::new();
+    let mut deletions: Vec<(String, Vec<String>)> = Vec::new();
+    match_readingless(&theirs, &mut base, db, &mut changes);
+
+    let keys: BTreeSet<String> = base.cards.keys().chain(db.keys()).chain(theirs.keys()).cloned().collect();
+    for key in keys {
+        let base_categories = base.cards.get(&key).map(|categories| sorted(categories));
+        let ours = db.get(&key).map(|card| sorted(&card.category));
+        let mut their_categories = theirs.get(&key).map(|card| sorted(&card.category));
+        if their_categories.is_none() && base_categories.as_ref().is_some_and(|categories| !coverage.covers(categories)) {
+            // For all this export says, Pleco still has the card as it was.
+            their_categories = base_categories.clone();
+            if ours.is_some() {
+                report.pending_deletion.push(key.clone());
+            }
+        }
+        let resolved = match (ours != base_categories, their_categories != base_categories) {
+            (_, false) => ours.clone(),
+            (false, true) => their_categories.clone(),
+            (true, true) if ours == their_categories => ours.clone(),
+            (true, true) => {
+                report.conflicts.push(key.clone());
+                match policy {
+                    ConflictPolicy::Local => ours.clone(),
+                    ConflictPolicy::Pleco => their_categories.clone(),
+                    ConflictPolicy::Union => {
+                        let union: BTreeSet<String> = ours.iter().chain(their_categories.iter()).flatten().cloned().collect();
+                        Some(union.into_iter().collect())
+                    },
+                }
+            },
+        };
+
+        // Bring the DB to the resolved state.
+        match (&resolved, &ours) {
+            (None, Some(_)) => deletions.push((key.clone(), base_categories.clone().unwrap_or_default())),
+            (Some(categories), None) => {
+                if let Some(card) = theirs.get(&key) {
+                    let card = Card { category: categories.clone(), ..card.clone() };
+                    insert_card(db, card, MergePolicy::Union, &mut changes);
+                    report.added_locally.push(key.clone());
+                }
+            },
+            (Some(categories), Some(current)) if categories != current => {
+                if let Some(card) = db.get_mut(&key) {
+                    card.category.retain(|category| categories.contains(category));
+                    for category in categories {
+                        if !card.category.contains(category) {
+                            card.category.push(category.clone());
+                        }
+                    }
+                    card.touch();
+                    changes.saved.push(key.clone());
+                    report.moved_locally.push(key.clone());
+                }
+            },
+            _ => {},
+        }
+
+        // And send Pleco whatever it doesn't have yet.
+        match (&resolved, &their_categories) {
+            (None, Some(their_categories)) => {
+                report.delete_in_pleco.push(key.clone());
+                // Still there on the Pleco side, so remember it to keep reminding.
+                next.cards.insert(key.clone(), their_categories.clone());
+            },
+            (Some(categories), _) => {
+                if their_categories.as_ref() != Some(categories) {
+                    if let Some(card) = db.get(&key) {
+                        outgoing.push(card.clone());
+                        report.exported.push(key.clone());
+                    }
+                    let stale: Vec<String> = their_categories
+                        .iter()
+                        .flatten()
+                        .filter(|category| !categories.contains(category))
+                        .cloned()
+                        .collect();
+                    if !stale.is_empty() {
+                        report.uncategorize_in_pleco.push((key.clone(), stale));
+                    }
+                    // Record what Pleco has until it shows up in an export, so a sync run before
+                    // the import file reached Pleco sends the card again instead of deleting it here.
+                    if let Some(their_categories) = their_categories {
+                        next.cards.insert(key.clone(), their_categories.clone());
+                    }
+                } else {
+                    next.cards.insert(key.clone(), categories.clone());
+                }
+            },
+            (None, None) => {},
+        }
+    }
+    Reconciled { report, changes, outgoing, next, deletions }
+}
+
+fn sorted(categories: &[String]) -> Vec<String> {
+    let categories: BTreeSet<&String> = categories.iter().collect();
+    categories.into_iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card(character: &str, pinyin: &str, categories: &[&str]) -> Card {
+        let categories = categories.iter().map(|category| category.to_string()).collect();
+        Card::new(character, categories, pinyin, Source::new("test", None, None))
+    }
+
+    fn cards(cards: &[Card]) -> BTreeMap<String, Card> {
+        cards.iter().map(|card| (card.key(), card.clone())).collect()
+    }
+
+    fn db(cards: &[Card]) -> DB {
+        cards.iter().map(|card| (card.key(), card.clone())).collect()
+    }
+
+    fn base(cards: &[Card]) -> Snapshot {
+        Snapshot { synced_at: None, cards: cards.iter().map(|card| (card.key(), sorted(&card.category))).collect() }
+    }
+
+    fn full() -> Coverage {
+        Coverage { full: true, categories: BTreeSet::new() }
+    }
+
+    fn categories(db: &DB, key: &str) -> Vec<String> {
+        sorted(&db[key].category)
+    }
+
+    #[test]
+    fn card_added_in_pleco_is_added_here() {
+        let new = card("学习", "xue2xi2", &["HSK1"]);
+        let mut db = db(&[]);
+        let reconciled = reconcile(cards(&[new]), base(&[]), &full(), ConflictPolicy::Union, &mut db);
+        assert_eq!(reconciled.report.added_locally, vec!["学习|xue2xi2"]);
+        assert!(reconciled.outgoing.is_empty());
+        assert_eq!(categories(&db, "学习|xue2xi2"), vec!["HSK1"]);
+        assert_eq!(reconciled.next.cards["学习|xue2xi2"], vec!["HSK1"]);
+    }
+
+    #[test]
+    fn card_added_here_is_sent_to_pleco() {
+        let new = card("学习", "xue2xi2", &["HSK1"]);
+        let mut db = db(&[new]);
+        let reconciled = reconcile(cards(&[]), base(&[]), &full(), ConflictPolicy::Union, &mut db);
+        assert_eq!(reconciled.report.exported, vec!["学习|xue2xi2"]);
+        assert_eq!(reconciled.outgoing.len(), 1);
+        // Not recorded until Pleco has it, so a later sync sends it again rather than deleting it.
+        assert!(!reconciled.next.cards.contains_key("学习|xue2xi2"));
+    }
+
+    #[test]
+    fn card_deleted_in_pleco_is_deleted_once_confirmed() {
+        let old = card("银行", "yin2hang2", &["HSK3"]);
+        let mut db = db(std::slice::from_ref(&old));
+        let mut reconciled = reconcile(cards(&[]), base(&[old]), &full(), ConflictPolicy::Union, &mut db);
+        assert_eq!(reconciled.deletions.len(), 1);
+        assert!(db.contains_key("银行|yin2hang2"), "nothing is deleted before confirmation");
+        reconciled.delete(&mut db, true);
+        assert!(!db.contains_key("银行|yin2hang2"));
+        assert_eq!(reconciled.report.deleted_locally, vec!["银行|yin2hang2"]);
+        assert_eq!(reconciled.changes.removed, vec!["银行|yin2hang2"]);
+        assert!(!reconciled.next.cards.contains_key("银行|yin2hang2"));
+    }
+
+    #[test]
+    fn unconfirmed_deletion_keeps_the_card_and_asks_again() {
+        let old = card("银行", "yin2hang2", &["HSK3"]);
+        let mut db = db(std::slice::from_ref(&old));
+        let mut reconciled = reconcile(cards(&[]), base(&[old]), &full(), ConflictPolicy::Union, &mut db);
+        reconciled.delete(&mut db, false);
+        assert!(db.contains_key("银行|yin2hang2"));
+        assert!(reconciled.report.deleted_locally.is_empty());
+        assert_eq!(reconciled.report.pending_deletion, vec!["银行|yin2hang2"]);
+        assert_eq!(reconciled.next.cards["银行|yin2hang2"], vec!["HSK3"]);
+    }
+
+    #[test]
+    fn card_deleted_here_is_listed_for_deleting_in_pleco() {
+        let old = card("银行", "yin2hang2", &["HSK3"]);
+        let mut db = db(&[]);
+        let reconciled = reconcile(cards(std::slice::from_ref(&old)), base(&[old]), &full(), ConflictPolicy::Union, &mut db);
+        assert_eq!(reconciled.report.delete_in_pleco, vec!["银行|yin2hang2"]);
+        assert!(reconciled.deletions.is_empty());
+        assert!(db.is_empty());
+    }
+
+    #[test]
+    fn card_moved_in_pleco_is_moved_here() {
+        let old = card("银行", "yin2hang2", &["HSK3"]);
+        let moved = card("银行", "yin2hang2", &["Lesson 2"]);
+        let mut db = db(std::slice::from_ref(&old));
+        let reconciled = reconcile(cards(&[moved]), base(&[old]), &full(), ConflictPolicy::Union, &mut db);
+        assert_eq!(reconciled.report.moved_locally, vec!["银行|yin2hang2"]);
+        assert_eq!(categories(&db, "银行|yin2hang2"), vec!["Lesson 2"]);
+        assert!(reconciled.outgoing.is_empty());
+    }
+
+    #[test]
+    fn card_moved_here_is_sent_to_pleco() {
+        let old = card("银行", "yin2hang2", &["HSK3"]);
+        let moved = card("银行", "yin2hang2", &["Lesson 2"]);
+        let mut db = db(&[moved]);
+        let reconciled = reconcile(cards(std::slice::from_ref(&old)), base(&[old]), &full(), ConflictPolicy::Union, &mut db);
+        assert_eq!(reconciled.report.exported, vec!["银行|yin2hang2"]);
+        assert_eq!(reconciled.report.uncategorize_in_pleco, vec![("银行|yin2hang2".to_string(), vec!["HSK3".to_string()])]);
+        assert!(reconciled.report.moved_locally.is_empty());
+    }
+
+    fn conflict(policy: ConflictPolicy) -> (Reconciled, DB) {
+        let old = card("银行", "yin2hang2", &["HSK3"]);
+        let mut db = db(&[card("银行", "yin2hang2", &["Mine"])]);
+        let reconciled = reconcile(cards(&[card("银行", "yin2hang2", &["Theirs"])]), base(&[old]), &full(), policy, &mut db);
+        assert_eq!(reconciled.report.conflicts, vec!["银行|yin2hang2"]);
+        (reconciled, db)
+    }
+
+    #[test]
+    fn conflict_kept_local() {
+        let (reconciled, db) = conflict(ConflictPolicy::Local);
+        assert_eq!(categories(&db, "银行|yin2hang2"), vec!["Mine"]);
+        assert_eq!(reconciled.report.exported, vec!["银行|yin2hang2"]);
+    }
+
+    #[test]
+    fn conflict_taken_from_pleco() {
+        let (reconciled, db) = conflict(ConflictPolicy::Pleco);
+        assert_eq!(categories(&db, "银行|yin2hang2"), vec!["Theirs"]);
+        assert!(reconciled.outgoing.is_empty());
+    }
+
+    #[test]
+    fn conflict_in_both_categories() {
+        let (reconciled, db) = conflict(ConflictPolicy::Union);
+        assert_eq!(categories(&db, "银行|yin2hang2"), vec!["Mine", "Theirs"]);
+        assert_eq!(reconciled.report.exported, vec!["银行|yin2hang2"]);
+    }
+
+    #[test]
+    fn partial_export_deletes_nothing_outside_its_categories() {
+        let kept = card("银行", "yin2hang2", &["HSK3"]);
+        let deleted = card("学习", "xue2xi2", &["HSK1"]);
+        let exported = card("你好", "ni3hao3", &["HSK1"]);
+        let mut db = db(&[kept.clone(), deleted.clone(), exported.clone()]);
+        let coverage = Coverage { full: false, categories: BTreeSet::from(["HSK1".to_string()]) };
+        let mut reconciled = reconcile(cards(std::slice::from_ref(&exported)), base(&[kept, deleted, exported]), &coverage, ConflictPolicy::Union, &mut db);
+        assert_eq!(reconciled.report.pending_deletion, vec!["银行|yin2hang2"]);
+        assert_eq!(reconciled.next.cards["银行|yin2hang2"], vec!["HSK3"]);
+        // Only the card from a category the export has counts as deleted in Pleco.
+        assert_eq!(reconciled.deletions.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>(), vec!["学习|xue2xi2"]);
+        reconciled.delete(&mut db, true);
+        assert!(db.contains_key("银行|yin2hang2"));
+        assert!(!db.contains_key("学习|xue2xi2"));
+    }
+
+    #[test]
+    fn readingless_card_matches_pleco_card_for_its_headword() {
+        let local = card("银行", "", &["HSK3"]);
+        let mut db = db(&[local]);
+        let reconciled = reconcile(cards(&[card("银行", "yin2hang2", &["HSK3"])]), base(&[]), &full(), ConflictPolicy::Union, &mut db);
+        assert!(reconciled.report.added_locally.is_empty());
+        assert!(reconciled.outgoing.is_empty());
+        assert_eq!(db.keys().collect::<Vec<_>>(), vec!["银行|yin2hang2"]);
+        assert_eq!(reconciled.changes.removed, vec!["银行"]);
+    }
+}
@@ -0,0 +1,240 @@
+use regex::Regex;
+
+use crate::ocr::OcrWord;
+use crate::pinyin;
+
+/// A word, or a character of one, as it was read off a page. `left` is its position across the
+/// page in pixels, when the page was OCRed.
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub text: String,
+    pub confidence: f32,
+    pub left: Option<i32>,
+}
+
+/// What a token of a vocabulary table row can be.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Number,
+    Han,
+    Latin,
+    Other,
+}
+
+fn kind(han: &Regex, text: &str) -> Kind {
+    let text = text.trim_end_matches(['.', ')', '、']);
+    if !text.is_empty() && text.chars().all(|c| c.is_ascii_digit()) {
+        Kind::Number
+    } else if !text.is_empty() && text.chars().all(|c| han.is_match(c.encode_utf8(&mut [0; 4])) || c == '·') {
+        Kind::Han
+    } else if text.chars().any(char::is_alphabetic) && !text.chars().any(|c| han.is_match(c.encode_utf8(&mut [0; 4]))) {
+        Kind::Latin
+    } else {
+        Kind::Other
+    }
+}
+
+/// Whether `text` reads as pinyin with a tone, so that glosses like `he` or `a` aren't taken for it.
+fn is_toned_pinyin(text: &str) -> bool {
+    pinyin::parse(text).is_ok_and(|syllables| !syllables.is_empty() && syllables.iter().any(|syllable| syllable.tone != 5))
+}
+
+/// Splits a row where a second entry starts, as happens when a page sets its list in two columns.
+fn split_entries(han: &Regex, row: Vec<Token>) -> Vec<Vec<Token>> {
+    let mut entries: Vec<Vec<Token>> = vec![Vec::new()];
+    for (index, token) in row.iter().enumerate() {
+        let starts_entry = index > 0
+            && kind(han, &token.text) == Kind::Number
+            && row.get(index + 1).is_some_and(|next| kind(han, &next.text) == Kind::Han)
+            && entries.last().is_some_and(|entry| entry.iter().any(|token| kind(han, &token.text) == Kind::Han));
+        if starts_entry {
+            entries.push(Vec::new());
+        }
+        if let Some(entry) = entries.last_mut() {
+            entry.push(token.clone());
+        }
+    }
+    entries
+}
+
+/// Reads a row like `607 散步 sànbù to take a walk`: an optional entry number, the headword, its
+/// pinyin and a gloss. `gloss_column` is how far right of where the row starts its gloss starts;
+/// Latin text before that is pinyin. Without one, pinyin runs until the first word that isn't
+/// pinyin with a tone. Gives where the gloss starts, measured the same way.
+fn parse_row(han: &Regex, row: &[Token], line: usize, gloss_column: Option<i32>) -> Option<(OcrWord, Option<i32>)> {
+    let start = row.first().and_then(|token| token.left);
+    let mut tokens = row.iter().peekable();
+    let entry = tokens.next_if(|token| kind(han, &token.text) == Kind::Number).and_then(|token| {
+        token.text.trim_end_matches(['.', ')', '、']).parse::<u32>().ok()
+    });
+    let mut word = OcrWord { entry, confidence: 100.0, line: Some(line), ..OcrWord::default() };
+    while let Some(token) = tokens.next_if(|token| kind(han, &token.text) == Kind::Han) {
+        word.text.push_str(&token.text);
+        word.confidence = word.confidence.min(token.confidence);
+    }
+    if word.text.is_empty() {
+        return None;
+    }
+
+    let mut pinyin: Vec<&str> = Vec::new();
+    while let Some(token) = tokens.next_if(|token| {
+        let latin = kind(han, &token.text) == Kind::Latin;
+        match (gloss_column, token.left, start) {
+            (Some(column), Some(left), Some(start)) => latin && left - start < column,
+            _ => latin && (is_toned_pinyin(&token.text) || (pinyin.is_empty() && pinyin::parse(&token.text).is_ok())),
+        }
+    }) {
+        pinyin.push(&token.text);
+    }
+    let gloss_left = tokens.peek().and_then(|token| Some(token.left? - start?));
+    let gloss: Vec<&str> = tokens.map(|token| token.text.as_str()).collect();
+    if pinyin.is_empty() && gloss.is_empty() {
+        return None;
+    }
+    word.pinyin = pinyin.join(" ");
+    word.gloss = gloss.join(" ");
+    let gloss_left = gloss_left.filter(|_| !word.pinyin.is_empty() && !word.gloss.is_empty());
+    Some((word, gloss_left))
+}
+
+/// Whether a row starting with `token` carries on the gloss above it, rather than being a page
+/// number or a heading.
+fn continues_gloss(han: &Regex, token: &Token, gloss_column: Option<i32>) -> bool {
+    kind(han, &token.text) == Kind::Latin && gloss_column.zip(token.left).is_none_or(|(column, left)| left >= column)
+}
+
+/// Reads the rows of a page as a vocabulary table, when most of its rows with Chinese on them
+/// are entries. The gloss column is placed where most entries' glosses start, measured from the
+/// start of the entry so that both halves of a page set in two columns agree, and a row without
+/// Chinese continues the gloss of the entry before it.
+pub fn entries(rows: Vec<Vec<Token>>) -> Option<Vec<OcrWord>> {
+    let han = Regex::new(r"^\p{Han}$").expect("Regex failed to initialize");
+    let rows: Vec<(usize, Vec<Token>)> = rows
+        .into_iter()
+        .enumerate()
+        .flat_map(|(index, row)| split_entries(&han, row).into_iter().map(move |row| (index + 1, row)))
+        .filter(|(_, row)| !row.is_empty())
+        .collect();
+
+    let mut gloss_starts: Vec<i32> = rows.iter().filter_map(|(line, row)| parse_row(&han, row, *line, None)).filter_map(|(_, left)| left).collect();
+    gloss_starts.sort();
+    let gloss_column = gloss_starts.get(gloss_starts.len() / 2).copied();
+
+    let mut words: Vec<OcrWord> = Vec::new();
+    // Where the last entry's gloss column is across the page.
+    let mut last_gloss_column = None;
+    let mut chinese_rows = 0;
+    for (line, row) in &rows {
+        let has_chinese = row.iter().any(|token| token.text.chars().any(|c| han.is_match(c.encode_utf8(&mut [0; 4]))));
+        chinese_rows += has_chinese as usize;
+        match parse_row(&han, row, *line, gloss_column) {
+            Some((word, _)) => {
+                last_gloss_column = gloss_column.zip(row.first().and_then(|token| token.left)).map(|(column, start)| start + column);
+                words.push(word);
+            },
+            None if !has_chinese && row.first().is_some_and(|token| continues_gloss(&han, token, last_gloss_column)) => {
+                if let Some(last) = words.last_mut().filter(|last| !last.gloss.is_empty()) {
+                    for token in row {
+                        last.gloss.push(' ');
+                        last.gloss.push_str(&token.text);
+                    }
+                }
+            },
+            None => {},
+        }
+    }
+    (words.len() >= 3 && words.len() * 2 >= chinese_rows).then_some(words)
+}
+
+/// Splits text into rows of whitespace separated tokens, for pages with a text layer.
+pub fn text_rows(text: &str) -> Vec<Vec<Token>> {
+    text.lines()
+        .map(|line| line.split_whitespace().map(|text| Token { text: text.to_string(), confidence: 100.0, left: None }).collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A row of word boxes, each its text and left edge.
+    fn row(boxes: &[(&str, i32)]) -> Vec<Token> {
+        boxes.iter().map(|(text, left)| Token { text: text.to_string(), confidence: 90.0, left: Some(*left) }).collect()
+    }
+
+    fn summary(words: &[OcrWord]) -> Vec<(Option<u32>, &str, &str, &str)> {
+        words.iter().map(|word| (word.entry, word.text.as_str(), word.pinyin.as_str(), word.gloss.as_str())).collect()
+    }
+
+    #[test]
+    fn gloss_column_is_where_most_glosses_start() {
+        let words = entries(vec![
+            // A toneless syllable would end the pinyin without the column to go by.
+            row(&[("1", 10), ("东西", 60), ("dōng", 160), ("xi", 220), ("thing", 300)]),
+            row(&[("2", 10), ("学习", 60), ("xuéxí", 160), ("to", 300), ("study", 330)]),
+            row(&[("3", 10), ("银行", 60), ("yínháng", 160), ("bank", 300)]),
+            row(&[("4", 10), ("你好", 60), ("nǐhǎo", 160), ("hello", 300)]),
+        ])
+        .unwrap();
+        assert_eq!(summary(&words), vec![
+            (Some(1), "东西", "dōng xi", "thing"),
+            (Some(2), "学习", "xuéxí", "to study"),
+            (Some(3), "银行", "yínháng", "bank"),
+            (Some(4), "你好", "nǐhǎo", "hello"),
+        ]);
+        assert_eq!(words.iter().map(|word| word.line).collect::<Vec<_>>(), vec![Some(1), Some(2), Some(3), Some(4)]);
+    }
+
+    #[test]
+    fn rows_without_chinese_continue_the_gloss_above() {
+        let words = entries(vec![
+            row(&[("Lesson", 10), ("two", 80)]),
+            row(&[("1", 10), ("散步", 60), ("sànbù", 160), ("to", 300), ("take", 330)]),
+            row(&[("a", 300), ("walk", 320)]),
+            row(&[("2", 10), ("学习", 60), ("xuéxí", 160), ("to", 300), ("study", 330)]),
+            row(&[("3", 10), ("银行", 60), ("yínháng", 160), ("bank", 300)]),
+            // Neither a page number nor text left of the gloss column carries on a gloss.
+            row(&[("12", 200)]),
+            row(&[("Notes", 10)]),
+        ])
+        .unwrap();
+        assert_eq!(summary(&words), vec![
+            (Some(1), "散步", "sànbù", "to take a walk"),
+            (Some(2), "学习", "xuéxí", "to study"),
+            (Some(3), "银行", "yínháng", "bank"),
+        ]);
+    }
+
+    #[test]
+    fn row_with_two_entries_is_split() {
+        let words = entries(vec![
+            row(&[("1", 10), ("散步", 60), ("sànbù", 160), ("walk", 300), ("2", 500), ("学习", 550), ("xuéxí", 650), ("study", 790)]),
+            row(&[("3", 10), ("银行", 60), ("yínháng", 160), ("bank", 300), ("4", 500), ("你好", 550), ("nǐhǎo", 650), ("hello", 790)]),
+        ])
+        .unwrap();
+        // Each half's gloss starts as far right of its own entry, wherever the half is on the page.
+        assert_eq!(summary(&words), vec![
+            (Some(1), "散步", "sànbù", "walk"),
+            (Some(2), "学习", "xuéxí", "study"),
+            (Some(3), "银行", "yínháng", "bank"),
+            (Some(4), "你好", "nǐhǎo", "hello"),
+        ]);
+        // Both halves of a row keep its line.
+        assert_eq!(words[1].line, Some(1));
+    }
+
+    #[test]
+    fn text_layer_rows_are_read_by_pinyin_alone() {
+        let words = entries(text_rows("1 散步 sànbù to take a walk 2 学习 xuéxí to study\n3. 银行 yín háng bank\n")).unwrap();
+        assert_eq!(summary(&words), vec![
+            (Some(1), "散步", "sànbù", "to take a walk"),
+            (Some(2), "学习", "xuéxí", "to study"),
+            (Some(3), "银行", "yín háng", "bank"),
+        ]);
+    }
+
+    #[test]
+    fn prose_is_not_a_table() {
+        assert!(entries(text_rows("我喜欢学习中文。\n他在银行工作。\n你好 hello\n")).is_none());
+    }
+}